@@ -0,0 +1,153 @@
+use mcs_binary::signer::{AgentRequest, AgentResponse, LocalSigner, Signer};
+use mcs_binary::{keys, signer, utils};
+use std::process;
+use std::sync::Arc;
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    let matches = utils::parse_agent_arguments();
+    let key_dir = matches
+        .get_one::<String>("keys")
+        .map(String::as_str)
+        .unwrap_or(keys::DEFAULT_KEY_DIR);
+    let socket = matches
+        .get_one::<String>("socket")
+        .map(String::as_str)
+        .unwrap_or(signer::DEFAULT_AGENT_SOCKET);
+
+    let local = match LocalSigner::load(key_dir) {
+        Ok(local) => Arc::new(local),
+        Err(e) => {
+            eprintln!("Failed to load client keys: {e}");
+            process::exit(1);
+        }
+    };
+    let client_ids = local.client_ids().await.unwrap_or_default();
+    if client_ids.is_empty() {
+        eprintln!("No client keys found in {key_dir}. Run keygen first.");
+        process::exit(1);
+    }
+    let now = utils::unix_millis();
+    for id in &client_ids {
+        match local.current_key_id(*id, now).await {
+            Ok(key_id) => println!("Client {id}: Signing with key {key_id}."),
+            Err(e) => {
+                eprintln!("Client {id}: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let (listener, owner) = match bind(socket) {
+        Ok(bound) => bound,
+        Err(e) => {
+            eprintln!("Failed to listen on {socket}: {e}");
+            process::exit(1);
+        }
+    };
+    println!(
+        "Signing agent for {} clients listening on {socket}.",
+        client_ids.len()
+    );
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => match stream.peer_cred() {
+                    Ok(peer) if peer.uid() == owner => {
+                        tokio::spawn(serve(stream, local.clone()));
+                    }
+                    Ok(peer) => eprintln!(
+                        "Refused connection from user {}, only user {owner} may sign.",
+                        peer.uid()
+                    ),
+                    Err(e) => eprintln!("Refused connection without peer credentials: {e}"),
+                },
+                Err(e) => eprintln!("Failed to accept connection: {e}"),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    let _ = std::fs::remove_file(socket);
+    println!("Signing agent stopped.");
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The signing agent needs Unix domain sockets, which this platform lacks.");
+    process::exit(1);
+}
+
+/// Listen on `path`, readable and writable by this user only, and return the listener with
+/// the id of that user. A socket left behind by an agent that is no longer running is
+/// replaced; a live one is not.
+#[cfg(unix)]
+fn bind(path: &str) -> std::io::Result<(tokio::net::UnixListener, u32)> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another agent is running",
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    // Bind inside a directory only this user can enter, so nobody can connect before the
+    // socket's permissions are narrowed, then move the socket into place.
+    let target = std::path::Path::new(path);
+    let private_dir = target.with_file_name(format!(
+        ".{}.{}",
+        target
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default(),
+        process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let staged = private_dir.join("agent.sock");
+    let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        let owner = std::fs::metadata(&staged)?.uid();
+        std::fs::rename(&staged, target)?;
+        Ok((listener, owner))
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&private_dir);
+    bound
+}
+
+/// Answer the JSON-line requests of one connection until the client hangs up.
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream, local: Arc<LocalSigner>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<AgentRequest>(&line) {
+            Ok(request) => local.handle(request),
+            Err(e) => AgentResponse::Error {
+                detail: format!("malformed request: {e}"),
+            },
+        };
+        let mut reply = match serde_json::to_string(&response) {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("Failed to encode reply: {e}");
+                return;
+            }
+        };
+        reply.push('\n');
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::utils;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Result of combining client values into a single global value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Aggregate {
+    /// `None` when there was nothing left to combine.
+    pub value: Option<f64>,
+    /// Indices into the input of the values left out of `value`.
+    pub excluded: Vec<usize>,
+}
+
+/// A verified client value together with the volume it was computed over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contribution {
+    pub value: f64,
+    pub volume: f64,
+}
+
+/// A strategy for combining client values into a global value.
+pub trait Aggregator: Debug + Send + Sync {
+    /// Short name published alongside the result.
+    fn name(&self) -> String;
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate;
+}
+
+/// Plain arithmetic mean of every value.
+#[derive(Debug, Clone, Copy)]
+pub struct Mean;
+
+/// Middle value, or the mean of the two middle values.
+#[derive(Debug, Clone, Copy)]
+pub struct Median;
+
+/// Mean after dropping the `trim` fraction of lowest and of highest values.
+#[derive(Debug, Clone, Copy)]
+pub struct TrimmedMean {
+    pub trim: f64,
+}
+
+/// Mean after dropping values more than `threshold` scaled median absolute
+/// deviations away from the median.
+#[derive(Debug, Clone, Copy)]
+pub struct MadFilter {
+    pub threshold: f64,
+}
+
+/// Mean of the client values weighted by each client's traded volume.
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeWeighted;
+
+/// Scales the MAD so it estimates the standard deviation of normally distributed values.
+const MAD_SCALE: f64 = 1.4826;
+
+impl Aggregator for Mean {
+    fn name(&self) -> String {
+        "mean".to_string()
+    }
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate {
+        Aggregate {
+            value: utils::calculate_average(&values(contributions)),
+            excluded: Vec::new(),
+        }
+    }
+}
+
+impl Aggregator for Median {
+    fn name(&self) -> String {
+        "median".to_string()
+    }
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate {
+        Aggregate {
+            value: median(&values(contributions)),
+            excluded: Vec::new(),
+        }
+    }
+}
+
+impl Aggregator for TrimmedMean {
+    fn name(&self) -> String {
+        format!("trimmed:{}", self.trim)
+    }
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate {
+        let values = values(contributions);
+        let order = sorted_indices(&values);
+        let cut = (values.len() as f64 * self.trim).floor() as usize;
+        if cut * 2 >= values.len() {
+            return Mean.aggregate(contributions);
+        }
+        let kept: Vec<f64> = order[cut..values.len() - cut]
+            .iter()
+            .map(|&i| values[i])
+            .collect();
+        let mut excluded: Vec<usize> = order[..cut]
+            .iter()
+            .chain(&order[values.len() - cut..])
+            .copied()
+            .collect();
+        excluded.sort_unstable();
+        Aggregate {
+            value: utils::calculate_average(&kept),
+            excluded,
+        }
+    }
+}
+
+impl Aggregator for MadFilter {
+    fn name(&self) -> String {
+        format!("mad:{}", self.threshold)
+    }
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate {
+        let values = values(contributions);
+        let Some(center) = median(&values) else {
+            return Aggregate::default();
+        };
+        let deviations: Vec<f64> = values.iter().map(|v| (v - center).abs()).collect();
+        let mad = median(&deviations).unwrap_or_default() * MAD_SCALE;
+        if mad == 0.0 {
+            // At least half the values agree exactly; there is no spread to judge outliers by.
+            return Mean.aggregate(contributions);
+        }
+        let (excluded, kept): (Vec<usize>, Vec<usize>) =
+            (0..values.len()).partition(|&i| deviations[i] > self.threshold * mad);
+        let kept: Vec<f64> = kept.into_iter().map(|i| values[i]).collect();
+        Aggregate {
+            value: utils::calculate_average(&kept),
+            excluded,
+        }
+    }
+}
+
+impl Aggregator for VolumeWeighted {
+    fn name(&self) -> String {
+        "vwap".to_string()
+    }
+
+    fn aggregate(&self, contributions: &[Contribution]) -> Aggregate {
+        let volume: f64 = contributions.iter().map(|c| c.volume).sum();
+        if volume <= 0.0 {
+            // No client reported any volume, so there is nothing to weight by.
+            return Mean.aggregate(contributions);
+        }
+        let weighted: f64 = contributions.iter().map(|c| c.value * c.volume).sum();
+        Aggregate {
+            value: Some(weighted / volume),
+            excluded: Vec::new(),
+        }
+    }
+}
+
+/// Build a strategy from its command-line name: `mean`, `median`, `vwap`,
+/// `trimmed[:FRACTION]` or `mad[:THRESHOLD]`.
+pub fn from_spec(spec: &str) -> Result<Arc<dyn Aggregator>, String> {
+    let (name, param) = match spec.split_once(':') {
+        Some((name, param)) => (name, Some(param)),
+        None => (spec, None),
+    };
+    let parse = |default: f64| -> Result<f64, String> {
+        param
+            .map(|p| {
+                p.parse::<f64>()
+                    .map_err(|e| format!("invalid parameter for {name}: {e}"))
+            })
+            .unwrap_or(Ok(default))
+    };
+    match name {
+        "mean" => Ok(Arc::new(Mean)),
+        "median" => Ok(Arc::new(Median)),
+        "vwap" => Ok(Arc::new(VolumeWeighted)),
+        "trimmed" => {
+            let trim = parse(0.1)?;
+            if !(0.0..0.5).contains(&trim) {
+                return Err(format!("trim fraction {trim} must be in [0, 0.5)"));
+            }
+            Ok(Arc::new(TrimmedMean { trim }))
+        }
+        "mad" => {
+            let threshold = parse(3.0)?;
+            if !(threshold.is_finite() && threshold > 0.0) {
+                return Err(format!("MAD threshold {threshold} must be positive"));
+            }
+            Ok(Arc::new(MadFilter { threshold }))
+        }
+        _ => Err(format!(
+            "unknown aggregation method {name:?}, use mean, median, vwap, trimmed or mad"
+        )),
+    }
+}
+
+fn values(contributions: &[Contribution]) -> Vec<f64> {
+    contributions.iter().map(|c| c.value).collect()
+}
+
+fn sorted_indices(values: &[f64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    order
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let order = sorted_indices(values);
+    let mid = order.len() / 2;
+    match order.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[order[mid]]),
+        _ => Some((values[order[mid - 1]] + values[order[mid]]) / 2.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contributions(values: &[f64]) -> Vec<Contribution> {
+        values
+            .iter()
+            .map(|&value| Contribution { value, volume: 1.0 })
+            .collect()
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[4.0]), Some(4.0));
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), Some(2.5));
+        assert_eq!(
+            Median
+                .aggregate(&contributions(&[10.0, 1.0, 7.0, 3.0]))
+                .value,
+            Some(5.0)
+        );
+    }
+
+    #[test]
+    fn trimmed_mean_drops_both_edges() {
+        let aggregate =
+            TrimmedMean { trim: 0.2 }.aggregate(&contributions(&[50.0, 1.0, 2.0, 3.0, -40.0]));
+        assert_eq!(aggregate.value, Some(2.0));
+        assert_eq!(aggregate.excluded, vec![0, 4]);
+    }
+
+    #[test]
+    fn trimmed_mean_close_to_half_keeps_the_middle() {
+        let trimmed = TrimmedMean { trim: 0.49 };
+        let aggregate = trimmed.aggregate(&contributions(&[9.0, 1.0, 5.0, 4.0, 100.0]));
+        assert_eq!(aggregate.value, Some(5.0));
+        assert_eq!(aggregate.excluded, vec![0, 1, 3, 4]);
+        // Too few values to cut any: two values cut by 0.49 leave both.
+        let aggregate = trimmed.aggregate(&contributions(&[2.0, 4.0]));
+        assert_eq!(aggregate.value, Some(3.0));
+        assert!(aggregate.excluded.is_empty());
+        assert_eq!(trimmed.aggregate(&[]), Aggregate::default());
+    }
+
+    #[test]
+    fn mad_filter_excludes_a_real_outlier() {
+        let aggregate = MadFilter { threshold: 3.0 }
+            .aggregate(&contributions(&[100.0, 101.0, 99.0, 100.5, 99.5, 250.0]));
+        assert_eq!(aggregate.value, Some(100.0));
+        assert_eq!(aggregate.excluded, vec![5]);
+    }
+
+    #[test]
+    fn mad_filter_without_spread_keeps_every_value() {
+        let aggregate =
+            MadFilter { threshold: 3.0 }.aggregate(&contributions(&[5.0, 5.0, 5.0, 8.0]));
+        assert_eq!(aggregate.value, Some(5.75));
+        assert!(aggregate.excluded.is_empty());
+        assert_eq!(
+            MadFilter { threshold: 3.0 }.aggregate(&[]),
+            Aggregate::default()
+        );
+    }
+
+    #[test]
+    fn vwap_weights_values_by_volume() {
+        let aggregate = VolumeWeighted.aggregate(&[
+            Contribution {
+                value: 100.0,
+                volume: 3.0,
+            },
+            Contribution {
+                value: 200.0,
+                volume: 1.0,
+            },
+        ]);
+        assert_eq!(aggregate.value, Some(125.0));
+    }
+
+    #[test]
+    fn vwap_without_volume_falls_back_to_the_mean() {
+        let zero = [
+            Contribution {
+                value: 100.0,
+                volume: 0.0,
+            },
+            Contribution {
+                value: 200.0,
+                volume: 0.0,
+            },
+        ];
+        assert_eq!(VolumeWeighted.aggregate(&zero).value, Some(150.0));
+        assert_eq!(VolumeWeighted.aggregate(&[]).value, None);
+    }
+
+    #[test]
+    fn from_spec_checks_parameters() {
+        assert_eq!(from_spec("mean").unwrap().name(), "mean");
+        assert_eq!(from_spec("trimmed").unwrap().name(), "trimmed:0.1");
+        assert_eq!(from_spec("trimmed:0.25").unwrap().name(), "trimmed:0.25");
+        assert_eq!(from_spec("mad").unwrap().name(), "mad:3");
+        for spec in [
+            "trimmed:0.5",
+            "trimmed:-0.1",
+            "trimmed:NaN",
+            "mad:0",
+            "mad:-1",
+            "mad:NaN",
+            "mad:inf",
+            "mad:x",
+            "mode",
+        ] {
+            assert!(from_spec(spec).is_err(), "{spec:?} was accepted");
+        }
+    }
+}
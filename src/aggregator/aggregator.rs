@@ -1,37 +1,191 @@
+use crate::keys::KeyRegistry;
+use crate::merkle;
+use crate::peers;
+use crate::protocol::{self, ErrorCode, GlobalResult, ProtocolError, Submission, WireMessage};
+use crate::registry::{self, SharedRegistry};
+use crate::round::{PublishedRound, RoundConfig, SummaryOutcome, SymbolRounds};
 use crate::utils;
 use base64::{engine::general_purpose, Engine as _};
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ed25519_dalek::{Signature, Verifier};
 use futures::{SinkExt, StreamExt};
-use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, Notify};
-use tokio::time::{timeout, Duration};
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::time::{interval, timeout, Duration, Instant};
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::{
+    accept_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
 
-/// Aggregator process: Compute global average from signed client messages.
-pub async fn aggregator_process(num_clients: usize, public_keys: Arc<Vec<VerifyingKey>>) {
-    let averages = Arc::new(Mutex::new(Vec::new()));
+/// Outcome of verifying a single client submission.
+#[derive(Debug)]
+pub enum Verification {
+    /// Signature checked out against the client's public key; the value is admitted.
+    Verified {
+        client_id: usize,
+        value: f64,
+        volume: f64,
+        /// [`Submission::leaf_hash`], committed to in the signed result.
+        leaf: merkle::Hash,
+    },
+    /// The client is known but the signature does not match the message.
+    BadSignature { client_id: usize },
+    /// No public key is registered for the claimed client id.
+    UnknownClient { client_id: usize },
+    /// The key is registered but disabled; counted with unknown clients.
+    DisabledClient { client_id: usize },
+    /// The submission could not be parsed at all.
+    Malformed { reason: String },
+    /// Signed for another round or outside the accepted clock window.
+    Stale { client_id: usize, reason: String },
+    /// A nonce or round the client has already used.
+    Replayed { client_id: usize, reason: String },
+}
+
+impl Verification {
+    /// Short description used when listing rejected submissions.
+    pub fn describe(&self) -> String {
+        match self {
+            Verification::Verified {
+                client_id, value, ..
+            } => {
+                format!("Client-{client_id}: verified {value:.4}")
+            }
+            Verification::BadSignature { client_id } => {
+                format!("Client-{client_id}: bad signature")
+            }
+            Verification::UnknownClient { client_id } => {
+                format!("Client-{client_id}: unknown client")
+            }
+            Verification::DisabledClient { client_id } => {
+                format!("Client-{client_id}: key disabled in the registry")
+            }
+            Verification::Malformed { reason } => format!("malformed submission: {reason}"),
+            Verification::Stale { client_id, reason } => {
+                format!("Client-{client_id}: stale submission ({reason})")
+            }
+            Verification::Replayed { client_id, reason } => {
+                format!("Client-{client_id}: replayed submission ({reason})")
+            }
+        }
+    }
+}
+
+/// Submissions timestamped further than this from the aggregator clock are stale.
+const MAX_CLOCK_SKEW_MS: u64 = 60_000;
+
+/// Per-client table of recently seen nonces and the last round submitted to per symbol.
+#[derive(Debug, Default)]
+struct ReplayGuard {
+    last_round: HashMap<(usize, String), u64>,
+    /// Nonce -> timestamp, kept only while the timestamp is inside the skew window.
+    seen_nonces: HashMap<usize, HashMap<u64, u64>>,
+}
+
+impl ReplayGuard {
+    /// Reject stale or duplicated submissions, recording the fresh ones.
+    fn admit(
+        &mut self,
+        submission: &Submission,
+        current_round: u64,
+        now: u64,
+    ) -> Result<(), Verification> {
+        let client_id = submission.client_id;
+        if submission.round_id != current_round {
+            return Err(Verification::Stale {
+                client_id,
+                reason: format!(
+                    "signed for round {}, current round is {current_round}",
+                    submission.round_id
+                ),
+            });
+        }
+        if now.abs_diff(submission.timestamp) > MAX_CLOCK_SKEW_MS {
+            return Err(Verification::Stale {
+                client_id,
+                reason: format!("timestamp {} outside clock window", submission.timestamp),
+            });
+        }
+
+        let nonces = self.seen_nonces.entry(client_id).or_default();
+        nonces.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_CLOCK_SKEW_MS);
+        if nonces.contains_key(&submission.nonce) {
+            return Err(Verification::Replayed {
+                client_id,
+                reason: format!("nonce {} already used", submission.nonce),
+            });
+        }
+        let round_key = (client_id, submission.symbol.clone());
+        if self.last_round.get(&round_key) >= Some(&submission.round_id) {
+            return Err(Verification::Replayed {
+                client_id,
+                reason: format!("already submitted for round {}", submission.round_id),
+            });
+        }
+
+        nonces.insert(submission.nonce, submission.timestamp);
+        self.last_round.insert(round_key, submission.round_id);
+        Ok(())
+    }
+}
 
-    let is_ready = Arc::new(Mutex::new(false));
-    let notify = Arc::new(Notify::new());
+/// Aggregator process: Compute global averages round by round from signed client messages,
+/// listening on every address in `listen`, over TLS with `tls`.
+pub async fn aggregator_process(
+    config: RoundConfig,
+    symbols: Vec<String>,
+    registry: SharedRegistry,
+    listen: &[String],
+    tls: Option<TlsAcceptor>,
+) {
+    let rounds = Arc::new(Mutex::new(SymbolRounds::new(
+        &symbols,
+        config.clone(),
+        registry.snapshot().enabled_count(),
+    )));
+    let replay_guard = Arc::new(Mutex::new(ReplayGuard::default()));
+    let published = Arc::new(Notify::new());
     let active_clients = Arc::new(AtomicUsize::new(0));
-    let clients_verified: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-    let listener = TcpListener::bind("127.0.0.1:8080")
-        .await
-        .expect("Failed to bind to address");
-    println!("Aggregator WebSocket server listening on ws://127.0.0.1:8080");
-    while let Ok((stream, _)) = listener.accept().await {
-        let public_keys = public_keys.clone();
-        let average_list = averages.clone();
-        let clients_verified_clone = clients_verified.clone();
+
+    tokio::spawn(watch_deadlines(
+        rounds.clone(),
+        published.clone(),
+        config.clone(),
+    ));
+    tokio::spawn(registry::watch_registry(registry.clone(), rounds.clone()));
+
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let mut connections = match accept_on(listen, scheme).await {
+        Ok(connections) => connections,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    while let Some(stream) = connections.recv().await {
+        let config = config.clone();
+        let tls = tls.clone();
+        let registry = registry.clone();
+        let rounds = rounds.clone();
+        let replay_guard = replay_guard.clone();
+        let published = published.clone();
         let active_clients_clone = active_clients.clone();
-        let is_ready_clone = is_ready.clone();
-        let notify_clone = notify.clone();
         tokio::spawn(async move {
-            let mut ws_stream: tokio_tungstenite::WebSocketStream<TcpStream> = accept_async(stream)
+            let stream = match &tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => MaybeTlsStream::NativeTls(stream),
+                    Err(e) => {
+                        eprintln!("TLS handshake failed: {e}");
+                        return;
+                    }
+                },
+                None => MaybeTlsStream::Plain(stream),
+            };
+            let mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>> = accept_async(stream)
                 .await
                 .expect("Failed to accept WebSocket");
             println!("New client connected!");
@@ -39,61 +193,159 @@ pub async fn aggregator_process(num_clients: usize, public_keys: Arc<Vec<Verifyi
             // Read messages from the WebSocket stream
             while let Some(msg) = ws_stream.next().await {
                 match msg {
-                    Ok(Message::Text(ref text)) if text.contains("receiver") => {
-                        let data: Vec<_> = text.split(",").collect();
-                        let duration: u64 = data[1].parse().unwrap_or(20) + 10; // extra 10 sec wait in case of delay in connection
-                        match timeout(Duration::from_secs(duration), async {
-                            loop {
-                                {
-                                    let ready = is_ready_clone.lock().await;
-                                    if *ready {
-                                        break;
+                    Ok(Message::Text(text)) => match protocol::decode(&text) {
+                        Ok(WireMessage::Subscribe { duration, symbol }) => {
+                            let reply =
+                                wait_for_result(&rounds, &published, &symbol, duration).await;
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::ProveInclusion {
+                            symbol,
+                            round_id,
+                            client_id,
+                        }) => {
+                            let reply =
+                                inclusion_proof(&rounds, &published, &symbol, round_id, client_id)
+                                    .await;
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::CosignCommit {
+                            symbol,
+                            round_id,
+                            client_id,
+                            commitment,
+                        }) => {
+                            let recorded = match rounds.lock().await.get_mut(&symbol) {
+                                Some(coordinator) => coordinator
+                                    .record_commitment(round_id, client_id, commitment)
+                                    .map_err(rejected),
+                                None => Err(unknown_symbol(&symbol)),
+                            };
+                            let reply = recorded
+                                .map(|()| WireMessage::Ack { client_id })
+                                .unwrap_or_else(|reply| reply);
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::CosignRequest {
+                            symbol,
+                            round_id,
+                            client_id,
+                        }) => {
+                            let reply =
+                                cosign_package(&rounds, &published, &symbol, round_id, client_id)
+                                    .await;
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::CosignShare {
+                            symbol,
+                            round_id,
+                            client_id,
+                            share,
+                        }) => {
+                            let mut rounds = rounds.lock().await;
+                            let reply = match rounds.get_mut(&symbol) {
+                                Some(coordinator) => {
+                                    match coordinator.record_share(round_id, client_id, &share) {
+                                        Ok(settled) => {
+                                            if settled {
+                                                if let Some(round) =
+                                                    coordinator.published_round(round_id)
+                                                {
+                                                    persist(round, &coordinator.config().data_dir);
+                                                }
+                                                published.notify_waiters();
+                                            }
+                                            WireMessage::Ack { client_id }
+                                        }
+                                        Err(detail) => {
+                                            eprintln!("Rejected signature share: {detail}");
+                                            rejected(detail)
+                                        }
                                     }
                                 }
-                                notify_clone.notified().await; // Wait to be notified
-                            }
-                        })
-                        .await
-                        {
-                            Ok(_) => {
-                                // The loop exited either because of the `is_ready` condition being true
-                                println!("Exited the loop successfully within the duration.");
-                            }
-                            Err(_) => {
-                                // Timeout occurred
+                                None => unknown_symbol(&symbol),
+                            };
+                            drop(rounds);
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::RoundSummary(summary)) => {
+                            let reply = record_summary(&rounds, &published, summary).await;
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::CurrentRound { symbol }) => {
+                            let reply = match rounds.lock().await.get(&symbol) {
+                                Some(coordinator) => WireMessage::RoundInfo {
+                                    symbol,
+                                    round_id: coordinator.round_id(),
+                                    state: coordinator.state(),
+                                },
+                                None => unknown_symbol(&symbol),
+                            };
+                            send_reply(&mut ws_stream, &reply).await;
+                        }
+                        Ok(WireMessage::Submit(submission)) => {
+                            let mut locked = rounds.lock().await;
+                            let Some(coordinator) = locked.get_mut(&submission.symbol) else {
+                                drop(locked);
                                 eprintln!(
-                                    "Timeout reached after {} seconds while waiting for the aggregator to be ready.",
-                                    duration
+                                    "Client-{}: submission for unknown symbol {}",
+                                    submission.client_id, submission.symbol
                                 );
+                                send_reply(&mut ws_stream, &unknown_symbol(&submission.symbol))
+                                    .await;
+                                continue;
+                            };
+                            let outcome = process_message(
+                                &submission,
+                                &registry.snapshot(),
+                                &mut *replay_guard.lock().await,
+                                coordinator.round_id(),
+                            );
+                            let closed = coordinator.record(&outcome, Instant::now());
+                            drop(locked);
+                            if let Some(round) = closed {
+                                announce(&round, &config, &rounds, &published);
+                                published.notify_waiters();
                             }
+                            let reply = match outcome {
+                                Verification::Verified { client_id, .. } => {
+                                    WireMessage::Ack { client_id }
+                                }
+                                rejected => {
+                                    eprintln!("Rejected submission: {}", rejected.describe());
+                                    WireMessage::Error {
+                                        code: ErrorCode::Rejected,
+                                        detail: rejected.describe(),
+                                    }
+                                }
+                            };
+                            send_reply(&mut ws_stream, &reply).await;
                         }
-                        let mut avg_vec = average_list.lock().await;
-                        let averages_copy = avg_vec.clone(); // Clone the data
-                        avg_vec.clear();
-                        let mut cv = clients_verified_clone.lock().await;
-                        *cv = 0;
-                        if let Some(global_avg) = utils::calculate_average(&averages_copy) {
-                            println!("Aggregator: Global average BTC price: {:.4}", global_avg);
-                            let response = format!("Global average BTC price: {:.4}", global_avg);
-                            if let Err(e) = ws_stream.send(Message::Text(response)).await {
-                                eprintln!("Failed to send message to client: {}", e);
-                            }
-                            utils::save_global_data(&averages_copy, global_avg).unwrap_or_else(
-                                |e| eprintln!("Aggregator: Failed to save global data: {e}"),
-                            );
-                        } else {
-                            eprintln!("Aggregator: No valid averages received.");
+                        Ok(other) => {
+                            eprintln!("Unexpected message from client: {:?}", other);
+                            let reply = WireMessage::Error {
+                                code: ErrorCode::UnexpectedMessage,
+                                detail:
+                                    "aggregator only accepts submit, subscribe, current_round, prove_inclusion, cosign and round_summary messages"
+                                        .to_string(),
+                            };
+                            send_reply(&mut ws_stream, &reply).await;
                         }
-                        let mut ready = is_ready_clone.lock().await;
-                        *ready = false;
-                    }
-                    Ok(Message::Text(text)) => match process_message(&text, &public_keys).await {
-                        Err(e) => eprintln!("Error processing message: {}", e),
-                        Ok(avg) => {
-                            let mut cv = clients_verified_clone.lock().await;
-                            *cv += 1;
-                            let mut avg_vec = average_list.lock().await;
-                            avg_vec.push(avg);
+                        Err(e) => {
+                            eprintln!("Error decoding message: {}", e);
+                            if let ProtocolError::Malformed(reason) = &e {
+                                let outcome = Verification::Malformed {
+                                    reason: reason.clone(),
+                                };
+                                // Tally it against the symbol's round when the frame names one.
+                                let mut rounds = rounds.lock().await;
+                                if let Some(coordinator) =
+                                    symbol_hint(&text).and_then(|symbol| rounds.get_mut(&symbol))
+                                {
+                                    coordinator.record(&outcome, Instant::now());
+                                }
+                            }
+                            send_reply(&mut ws_stream, &WireMessage::from_error(&e)).await;
                         }
                     },
                     Err(e) => {
@@ -105,58 +357,580 @@ pub async fn aggregator_process(num_clients: usize, public_keys: Arc<Vec<Verifyi
                     }
                 }
             }
-            let cv = clients_verified_clone.lock().await;
             active_clients_clone.fetch_sub(1, Ordering::SeqCst);
             eprintln!(
-                "Client disconnected. active: {} count: {}",
-                active_clients_clone.load(Ordering::SeqCst),
-                cv
+                "Client disconnected. active: {}",
+                active_clients_clone.load(Ordering::SeqCst)
             );
+        });
+    }
+}
 
-            if *cv >= num_clients {
-                let mut ready = is_ready_clone.lock().await;
-                *ready = true;
-                notify_clone.notify_one();
+/// Bind every address in `listen` and pass on the connections accepted on any of them.
+/// Fails if one cannot be bound, so a typo does not leave the aggregator half reachable.
+async fn accept_on(
+    listen: &[String],
+    scheme: &str,
+) -> Result<mpsc::UnboundedReceiver<TcpStream>, String> {
+    if listen.is_empty() {
+        return Err("No address to listen on".to_string());
+    }
+    let mut listeners = Vec::new();
+    for address in listen {
+        let listener = TcpListener::bind(address.as_str())
+            .await
+            .map_err(|e| format!("Failed to bind to {address}: {e}"))?;
+        let local = listener
+            .local_addr()
+            .map_or_else(|_| address.clone(), |local| local.to_string());
+        println!("Aggregator WebSocket server listening on {scheme}://{local}");
+        listeners.push(listener);
+    }
+    let (sender, connections) = mpsc::unbounded_channel();
+    for listener in listeners {
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if sender.send(stream).is_err() {
+                    break;
+                }
             }
         });
     }
+    Ok(connections)
+}
+
+/// Close rounds whose deadline has passed, waking any waiting receivers.
+async fn watch_deadlines(
+    rounds: Arc<Mutex<SymbolRounds>>,
+    published: Arc<Notify>,
+    config: RoundConfig,
+) {
+    let mut ticker = interval(Duration::from_millis(250));
+    loop {
+        ticker.tick().await;
+        let mut locked = rounds.lock().await;
+        let closed = locked.poll_deadlines(Instant::now());
+        let settled = locked.poll_settle_deadlines(Instant::now());
+        drop(locked);
+        for round in &closed {
+            announce(round, &config, &rounds, &published);
+        }
+        if !closed.is_empty() || settled {
+            published.notify_waiters();
+        }
+    }
+}
+
+fn rejected(detail: String) -> WireMessage {
+    WireMessage::Error {
+        code: ErrorCode::Rejected,
+        detail,
+    }
+}
+
+fn unknown_symbol(symbol: &str) -> WireMessage {
+    WireMessage::Error {
+        code: ErrorCode::UnknownSymbol,
+        detail: format!("no rounds are run for symbol {symbol}"),
+    }
+}
+
+/// The `symbol` field of a frame that failed to decode, if it has one.
+fn symbol_hint(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    value.get("symbol")?.as_str().map(str::to_string)
 }
 
-async fn process_message(
-    msg: &str,
-    public_keys: &[VerifyingKey],
-) -> Result<f64, Box<dyn std::error::Error + Send>> {
-    let data: Value = match serde_json::from_str(msg) {
-        Ok(val) => val,
-        Err(e) => return Err(Box::new(e) as Box<dyn std::error::Error + Send>),
+/// Log and persist a freshly published round, and send it to the federation peers.
+fn announce(
+    round: &PublishedRound,
+    config: &RoundConfig,
+    rounds: &Arc<Mutex<SymbolRounds>>,
+    published: &Arc<Notify>,
+) {
+    println!("Aggregator: {}", round.result);
+    persist(round, &config.data_dir);
+    if let Some(federation) = &config.federation {
+        tokio::spawn(peers::share_summary(
+            round.result.clone(),
+            federation.clone(),
+            config.peer_tls.clone(),
+            rounds.clone(),
+            published.clone(),
+        ));
+    }
+}
+
+/// Compare a federation peer's round summary with this aggregator's result. The reply is
+/// this aggregator's own result for the round, once published.
+pub async fn record_summary(
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+    summary: GlobalResult,
+) -> WireMessage {
+    let (symbol, round_id) = (summary.symbol.clone(), summary.round_id);
+    let mut rounds = rounds.lock().await;
+    let Some(coordinator) = rounds.get_mut(&symbol) else {
+        return unknown_symbol(&symbol);
     };
+    match coordinator.record_summary(summary) {
+        Ok(SummaryOutcome::Compared { own, settled }) => {
+            if settled {
+                if let Some(round) = coordinator.published_round(round_id) {
+                    persist(round, &coordinator.config().data_dir);
+                }
+                published.notify_waiters();
+            }
+            WireMessage::RoundSummary(*own)
+        }
+        Ok(SummaryOutcome::Held) => WireMessage::Error {
+            code: ErrorCode::NotFound,
+            detail: format!("{symbol} round {round_id} is not published here yet"),
+        },
+        Err(detail) => {
+            eprintln!("Federation: Rejected {symbol} round {round_id} summary: {detail}");
+            rejected(detail)
+        }
+    }
+}
 
-    let client_id = data["client_id"].as_u64().unwrap() as usize;
-    let message = data["message"].as_str().unwrap();
+/// Save the round's result in `data_dir`, again once it gains a group signature or becomes
+/// final. Rounds without an average are saved too, to keep their rejections.
+fn persist(round: &PublishedRound, data_dir: &Path) {
+    utils::save_global_data(data_dir, &round.result, &round.values, &round.rejected)
+        .unwrap_or_else(|e| eprintln!("Aggregator: Failed to save global data: {e}"));
+}
 
-    // Convert Vec<u8> to [u8; 64]
-    let signature_vec = match general_purpose::STANDARD.decode(data["signature"].as_str().unwrap())
+/// Wait until the symbol's round open at subscription time, or the first one after it if
+/// nobody submits to it before its clock slot ends, is published.
+async fn wait_for_result(
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+    symbol: &str,
+    duration: u64,
+) -> WireMessage {
+    let (round_id, deadline) = match rounds.lock().await.get(symbol) {
+        Some(coordinator) => (coordinator.round_id(), coordinator.config().deadline),
+        None => return unknown_symbol(symbol),
+    };
+    // The clients listen for `duration` seconds before submitting, then the round may
+    // collect until its deadline, and be co-signed and cross-checked by the federation for
+    // another deadline; allow an extra 10 sec in case of delay in connection.
+    let wait = Duration::from_secs(duration + 10) + deadline * 2;
+    match wait_for_round(rounds, published, symbol, round_id, wait, true).await {
+        Ok(round) => WireMessage::GlobalResult(round.result),
+        Err(reply) => *reply,
+    }
+}
+
+/// Prove that the client's accepted submission is part of the round, once it is published.
+async fn inclusion_proof(
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+    symbol: &str,
+    round_id: u64,
+    client_id: usize,
+) -> WireMessage {
+    let deadline = match rounds.lock().await.get(symbol) {
+        Some(coordinator) => coordinator.config().deadline,
+        None => return unknown_symbol(symbol),
+    };
+    let round = match wait_for_round(
+        rounds,
+        published,
+        symbol,
+        round_id,
+        deadline + Duration::from_secs(10),
+        false,
+    )
+    .await
     {
-        Ok(sig) => sig,
-        Err(e) => return Err(Box::new(e) as Box<dyn std::error::Error + Send>),
+        Ok(round) => round,
+        Err(reply) => return *reply,
+    };
+    match round.inclusion_proof(client_id) {
+        Some(proof) => WireMessage::InclusionProof {
+            client_id,
+            result: round.result,
+            proof,
+        },
+        None => WireMessage::Error {
+            code: ErrorCode::NotFound,
+            detail: format!(
+                "client {client_id} has no accepted submission in {symbol} round {round_id}"
+            ),
+        },
+    }
+}
+
+/// Co-signing package of a round, once it is published.
+async fn cosign_package(
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+    symbol: &str,
+    round_id: u64,
+    client_id: usize,
+) -> WireMessage {
+    let deadline = match rounds.lock().await.get(symbol) {
+        Some(coordinator) => coordinator.config().deadline,
+        None => return unknown_symbol(symbol),
+    };
+    let wait = deadline + Duration::from_secs(10);
+    if let Err(reply) = wait_for_round(rounds, published, symbol, round_id, wait, false).await {
+        return *reply;
+    }
+    match rounds.lock().await.get(symbol) {
+        Some(coordinator) => match coordinator.cosign_package(round_id, client_id) {
+            Ok((result, commitments)) => WireMessage::CosignPackage {
+                result,
+                commitments: commitments.into_iter().collect(),
+            },
+            Err((code, detail)) => WireMessage::Error { code, detail },
+        },
+        None => unknown_symbol(symbol),
+    }
+}
+
+/// Wait up to `wait` for the symbol's round `round_id` to be published, and with
+/// `until_settled` also co-signed and cross-checked. Subscribers wait until settled and take
+/// a later round if `round_id` is skipped. The error is the reply to send instead.
+async fn wait_for_round(
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+    symbol: &str,
+    round_id: u64,
+    wait: Duration,
+    until_settled: bool,
+) -> Result<PublishedRound, Box<WireMessage>> {
+    let result = timeout(wait, async {
+        loop {
+            let notified = published.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let rounds = rounds.lock().await;
+            let Some(coordinator) = rounds.get(symbol) else {
+                return Err(Box::new(unknown_symbol(symbol)));
+            };
+            let round = if until_settled {
+                coordinator.published_from(round_id)
+            } else {
+                coordinator.published_round(round_id)
+            };
+            match round {
+                Some(round) if round.settled() || !until_settled => return Ok(round.clone()),
+                Some(_) => {}
+                None if round_id < coordinator.round_id() && !until_settled => {
+                    return Err(Box::new(WireMessage::Error {
+                        code: ErrorCode::NotFound,
+                        detail: format!("{symbol} round {round_id} is no longer kept"),
+                    }));
+                }
+                None => {}
+            }
+            drop(rounds);
+            notified.await;
+        }
+    })
+    .await;
+    result.unwrap_or_else(|_| {
+        eprintln!(
+            "Timeout reached after {} seconds while waiting for {symbol} round {round_id}.",
+            wait.as_secs()
+        );
+        Err(Box::new(WireMessage::Error {
+            code: ErrorCode::Timeout,
+            detail: format!("{symbol} round {round_id} was not published in time"),
+        }))
+    })
+}
+
+/// Send a protocol reply, logging rather than failing if the peer is gone.
+async fn send_reply(
+    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    reply: &WireMessage,
+) {
+    if let Err(e) = ws_stream.send(Message::Text(reply.to_text())).await {
+        eprintln!("Failed to send message to client: {}", e);
+    }
+}
+
+/// Verify a signed client submission and classify the outcome.
+fn process_message(
+    submission: &Submission,
+    registry: &KeyRegistry,
+    replay_guard: &mut ReplayGuard,
+    current_round: u64,
+) -> Verification {
+    let malformed = |reason: &str| Verification::Malformed {
+        reason: reason.to_string(),
     };
+    let client_id = submission.client_id;
+    if let Err(reason) = submission.check_fields() {
+        return malformed(&reason);
+    }
 
-    let signature_array: &[u8; 64] = signature_vec
-        .as_slice()
-        .try_into()
-        .expect("Invalid signature");
+    // Convert Vec<u8> to [u8; 64]
+    let signature_vec = match general_purpose::STANDARD.decode(&submission.signature) {
+        Ok(sig) => sig,
+        Err(e) => return malformed(&e.to_string()),
+    };
+    let Ok(signature_array) = <[u8; 64]>::try_from(signature_vec.as_slice()) else {
+        return malformed("signature must be 64 bytes");
+    };
+    let signature = Signature::from_bytes(&signature_array);
 
-    let signature = Signature::from_bytes(signature_array);
+    let Some(record) = registry
+        .by_key_id(&submission.key_id)
+        .filter(|record| record.client_id == client_id)
+    else {
+        return Verification::UnknownClient { client_id };
+    };
+    if !record.enabled {
+        return Verification::DisabledClient { client_id };
+    }
 
     // Verify the signature using the client's public key
-    if public_keys[client_id - 1]
-        .verify(message.as_bytes(), &signature)
-        .is_ok()
-    {
-        println!("Verified message from Client-{client_id}: {}", message);
-    } else {
-        eprintln!("Failed to verify signature for Client-{client_id}");
+    let payload = submission.signing_payload();
+    if record.key.verify(payload.as_bytes(), &signature).is_err() {
+        return Verification::BadSignature { client_id };
+    }
+    if !record.validity.contains(submission.timestamp) {
+        return Verification::Stale {
+            client_id,
+            reason: format!(
+                "key {} is not valid at {}",
+                record.key_id, submission.timestamp
+            ),
+        };
+    }
+    let message = submission.message.as_str();
+    let Ok(value) = message.parse::<f64>() else {
+        return malformed("message is not a number");
+    };
+    // "NaN" and "inf" parse too, and would poison every average of the round.
+    if !value.is_finite() || value <= 0.0 {
+        return malformed("price must be a positive number");
     }
 
-    Ok(message.parse::<f64>().unwrap())
+    if !submission.volume.is_finite() || submission.volume < 0.0 {
+        return malformed("volume must be a non-negative number");
+    }
+    // Admit last, so a rejected submission does not use up the nonce and round its resend
+    // needs.
+    if let Err(rejected) = replay_guard.admit(submission, current_round, utils::unix_millis()) {
+        return rejected;
+    }
+
+    println!(
+        "Verified message from Client-{client_id}: {} (volume {})",
+        message, submission.volume
+    );
+    Verification::Verified {
+        client_id,
+        value,
+        volume: submission.volume,
+        leaf: submission.leaf_hash(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use mcs_binary::keys::ClientKeyFile;
+
+    /// Client 1's key and a registry holding it.
+    fn client() -> (SigningKey, String, KeyRegistry) {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let key_file = ClientKeyFile::new(1, &key);
+        let registry = KeyRegistry::from_entries(key_file.registry_entries(0).unwrap()).unwrap();
+        (key, key_file.keys[0].key_id.clone(), registry)
+    }
+
+    /// A submission of `message` for `round_id`, signed with `key`.
+    fn submission(
+        key: &SigningKey,
+        key_id: &str,
+        round_id: u64,
+        nonce: u64,
+        message: &str,
+    ) -> Submission {
+        let mut submission = Submission {
+            client_id: 1,
+            key_id: key_id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            round_id,
+            timestamp: utils::unix_millis(),
+            nonce,
+            message: message.to_string(),
+            volume: 1.5,
+            signature: String::new(),
+        };
+        let signature = key.sign(submission.signing_payload().as_bytes());
+        submission.signature = general_purpose::STANDARD.encode(signature.to_bytes());
+        submission
+    }
+
+    #[test]
+    fn delimiters_in_signed_fields_are_malformed() {
+        let (key, key_id, registry) = client();
+        let mut guard = ReplayGuard::default();
+        // Signed as `...|symbol=BTC|round=5|...`, this would read like `BTC` for round 5.
+        let mut shifted = submission(&key, &key_id, 5, 1, "100.0");
+        shifted.symbol = "BTC|round=5".to_string();
+        let in_value = submission(&key, &key_id, 5, 2, "100.0|volume=9");
+        for submission in [shifted, in_value] {
+            let outcome = process_message(&submission, &registry, &mut guard, 5);
+            assert!(
+                matches!(&outcome, Verification::Malformed { reason } if reason.contains("'|'")),
+                "{outcome:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn keys_rotated_out_are_stale_after_the_overlap() {
+        let (old, new) = (
+            SigningKey::from_bytes(&[7; 32]),
+            SigningKey::from_bytes(&[8; 32]),
+        );
+        let mut key_file = ClientKeyFile::new(1, &old);
+        key_file.rotate(&new, utils::unix_millis() - 30_000, 10_000);
+        let registry = KeyRegistry::from_entries(key_file.registry_entries(0).unwrap()).unwrap();
+        let (old_id, new_id) = (&key_file.keys[0].key_id, &key_file.keys[1].key_id);
+        let mut guard = ReplayGuard::default();
+
+        let outcome = process_message(
+            &submission(&old, old_id, 5, 1, "100.0"),
+            &registry,
+            &mut guard,
+            5,
+        );
+        assert!(matches!(outcome, Verification::Stale { .. }), "{outcome:?}");
+        let outcome = process_message(
+            &submission(&new, new_id, 5, 2, "100.0"),
+            &registry,
+            &mut guard,
+            5,
+        );
+        assert!(
+            matches!(outcome, Verification::Verified { .. }),
+            "{outcome:?}"
+        );
+    }
+
+    #[test]
+    fn non_finite_and_non_positive_prices_are_malformed() {
+        let (key, key_id, registry) = client();
+        let mut guard = ReplayGuard::default();
+        for (nonce, message) in ["NaN", "inf", "-inf", "0", "-1.5"].into_iter().enumerate() {
+            let submission = submission(&key, &key_id, 5, nonce as u64, message);
+            let outcome = process_message(&submission, &registry, &mut guard, 5);
+            assert!(
+                matches!(outcome, Verification::Malformed { .. }),
+                "{message}: {outcome:?}"
+            );
+        }
+        let submission = submission(&key, &key_id, 5, 9, "100.5");
+        let outcome = process_message(&submission, &registry, &mut guard, 5);
+        assert!(
+            matches!(outcome, Verification::Verified { value, .. } if value == 100.5),
+            "{outcome:?}"
+        );
+    }
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    /// An unsigned submission of client 1 for `symbol`, which the replay guard does not check.
+    fn timed(symbol: &str, round_id: u64, nonce: u64, timestamp: u64) -> Submission {
+        Submission {
+            client_id: 1,
+            key_id: String::new(),
+            symbol: symbol.to_string(),
+            round_id,
+            timestamp,
+            nonce,
+            message: "100.0".to_string(),
+            volume: 1.0,
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn replay_guard_rejects_a_duplicate_nonce() {
+        let mut guard = ReplayGuard::default();
+        guard.admit(&timed("BTCUSDT", 5, 1, NOW), 5, NOW).unwrap();
+        // Even for another symbol, in the next round.
+        let outcome = guard.admit(&timed("ETHUSDT", 6, 1, NOW + 1_000), 6, NOW + 1_000);
+        assert!(
+            matches!(&outcome, Err(Verification::Replayed { reason, .. }) if reason.contains("nonce 1")),
+            "{outcome:?}"
+        );
+        // Once the first use is outside the clock window, the nonce can be used again.
+        let later = NOW + MAX_CLOCK_SKEW_MS + 1;
+        guard
+            .admit(&timed("BTCUSDT", 7, 1, later), 7, later)
+            .unwrap();
+    }
+
+    #[test]
+    fn replay_guard_rejects_timestamps_outside_the_clock_window() {
+        let mut guard = ReplayGuard::default();
+        for timestamp in [NOW - MAX_CLOCK_SKEW_MS - 1, NOW + MAX_CLOCK_SKEW_MS + 1] {
+            let outcome = guard.admit(&timed("BTCUSDT", 5, timestamp, timestamp), 5, NOW);
+            assert!(
+                matches!(&outcome, Err(Verification::Stale { reason, .. }) if reason.contains("clock window")),
+                "{outcome:?}"
+            );
+        }
+        for (nonce, timestamp) in [(1, NOW - MAX_CLOCK_SKEW_MS), (2, NOW + MAX_CLOCK_SKEW_MS)] {
+            let symbol = format!("PAIR{nonce}");
+            guard
+                .admit(&timed(&symbol, 5, nonce, timestamp), 5, NOW)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn replay_guard_rejects_stale_and_repeated_rounds() {
+        let mut guard = ReplayGuard::default();
+        guard.admit(&timed("BTCUSDT", 5, 1, NOW), 5, NOW).unwrap();
+
+        // Signed for a round other than the current one.
+        for round_id in [4, 6] {
+            let outcome = guard.admit(&timed("BTCUSDT", round_id, 2, NOW), 5, NOW);
+            assert!(
+                matches!(outcome, Err(Verification::Stale { .. })),
+                "{outcome:?}"
+            );
+        }
+        // The same round again, with a fresh nonce.
+        let outcome = guard.admit(&timed("BTCUSDT", 5, 2, NOW), 5, NOW);
+        assert!(
+            matches!(&outcome, Err(Verification::Replayed { reason, .. }) if reason.contains("round 5")),
+            "{outcome:?}"
+        );
+        // A round before the last one submitted to, should the current round go back.
+        let outcome = guard.admit(&timed("BTCUSDT", 4, 3, NOW), 4, NOW);
+        assert!(
+            matches!(outcome, Err(Verification::Replayed { .. })),
+            "{outcome:?}"
+        );
+        // Rounds are tracked per symbol.
+        guard.admit(&timed("ETHUSDT", 5, 4, NOW), 5, NOW).unwrap();
+        guard.admit(&timed("BTCUSDT", 6, 5, NOW), 6, NOW).unwrap();
+    }
+
+    #[test]
+    fn rejected_submissions_do_not_consume_their_nonce() {
+        let mut guard = ReplayGuard::default();
+        let stale = NOW - MAX_CLOCK_SKEW_MS - 1;
+        assert!(guard.admit(&timed("BTCUSDT", 5, 1, stale), 5, NOW).is_err());
+        assert!(guard.admit(&timed("BTCUSDT", 4, 1, NOW), 5, NOW).is_err());
+        guard.admit(&timed("BTCUSDT", 5, 1, NOW), 5, NOW).unwrap();
+
+        assert!(guard.admit(&timed("BTCUSDT", 5, 2, NOW), 5, NOW).is_err());
+        guard.admit(&timed("BTCUSDT", 6, 2, NOW), 6, NOW).unwrap();
+    }
 }
@@ -1,14 +1,218 @@
-use mcs_binary::utils;
+use mcs_binary::aggregation::{self, Mean};
+use mcs_binary::config::{self, Config};
+use mcs_binary::federation::{self, Federation};
+use mcs_binary::{frost, keys, merkle, protocol, tls, utils};
+use registry::SharedRegistry;
+use round::{PartialPolicy, Quorum, RoundConfig};
+use std::process;
 use std::sync::Arc;
+use tokio::time::Duration;
 mod aggregator;
+mod peers;
+mod registry;
+mod round;
 
 #[tokio::main]
 async fn main() {
-    let num_clients = 5; // Adjust for multiple client instances
-    let keypairs = utils::generate_keypairs(num_clients);
-    utils::save_keys(keypairs.clone(), "client_keys.json").expect("Failed to save keys");
-    let public_keys = utils::load_public_keys("client_keys.json");
-    let public_keys = Arc::new(public_keys);
+    let matches = utils::parse_aggregator_arguments();
+    let config = Config::load(&utils::aggregator_command(), &matches, |name| {
+        std::env::var(name).ok()
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to load configuration: {e}");
+        process::exit(1);
+    });
+    let problems = problems(&config);
+    if matches.subcommand_matches("config").is_some() {
+        process::exit(config::check(&config, &problems));
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("Invalid configuration: {problem}");
+        }
+        process::exit(1);
+    }
 
-    aggregator::aggregator_process(num_clients, public_keys).await;
+    let registry = config.keys.registry.as_str();
+    let registry = match SharedRegistry::load(registry) {
+        Ok(shared) => {
+            let loaded = shared.snapshot();
+            println!(
+                "Loaded {} public keys ({} clients enabled) from {registry}.",
+                loaded.len(),
+                loaded.enabled_count()
+            );
+            shared
+        }
+        Err(e) => {
+            eprintln!("Failed to load key registry: {e}");
+            if let Some(hint) = keys::legacy_keys_hint() {
+                eprintln!("{hint}");
+            }
+            process::exit(1);
+        }
+    };
+
+    let signing_key = match keys::read_aggregator_key(&config.keys.signing_key) {
+        Ok(key) => {
+            println!(
+                "Signing results with aggregator key {}.",
+                keys::key_id(&key.verifying_key())
+            );
+            Arc::new(key)
+        }
+        Err(e) => {
+            eprintln!("Failed to load aggregator signing key: {e}");
+            process::exit(1);
+        }
+    };
+
+    let cosign = if config.aggregation.cosign {
+        match frost::read_group(&config.keys.group) {
+            Ok(loaded) => {
+                println!(
+                    "Results are co-signed by any {} of {} clients, group key {}.",
+                    loaded.threshold,
+                    loaded.verifying_shares.len(),
+                    loaded
+                        .verifying_key()
+                        .map(|key| keys::key_id(&key))
+                        .unwrap_or_default()
+                );
+                Some(Arc::new(loaded))
+            }
+            Err(e) => {
+                eprintln!("Failed to load threshold group: {e}");
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let network = &config.network;
+    let acceptor = match (&network.tls_cert, &network.tls_key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert, key) {
+            Ok(acceptor) => {
+                println!("Serving wss:// with the certificate in {}.", cert.display());
+                Some(acceptor)
+            }
+            Err(e) => {
+                eprintln!("Failed to load TLS certificate: {e}");
+                process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let peer_tls = network.tls_ca.as_deref().map(|ca| {
+        tls::connector(ca).unwrap_or_else(|e| {
+            eprintln!("Failed to load TLS CA certificates: {e}");
+            process::exit(1);
+        })
+    });
+
+    let own_key = keys::key_id(&signing_key.verifying_key());
+    let (federation, listen) = match &config.network.federation {
+        Some(path) => match load_federation(path, &own_key, acceptor.is_some()) {
+            Ok((loaded, listen)) => {
+                println!(
+                    "Cross-checking results with {} federated aggregators, final once {} agree.",
+                    loaded.members.len(),
+                    loaded.majority
+                );
+                (Some(Arc::new(loaded)), listen)
+            }
+            Err(e) => {
+                eprintln!("Failed to load federation: {e}");
+                process::exit(1);
+            }
+        },
+        None => (None, utils::DEFAULT_LISTEN.to_string()),
+    };
+    // Explicit addresses win over the federation member URL.
+    let listen = config
+        .network
+        .listen
+        .clone()
+        .unwrap_or_else(|| vec![listen]);
+
+    // Checked above, so the fallbacks are never taken.
+    let aggregation = &config.aggregation;
+    let quorum: Quorum = match &aggregation.quorum {
+        Some(value) => value.parse().unwrap_or(Quorum::Fraction(1.0)),
+        None => Quorum::Fraction(1.0),
+    };
+    let partial: PartialPolicy = aggregation
+        .partial
+        .parse()
+        .unwrap_or(PartialPolicy::Degraded);
+    let method = aggregation::from_spec(&aggregation.method).unwrap_or_else(|_| Arc::new(Mean));
+    let round_config = RoundConfig {
+        deadline: Duration::from_secs(aggregation.deadline),
+        quorum,
+        partial,
+        method,
+        signing_key,
+        cosign,
+        federation,
+        data_dir: config.storage.data_dir.clone(),
+        peer_tls,
+        clock: utils::unix_millis,
+    };
+
+    aggregator::aggregator_process(round_config, config.symbols(), registry, &listen, acceptor)
+        .await;
+}
+
+/// Every problem with the configuration, including the settings only the aggregator reads.
+fn problems(config: &Config) -> Vec<String> {
+    let mut problems = config.problems();
+    if let Some(Err(e)) = config
+        .aggregation
+        .quorum
+        .as_ref()
+        .map(|q| q.parse::<Quorum>())
+    {
+        problems.push(format!("aggregation.quorum: {e}"));
+    }
+    if let Err(e) = config.aggregation.partial.parse::<PartialPolicy>() {
+        problems.push(format!("aggregation.partial: {e}"));
+    }
+    problems
+}
+
+/// Read the federation and find this aggregator in it by its key id; returns the address
+/// its member URL asks it to listen on. A `wss://` member URL needs a TLS certificate.
+fn load_federation(
+    path: &str,
+    own_key: &str,
+    serves_tls: bool,
+) -> Result<(Federation, String), String> {
+    let federation = federation::read_federation(path).map_err(|e| e.to_string())?;
+    let member = federation.member(own_key).ok_or_else(|| {
+        format!("aggregator key {own_key} is not a member of the federation in {path}")
+    })?;
+    let rest = match (member.url.split_once("://"), serves_tls) {
+        (Some(("ws", rest)), false) | (Some(("wss", rest)), true) => rest,
+        (Some(("wss", _)), false) => {
+            return Err(format!(
+                "member URL {} is wss:// but no TLS certificate is given",
+                member.url
+            ))
+        }
+        (Some(("ws", _)), true) => {
+            return Err(format!(
+                "member URL {} is ws:// but a TLS certificate is given",
+                member.url
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "member URL {} must start with ws:// or wss://",
+                member.url
+            ))
+        }
+    };
+    let address = rest.split('/').next().unwrap_or(rest).to_string();
+    Ok((federation, address))
 }
@@ -0,0 +1,82 @@
+use crate::aggregator;
+use crate::federation::Federation;
+use crate::protocol::{self, ErrorCode, GlobalResult, WireMessage};
+use crate::round::SymbolRounds;
+use crate::tls;
+use futures::{SinkExt, StreamExt};
+use native_tls::TlsConnector;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// How long a peer gets to accept a connection and to answer a summary.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Push a freshly published result to every other federation member. A member that has
+/// published the same round answers with its own result, which is compared like a pushed one.
+pub async fn share_summary(
+    result: GlobalResult,
+    federation: Arc<Federation>,
+    tls: Option<TlsConnector>,
+    rounds: Arc<Mutex<SymbolRounds>>,
+    published: Arc<Notify>,
+) {
+    let exchanges = federation
+        .members
+        .iter()
+        .filter(|member| member.key_id() != result.aggregator_key)
+        .map(|member| exchange(&member.url, tls.as_ref(), &result, &rounds, &published));
+    futures::future::join_all(exchanges).await;
+}
+
+async fn exchange(
+    url: &str,
+    tls: Option<&TlsConnector>,
+    summary: &GlobalResult,
+    rounds: &Mutex<SymbolRounds>,
+    published: &Notify,
+) {
+    let (symbol, round_id) = (&summary.symbol, summary.round_id);
+    match send_summary(url, tls, summary).await {
+        Ok(WireMessage::RoundSummary(theirs)) => {
+            aggregator::record_summary(rounds, published, theirs).await;
+        }
+        // The peer compares once it publishes the round, and then pushes its own result.
+        Ok(WireMessage::Error {
+            code: ErrorCode::NotFound,
+            ..
+        }) => {}
+        Ok(WireMessage::Error { code, detail }) => {
+            eprintln!("Federation: {url} refused {symbol} round {round_id} ({code:?}): {detail}")
+        }
+        Ok(other) => eprintln!("Federation: Unexpected reply from {url}: {other:?}"),
+        Err(e) => eprintln!("Federation: Cannot send {symbol} round {round_id} to {url}: {e}"),
+    }
+}
+
+/// Send the summary over a fresh connection, so peers may restart between rounds, and
+/// return the peer's reply.
+async fn send_summary(
+    url: &str,
+    tls: Option<&TlsConnector>,
+    summary: &GlobalResult,
+) -> Result<WireMessage, String> {
+    let mut ws_stream = timeout(PEER_TIMEOUT, tls::connect(url, tls))
+        .await
+        .map_err(|_| "connection timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    ws_stream
+        .send(Message::Text(
+            WireMessage::RoundSummary(summary.clone()).to_text(),
+        ))
+        .await
+        .map_err(|e| e.to_string())?;
+    let reply = match timeout(PEER_TIMEOUT, ws_stream.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => protocol::decode(&text).map_err(|e| e.to_string()),
+        Ok(_) => Err("connection closed before a reply".to_string()),
+        Err(_) => Err("no reply".to_string()),
+    };
+    let _ = ws_stream.close(None).await;
+    reply
+}
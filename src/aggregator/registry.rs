@@ -0,0 +1,111 @@
+use crate::keys::{KeyError, KeyRegistry};
+use crate::round::SymbolRounds;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{interval, Duration};
+
+/// How often the registry file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The registry file and the keys currently loaded from it. A reload swaps in the whole new
+/// registry at once, so each submission is checked against one consistent set of keys.
+#[derive(Debug, Clone)]
+pub struct SharedRegistry {
+    path: String,
+    current: Arc<RwLock<Arc<KeyRegistry>>>,
+}
+
+impl SharedRegistry {
+    pub fn load(path: &str) -> Result<Self, KeyError> {
+        Ok(SharedRegistry {
+            path: path.to_string(),
+            current: Arc::new(RwLock::new(Arc::new(KeyRegistry::load(path)?))),
+        })
+    }
+
+    /// The registry in effect right now.
+    pub fn snapshot(&self) -> Arc<KeyRegistry> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Load the registry file again and swap it in. On error the current keys stay in place.
+    fn reload(&self) -> Result<Arc<KeyRegistry>, KeyError> {
+        let loaded = Arc::new(KeyRegistry::load(&self.path)?);
+        let previous = std::mem::replace(
+            &mut *self.current.write().unwrap_or_else(|e| e.into_inner()),
+            loaded.clone(),
+        );
+        let enabled = |registry: &KeyRegistry| -> HashSet<String> {
+            registry
+                .iter()
+                .filter(|record| record.enabled)
+                .map(|record| record.key_id.clone())
+                .collect()
+        };
+        let (before, after) = (enabled(&previous), enabled(&loaded));
+        println!(
+            "Reloaded {}: {} keys ({} enabled), {} added, {} revoked.",
+            self.path,
+            loaded.len(),
+            after.len(),
+            after.difference(&before).count(),
+            before.difference(&after).count()
+        );
+        Ok(loaded)
+    }
+}
+
+/// Reload the registry whenever its file changes or the aggregator receives SIGHUP, and
+/// resize the quorum of every symbol's rounds to the new number of enabled clients.
+pub async fn watch_registry(registry: SharedRegistry, rounds: Arc<Mutex<SymbolRounds>>) {
+    let hangup = Arc::new(Notify::new());
+    #[cfg(unix)]
+    tokio::spawn(forward_hangups(hangup.clone()));
+
+    let mut last_contents = contents_hash(&registry.path);
+    let mut ticker = interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = hangup.notified() => println!("Received SIGHUP, reloading key registry."),
+            _ = ticker.tick() => {
+                let contents = contents_hash(&registry.path);
+                if contents == last_contents {
+                    continue;
+                }
+                last_contents = contents;
+                println!("{} changed, reloading key registry.", registry.path);
+            }
+        }
+        match registry.reload() {
+            Ok(loaded) => rounds.lock().await.set_registered(loaded.enabled_count()),
+            Err(e) => eprintln!("Failed to reload key registry, keeping current keys: {e}"),
+        }
+    }
+}
+
+/// Hash of the file's contents. Unlike its modification time, this changes with every write,
+/// however quickly writes follow each other.
+fn contents_hash(path: &str) -> Option<[u8; 32]> {
+    fs::read(path).ok().map(|data| Sha256::digest(data).into())
+}
+
+#[cfg(unix)]
+async fn forward_hangups(hangup: Arc<Notify>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        hangup.notify_one();
+    }
+}
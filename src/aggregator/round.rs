@@ -0,0 +1,1024 @@
+use crate::aggregation::{Aggregator, Contribution};
+use crate::aggregator::Verification;
+use crate::federation::Federation;
+use crate::frost::{NonceCommitment, SignatureShare, ThresholdGroup};
+use crate::keys;
+use crate::merkle::{self, MerkleTree};
+use crate::protocol::{
+    Endorsement, ErrorCode, ExcludedSubmission, GlobalResult, RejectionCounts, RoundState,
+};
+use crate::utils;
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signer, SigningKey};
+use native_tls::TlsConnector;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// Published rounds kept per symbol for inclusion proofs.
+const PUBLISHED_HISTORY: usize = 16;
+
+/// Settings shared by every round of a running aggregator.
+#[derive(Debug, Clone)]
+pub struct RoundConfig {
+    /// How long a round keeps collecting after its first submission, accepted or not. Also
+    /// the length of the clock slots rounds are numbered by.
+    pub deadline: Duration,
+    /// Contributors needed for a full result; reaching it closes a round early.
+    pub quorum: Quorum,
+    /// How a round that closes under quorum is published.
+    pub partial: PartialPolicy,
+    /// Strategy combining the accepted values into the global value.
+    pub method: Arc<dyn Aggregator>,
+    /// The aggregator's own key, signing every published result.
+    pub signing_key: Arc<SigningKey>,
+    /// Client group that co-signs published results with a threshold signature, if any.
+    pub cosign: Option<Arc<ThresholdGroup>>,
+    /// Aggregators this one cross-checks its results with, if any.
+    pub federation: Option<Arc<Federation>>,
+    /// Directory the global data files are written to.
+    pub data_dir: PathBuf,
+    /// Verifies federation peers serving `wss://` against a private CA, if given.
+    pub peer_tls: Option<TlsConnector>,
+    /// Wall clock in Unix milliseconds that rounds are numbered by, normally
+    /// [`utils::unix_millis`].
+    pub clock: fn() -> u64,
+}
+
+/// Number of contributors a round needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quorum {
+    /// An absolute number of contributors.
+    Count(usize),
+    /// A fraction in `(0, 1]` of the registered client keys.
+    Fraction(f64),
+}
+
+impl Quorum {
+    /// Contributors required given the number of registered keys; never less than one.
+    pub fn required(&self, registered: usize) -> usize {
+        let required = match *self {
+            Quorum::Count(count) => count,
+            Quorum::Fraction(fraction) => (fraction * registered as f64).ceil() as usize,
+        };
+        required.max(1)
+    }
+}
+
+impl FromStr for Quorum {
+    type Err = String;
+
+    /// Accepts `3`, `0.6` or `60%`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let fraction = if let Some(percent) = s.strip_suffix('%') {
+            percent.trim().parse::<f64>().map(|p| p / 100.0)
+        } else if s.contains('.') {
+            s.parse::<f64>()
+        } else {
+            return match s.parse::<usize>() {
+                Ok(0) => Err(format!("quorum {s:?} must be at least 1")),
+                Ok(count) => Ok(Quorum::Count(count)),
+                Err(e) => Err(format!("invalid quorum {s:?}: {e}")),
+            };
+        };
+        match fraction {
+            Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(Quorum::Fraction(fraction)),
+            Ok(_) => Err(format!("quorum fraction {s:?} must be in (0, 1]")),
+            Err(e) => Err(format!("invalid quorum {s:?}: {e}")),
+        }
+    }
+}
+
+/// What to do with a round that closes with fewer contributors than the quorum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialPolicy {
+    /// Publish the average, flagged with `quorum_met: false`.
+    Degraded,
+    /// Publish the round without an average.
+    Refuse,
+}
+
+impl FromStr for PartialPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "degraded" => Ok(PartialPolicy::Degraded),
+            "refuse" => Ok(PartialPolicy::Refuse),
+            _ => Err(format!(
+                "invalid partial policy {s:?}, use degraded or refuse"
+            )),
+        }
+    }
+}
+
+/// Submissions rejected during a round, grouped by reason.
+#[derive(Debug, Default)]
+struct RejectionTally {
+    counts: RejectionCounts,
+    entries: Vec<String>,
+}
+
+impl RejectionTally {
+    fn record(&mut self, outcome: &Verification) {
+        match outcome {
+            Verification::Verified { .. } => return,
+            Verification::BadSignature { .. } => self.counts.bad_signature += 1,
+            Verification::UnknownClient { .. } | Verification::DisabledClient { .. } => {
+                self.counts.unknown_client += 1
+            }
+            Verification::Malformed { .. } => self.counts.malformed += 1,
+            Verification::Stale { .. } => self.counts.stale += 1,
+            Verification::Replayed { .. } => self.counts.replayed += 1,
+        }
+        self.entries.push(outcome.describe());
+    }
+}
+
+/// Everything a closed round produced.
+#[derive(Debug, Clone)]
+pub struct PublishedRound {
+    pub result: GlobalResult,
+    pub values: Vec<f64>,
+    pub rejected: Vec<String>,
+    /// Merkle tree over the accepted submissions, leaves ordered like `contributor_ids`.
+    pub tree: MerkleTree,
+    /// Signing session while contributors co-sign the result.
+    pub cosign: Option<CosignSession>,
+    /// While cross-checking with the other federation members, when the round is settled as
+    /// not final if no majority has agreed by then.
+    pub federation_deadline: Option<Instant>,
+    /// Federation members that published a different result for the round.
+    divergent: BTreeSet<String>,
+}
+
+/// Co-signing of one published result by the contributors that committed to nonces.
+#[derive(Debug, Clone)]
+pub struct CosignSession {
+    /// Nonce commitments of every signer; all of them must send a share.
+    pub commitments: BTreeMap<usize, NonceCommitment>,
+    /// Verified signature shares received so far.
+    shares: BTreeMap<usize, SignatureShare>,
+    /// When the round is published without a group signature if shares are missing.
+    deadline: Instant,
+}
+
+/// What became of a peer's round summary.
+#[derive(Debug)]
+pub enum SummaryOutcome {
+    /// The round is published here too; carries this aggregator's result for the peer and
+    /// whether the comparison settled the round.
+    Compared {
+        own: Box<GlobalResult>,
+        settled: bool,
+    },
+    /// The round is not published here yet; the summary is compared once it is.
+    Held,
+}
+
+impl PublishedRound {
+    /// Proof that `client_id`'s accepted submission is part of this round.
+    pub fn inclusion_proof(&self, client_id: usize) -> Option<merkle::MerkleProof> {
+        let index = self.result.contributor_ids.binary_search(&client_id).ok()?;
+        self.tree.proof(index)
+    }
+
+    /// Whether the result is settled: co-signing and the federation cross-check are finished
+    /// or not attempted.
+    pub fn settled(&self) -> bool {
+        self.cosign.is_none() && self.federation_deadline.is_none()
+    }
+
+    /// Compare a peer's summary of this round with the result published here, endorsing it
+    /// or logging a divergence. Returns whether the round was settled by it, as final or as
+    /// unable to become final.
+    fn compare_summary(&mut self, summary: &GlobalResult, federation: &Federation) -> bool {
+        let (symbol, round_id) = (self.result.symbol.clone(), self.result.round_id);
+        let peer = &summary.aggregator_key;
+        if !self.result.agrees_with(summary) {
+            if !self.divergent.insert(peer.clone()) {
+                return false;
+            }
+            eprintln!(
+                "{symbol} round {round_id}: Divergence: aggregator {peer} published {}, this aggregator {}.",
+                outline(summary),
+                outline(&self.result)
+            );
+            let undecided = federation.members.len() - self.divergent.len();
+            if self.federation_deadline.is_some() && undecided < federation.majority {
+                eprintln!(
+                    "{symbol} round {round_id}: Not final, {} of {} aggregators disagree.",
+                    self.divergent.len(),
+                    federation.members.len()
+                );
+                self.federation_deadline = None;
+                return self.settled();
+            }
+            return false;
+        }
+        if self
+            .result
+            .endorsements
+            .iter()
+            .any(|endorsement| endorsement.aggregator_key == *peer)
+        {
+            return false;
+        }
+        self.result.endorsements.push(Endorsement {
+            aggregator_key: peer.clone(),
+            signature: summary.signature.clone(),
+        });
+        let agreeing = self.result.endorsements.len() + 1;
+        if self.federation_deadline.is_some() && agreeing >= federation.majority {
+            println!(
+                "{symbol} round {round_id}: Final, published alike by {agreeing} of {} aggregators.",
+                federation.members.len()
+            );
+            self.federation_deadline = None;
+            return self.settled();
+        }
+        false
+    }
+}
+
+/// Number of the current clock slot, one deadline long, counted from the Unix epoch.
+fn clock_slot(config: &RoundConfig) -> u64 {
+    (config.clock)() / (config.deadline.as_millis() as u64).max(1)
+}
+
+/// The attested parts of a result that federation members compare, for divergence logs.
+fn outline(result: &GlobalResult) -> String {
+    let value = result
+        .average
+        .map_or_else(|| "no value".to_string(), |average| format!("{average:.4}"));
+    format!(
+        "{value} from contributors {:?} with merkle root {}",
+        result.contributor_ids, result.merkle_root
+    )
+}
+
+/// Drives rounds through Open -> Collecting -> Closed -> Published, then opens the next one.
+#[derive(Debug)]
+pub struct RoundCoordinator {
+    symbol: String,
+    config: RoundConfig,
+    /// Number of registered client keys, used to resolve fractional quorums.
+    registered: usize,
+    /// At least the clock slot the round opened in, so federation members number their rounds
+    /// alike however long they have been running.
+    round_id: u64,
+    state: RoundState,
+    deadline: Option<Instant>,
+    /// Accepted `(client_id, contribution, Merkle leaf)` of the current round.
+    submissions: Vec<(usize, Contribution, merkle::Hash)>,
+    rejections: RejectionTally,
+    /// Nonce commitments for co-signing the current round's result; only those of its
+    /// contributors are kept when it is published.
+    commitments: BTreeMap<usize, NonceCommitment>,
+    /// Peers' summaries of rounds not published here yet.
+    early_summaries: Vec<GlobalResult>,
+    /// The most recently published rounds, oldest first.
+    published: VecDeque<PublishedRound>,
+    /// Every state entered, for the tests to follow the lifecycle.
+    #[cfg(test)]
+    history: Vec<RoundState>,
+}
+
+impl RoundCoordinator {
+    pub fn new(symbol: String, config: RoundConfig, registered: usize) -> Self {
+        let round_id = clock_slot(&config);
+        println!(
+            "{symbol} round {round_id}: Open (quorum {} of {registered} registered, deadline {}s, partial results {:?}, method {})",
+            config.quorum.required(registered),
+            config.deadline.as_secs(),
+            config.partial,
+            config.method.name()
+        );
+        RoundCoordinator {
+            symbol,
+            config,
+            registered,
+            round_id,
+            state: RoundState::Open,
+            deadline: None,
+            submissions: Vec::new(),
+            rejections: RejectionTally::default(),
+            commitments: BTreeMap::new(),
+            early_summaries: Vec::new(),
+            published: VecDeque::new(),
+            #[cfg(test)]
+            history: vec![RoundState::Open],
+        }
+    }
+
+    pub fn round_id(&self) -> u64 {
+        self.round_id
+    }
+
+    pub fn state(&self) -> RoundState {
+        self.state
+    }
+
+    pub fn config(&self) -> &RoundConfig {
+        &self.config
+    }
+
+    /// Contributors required for a full result.
+    pub fn required_quorum(&self) -> usize {
+        self.config.quorum.required(self.registered)
+    }
+
+    /// Update the number of registered client keys after a registry reload. A lower quorum
+    /// applies from the round's next accepted submission or its deadline.
+    pub fn set_registered(&mut self, registered: usize) {
+        if registered != self.registered {
+            self.registered = registered;
+            println!(
+                "{} round {}: quorum is now {} of {registered} registered.",
+                self.symbol,
+                self.round_id,
+                self.required_quorum()
+            );
+        }
+    }
+
+    /// Record a verification outcome, publishing the round once the quorum is met. Rejected
+    /// submissions start the deadline too, so a round of only rejections is still published
+    /// and saved with them.
+    pub fn record(&mut self, outcome: &Verification, now: Instant) -> Option<PublishedRound> {
+        if self.state == RoundState::Open {
+            self.deadline = Some(now + self.config.deadline);
+            self.transition(RoundState::Collecting);
+        }
+        let Verification::Verified {
+            client_id,
+            value,
+            volume,
+            leaf,
+        } = outcome
+        else {
+            self.rejections.record(outcome);
+            return None;
+        };
+        // The replay guard admits one submission per client and round; should another
+        // reach the round anyway, the first one stands.
+        if self.submissions.iter().any(|(id, ..)| id == client_id) {
+            eprintln!(
+                "{} round {}: Ignoring a second submission of Client-{client_id}.",
+                self.symbol, self.round_id
+            );
+            return None;
+        }
+        let contribution = Contribution {
+            value: *value,
+            volume: *volume,
+        };
+        self.submissions.push((*client_id, contribution, *leaf));
+        if self.submissions.len() >= self.required_quorum() {
+            return Some(self.publish(now));
+        }
+        None
+    }
+
+    /// Publish the round if it is collecting and its deadline has passed. A round nobody
+    /// submitted to moves on to the current clock slot instead.
+    pub fn poll_deadline(&mut self, now: Instant) -> Option<PublishedRound> {
+        let slot = clock_slot(&self.config);
+        if self.state == RoundState::Open && slot > self.round_id {
+            self.round_id = slot;
+            self.commitments.clear();
+        }
+        match self.deadline {
+            Some(deadline) if self.state == RoundState::Collecting && now >= deadline => {
+                println!("{} round {}: Deadline reached.", self.symbol, self.round_id);
+                Some(self.publish(now))
+            }
+            _ => None,
+        }
+    }
+
+    /// Published round `round_id`, while it is among the last few rounds.
+    pub fn published_round(&self, round_id: u64) -> Option<&PublishedRound> {
+        self.published
+            .iter()
+            .rev()
+            .find(|published| published.result.round_id == round_id)
+    }
+
+    /// The first published round from `round_id` on, while it is among the last few rounds.
+    pub fn published_from(&self, round_id: u64) -> Option<&PublishedRound> {
+        self.published
+            .iter()
+            .find(|published| published.result.round_id >= round_id)
+    }
+
+    /// Record a group member's commitment to the nonces it will co-sign this round's result
+    /// with. Clients commit before submitting, as the round may close on their submission.
+    pub fn record_commitment(
+        &mut self,
+        round_id: u64,
+        client_id: usize,
+        commitment: NonceCommitment,
+    ) -> Result<(), String> {
+        let Some(group) = &self.config.cosign else {
+            return Err("results are not co-signed".to_string());
+        };
+        if round_id != self.round_id {
+            return Err(format!(
+                "commitment for round {round_id}, current round is {}",
+                self.round_id
+            ));
+        }
+        if !group.verifying_shares.contains_key(&client_id) {
+            return Err(format!(
+                "client {client_id} holds no share of the threshold group"
+            ));
+        }
+        if self.commitments.contains_key(&client_id) {
+            return Err(format!(
+                "client {client_id} already committed for round {round_id}"
+            ));
+        }
+        self.commitments.insert(client_id, commitment);
+        Ok(())
+    }
+
+    /// The result of round `round_id` and the signers' commitments, while it is being
+    /// co-signed by `client_id`.
+    pub fn cosign_package(
+        &self,
+        round_id: u64,
+        client_id: usize,
+    ) -> Result<(GlobalResult, BTreeMap<usize, NonceCommitment>), (ErrorCode, String)> {
+        let not_found = |detail: String| (ErrorCode::NotFound, detail);
+        let round = self
+            .published_round(round_id)
+            .ok_or_else(|| not_found(format!("{} round {round_id} is not kept", self.symbol)))?;
+        match &round.cosign {
+            Some(session) if session.commitments.contains_key(&client_id) => {
+                Ok((round.result.clone(), session.commitments.clone()))
+            }
+            Some(_) => Err(not_found(format!(
+                "client {client_id} is not a signer of {} round {round_id}",
+                self.symbol
+            ))),
+            None => Err(not_found(format!(
+                "{} round {round_id} is not being co-signed",
+                self.symbol
+            ))),
+        }
+    }
+
+    /// Verify and keep a signer's share. Once every signer has sent one, the shares are combined
+    /// into the result's group signature and `true` is returned.
+    pub fn record_share(
+        &mut self,
+        round_id: u64,
+        client_id: usize,
+        share: &str,
+    ) -> Result<bool, String> {
+        let symbol = self.symbol.clone();
+        let Some(group) = self.config.cosign.clone() else {
+            return Err("results are not co-signed".to_string());
+        };
+        let round = self
+            .published
+            .iter_mut()
+            .find(|published| published.result.round_id == round_id)
+            .ok_or_else(|| format!("{symbol} round {round_id} is not kept"))?;
+        let Some(session) = round.cosign.as_mut() else {
+            return Err(format!("{symbol} round {round_id} is not being co-signed"));
+        };
+        let message = round.result.signing_payload();
+        let z = group.verify_share(client_id, share, message.as_bytes(), &session.commitments)?;
+        session.shares.insert(client_id, z);
+        if session.shares.len() < session.commitments.len() {
+            return Ok(false);
+        }
+        match group.aggregate(message.as_bytes(), &session.commitments, &session.shares) {
+            Ok(signature) => {
+                println!(
+                    "{symbol} round {round_id}: Result co-signed by clients {:?}.",
+                    session.commitments.keys().collect::<Vec<_>>()
+                );
+                round.result.group_signature =
+                    Some(general_purpose::STANDARD.encode(signature.to_bytes()));
+            }
+            Err(e) => eprintln!("{symbol} round {round_id}: Co-signing failed: {e}"),
+        }
+        round.cosign = None;
+        Ok(true)
+    }
+
+    /// Check a federation peer's signed summary of one of its rounds against the result
+    /// published here, or hold it until the round is.
+    pub fn record_summary(&mut self, summary: GlobalResult) -> Result<SummaryOutcome, String> {
+        let Some(federation) = self.config.federation.clone() else {
+            return Err("this aggregator is not federated".to_string());
+        };
+        if summary.aggregator_key == keys::key_id(&self.config.signing_key.verifying_key()) {
+            return Err("summary is signed with this aggregator's own key".to_string());
+        }
+        federation.verify_signature(&summary, &summary.aggregator_key, &summary.signature)?;
+        let round_id = summary.round_id;
+        if let Some(round) = self
+            .published
+            .iter_mut()
+            .find(|published| published.result.round_id == round_id)
+        {
+            let settled = round.compare_summary(&summary, &federation);
+            return Ok(SummaryOutcome::Compared {
+                own: Box::new(round.result.clone()),
+                settled,
+            });
+        }
+        if round_id < self.round_id {
+            return Err(format!("{} round {round_id} is not kept", self.symbol));
+        }
+        if round_id >= self.round_id + PUBLISHED_HISTORY as u64 {
+            return Err(format!(
+                "{} round {round_id} is too far ahead of round {}",
+                self.symbol, self.round_id
+            ));
+        }
+        self.early_summaries.retain(|held| {
+            held.round_id != round_id || held.aggregator_key != summary.aggregator_key
+        });
+        self.early_summaries.push(summary);
+        Ok(SummaryOutcome::Held)
+    }
+
+    /// Settle results whose co-signers or federation peers missed the deadline. Returns
+    /// whether any round was settled.
+    pub fn poll_settle_deadlines(&mut self, now: Instant) -> bool {
+        let mut settled = false;
+        for round in &mut self.published {
+            if let Some(deadline) = round.federation_deadline {
+                if now >= deadline {
+                    eprintln!(
+                        "{} round {}: Not final, published alike by {} of the {} aggregators required.",
+                        self.symbol,
+                        round.result.round_id,
+                        round.result.endorsements.len() + 1,
+                        self.config
+                            .federation
+                            .as_ref()
+                            .map_or(1, |federation| federation.majority)
+                    );
+                    round.federation_deadline = None;
+                    settled |= round.settled();
+                }
+            }
+            let Some(session) = &round.cosign else {
+                continue;
+            };
+            if now >= session.deadline {
+                eprintln!(
+                    "{} round {}: Publishing without a group signature, {} of {} signature shares received.",
+                    self.symbol,
+                    round.result.round_id,
+                    session.shares.len(),
+                    session.commitments.len()
+                );
+                round.cosign = None;
+                settled |= round.settled();
+            }
+        }
+        settled
+    }
+
+    fn publish(&mut self, now: Instant) -> PublishedRound {
+        self.transition(RoundState::Closed);
+        let submissions = std::mem::take(&mut self.submissions);
+        let contributions: Vec<Contribution> = submissions.iter().map(|&(_, c, _)| c).collect();
+        let values: Vec<f64> = contributions.iter().map(|c| c.value).collect();
+        let rejections = std::mem::take(&mut self.rejections);
+        let quorum = self.required_quorum();
+        let quorum_met = values.len() >= quorum;
+        let aggregate = self.config.method.aggregate(&contributions);
+        let average = match self.config.partial {
+            PartialPolicy::Refuse if !quorum_met => {
+                eprintln!(
+                    "{} round {}: Withholding result, {} of {quorum} contributors.",
+                    self.symbol,
+                    self.round_id,
+                    values.len()
+                );
+                None
+            }
+            _ => aggregate.value,
+        };
+        let excluded = aggregate
+            .excluded
+            .iter()
+            .map(|&i| ExcludedSubmission {
+                client_id: submissions[i].0,
+                value: submissions[i].1.value,
+            })
+            .collect();
+        let mut accepted: Vec<(usize, merkle::Hash)> = submissions
+            .iter()
+            .map(|&(client_id, _, leaf)| (client_id, leaf))
+            .collect();
+        accepted.sort_unstable();
+        let tree = MerkleTree::new(accepted.iter().map(|&(_, leaf)| leaf).collect());
+        let mut result = GlobalResult {
+            symbol: self.symbol.clone(),
+            round_id: self.round_id,
+            average,
+            contributors: values.len(),
+            quorum,
+            quorum_met,
+            method: self.config.method.name(),
+            excluded,
+            rejected: rejections.counts,
+            contributor_ids: accepted.iter().map(|&(client_id, _)| client_id).collect(),
+            merkle_root: utils::to_hex(&tree.root()),
+            aggregator_key: keys::key_id(&self.config.signing_key.verifying_key()),
+            signature: String::new(),
+            group_signature: None,
+            endorsements: Vec::new(),
+        };
+        let signature = self
+            .config
+            .signing_key
+            .sign(result.signing_payload().as_bytes());
+        result.signature = general_purpose::STANDARD.encode(signature.to_bytes());
+        let cosign = self.start_cosign(&result.contributor_ids, average.is_some(), now);
+        let mut published = PublishedRound {
+            result,
+            values,
+            rejected: rejections.entries,
+            tree,
+            cosign,
+            federation_deadline: self.federation_deadline(now),
+            divergent: BTreeSet::new(),
+        };
+        if let Some(federation) = self.config.federation.clone() {
+            let round_id = self.round_id;
+            for summary in std::mem::take(&mut self.early_summaries) {
+                if summary.round_id == round_id {
+                    published.compare_summary(&summary, &federation);
+                } else if summary.round_id > round_id {
+                    self.early_summaries.push(summary);
+                }
+            }
+        }
+        self.transition(RoundState::Published);
+        if self.published.len() == PUBLISHED_HISTORY {
+            self.published.pop_front();
+        }
+        self.published.push_back(published.clone());
+
+        self.round_id = (self.round_id + 1).max(clock_slot(&self.config));
+        self.deadline = None;
+        self.transition(RoundState::Open);
+        published
+    }
+
+    /// Open a co-signing session for the round being published if enough of its contributors
+    /// committed to nonces.
+    fn start_cosign(
+        &mut self,
+        contributor_ids: &[usize],
+        has_value: bool,
+        now: Instant,
+    ) -> Option<CosignSession> {
+        let mut commitments = std::mem::take(&mut self.commitments);
+        commitments.retain(|client_id, _| contributor_ids.binary_search(client_id).is_ok());
+        let group = self.config.cosign.as_ref()?;
+        if !has_value {
+            return None;
+        }
+        if commitments.len() < group.threshold {
+            eprintln!(
+                "{} round {}: Not co-signing, {} of {} required signers committed.",
+                self.symbol,
+                self.round_id,
+                commitments.len(),
+                group.threshold
+            );
+            return None;
+        }
+        Some(CosignSession {
+            commitments,
+            shares: BTreeMap::new(),
+            deadline: now + self.config.deadline,
+        })
+    }
+
+    /// How long to wait for the other federation members to publish the same result, unless
+    /// this aggregator's result is a majority on its own.
+    fn federation_deadline(&self, now: Instant) -> Option<Instant> {
+        match &self.config.federation {
+            Some(federation) if federation.majority > 1 => Some(now + self.config.deadline),
+            _ => None,
+        }
+    }
+
+    fn transition(&mut self, state: RoundState) {
+        println!(
+            "{} round {}: {:?} -> {:?}",
+            self.symbol, self.round_id, self.state, state
+        );
+        self.state = state;
+        #[cfg(test)]
+        self.history.push(state);
+    }
+}
+
+/// Independent round coordinators, one per configured symbol.
+#[derive(Debug)]
+pub struct SymbolRounds {
+    rounds: BTreeMap<String, RoundCoordinator>,
+}
+
+impl SymbolRounds {
+    pub fn new(symbols: &[String], config: RoundConfig, registered: usize) -> Self {
+        let rounds = symbols
+            .iter()
+            .map(|symbol| {
+                let coordinator = RoundCoordinator::new(symbol.clone(), config.clone(), registered);
+                (symbol.clone(), coordinator)
+            })
+            .collect();
+        SymbolRounds { rounds }
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&RoundCoordinator> {
+        self.rounds.get(symbol)
+    }
+
+    pub fn get_mut(&mut self, symbol: &str) -> Option<&mut RoundCoordinator> {
+        self.rounds.get_mut(symbol)
+    }
+
+    pub fn set_registered(&mut self, registered: usize) {
+        for coordinator in self.rounds.values_mut() {
+            coordinator.set_registered(registered);
+        }
+    }
+
+    /// Publish every round whose deadline has passed.
+    pub fn poll_deadlines(&mut self, now: Instant) -> Vec<PublishedRound> {
+        self.rounds
+            .values_mut()
+            .filter_map(|coordinator| coordinator.poll_deadline(now))
+            .collect()
+    }
+
+    /// Settle every round whose co-signing or federation cross-check is past its deadline;
+    /// returns whether any was.
+    pub fn poll_settle_deadlines(&mut self, now: Instant) -> bool {
+        self.rounds
+            .values_mut()
+            .map(|coordinator| coordinator.poll_settle_deadlines(now))
+            .filter(|&settled| settled)
+            .count()
+            > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn quorum_parses_counts_and_fractions() {
+        assert_eq!("3".parse(), Ok(Quorum::Count(3)));
+        assert_eq!(" 1 ".parse(), Ok(Quorum::Count(1)));
+        assert_eq!("0.6".parse(), Ok(Quorum::Fraction(0.6)));
+        assert_eq!("1.0".parse(), Ok(Quorum::Fraction(1.0)));
+        assert_eq!("60%".parse(), Ok(Quorum::Fraction(0.6)));
+        assert_eq!("100 %".parse(), Ok(Quorum::Fraction(1.0)));
+    }
+
+    #[test]
+    fn quorum_rejects_empty_and_out_of_range_values() {
+        for spec in [
+            "0", "-1", "1.5", "0.0", "-0.5", "0%", "150%", "", "many", "nan%",
+        ] {
+            assert!(spec.parse::<Quorum>().is_err(), "{spec:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn quorum_requires_at_least_one_contributor() {
+        assert_eq!(Quorum::Count(3).required(10), 3);
+        assert_eq!(Quorum::Fraction(0.6).required(5), 3);
+        assert_eq!(Quorum::Fraction(0.5).required(5), 3);
+        assert_eq!(Quorum::Fraction(0.1).required(0), 1);
+    }
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    thread_local! {
+        /// Unix milliseconds of the clock the coordinators of this thread's test read.
+        static NOW_MILLIS: Cell<u64> = const { Cell::new(7 * HOUR_MS + 1_234) };
+    }
+
+    fn test_clock() -> u64 {
+        NOW_MILLIS.with(Cell::get)
+    }
+
+    /// A coordinator for BTCUSDT with hour-long rounds and a quorum of 2 of 3 clients,
+    /// signing with `key`.
+    fn coordinator(key: &SigningKey, federation: Option<Arc<Federation>>) -> RoundCoordinator {
+        let config = RoundConfig {
+            deadline: Duration::from_secs(3600),
+            quorum: Quorum::Count(2),
+            partial: PartialPolicy::Degraded,
+            method: crate::aggregation::from_spec("mean").unwrap(),
+            signing_key: Arc::new(key.clone()),
+            cosign: None,
+            federation,
+            data_dir: PathBuf::new(),
+            peer_tls: None,
+            clock: test_clock,
+        };
+        RoundCoordinator::new("BTCUSDT".to_string(), config, 3)
+    }
+
+    fn member(key: &SigningKey, federation: &Arc<Federation>) -> RoundCoordinator {
+        coordinator(key, Some(federation.clone()))
+    }
+
+    fn verified(client_id: usize, value: f64) -> Verification {
+        Verification::Verified {
+            client_id,
+            value,
+            volume: 1.0,
+            leaf: merkle::leaf_hash(format!("{client_id}:{value}").as_bytes()),
+        }
+    }
+
+    /// Feed the same submissions to two federation members and compare what they publish.
+    fn publish_pair(left: &[(usize, f64)], right: &[(usize, f64)]) -> (bool, usize) {
+        let keys = [
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        ];
+        let federation = Arc::new(Federation {
+            majority: 2,
+            members: keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| crate::federation::FederationMember {
+                    url: format!("ws://127.0.0.1:808{i}"),
+                    public_key: general_purpose::STANDARD.encode(key.verifying_key().to_bytes()),
+                })
+                .collect(),
+        });
+        let mut a = member(&keys[0], &federation);
+        let mut b = member(&keys[1], &federation);
+        // Started independently, both open the round of the clock's hour-long slot.
+        let round_id = 7;
+        assert_eq!(a.round_id(), round_id);
+        assert_eq!(b.round_id(), round_id);
+        let now = Instant::now();
+        let publish = |coordinator: &mut RoundCoordinator, submissions: &[(usize, f64)]| {
+            submissions
+                .iter()
+                .find_map(|&(client_id, value)| {
+                    coordinator.record(&verified(client_id, value), now)
+                })
+                .unwrap()
+        };
+        let ours = publish(&mut a, left);
+        let theirs = publish(&mut b, right);
+        assert_eq!(ours.result.round_id, round_id);
+        assert_eq!(theirs.result.round_id, round_id);
+        assert_eq!(a.round_id(), round_id + 1);
+        assert_eq!(b.round_id(), round_id + 1);
+
+        let agrees = ours.result.agrees_with(&theirs.result);
+        assert_eq!(agrees, theirs.result.agrees_with(&ours.result));
+        let Ok(SummaryOutcome::Compared { own, .. }) = a.record_summary(theirs.result) else {
+            panic!("round {round_id} is published by both");
+        };
+        (agrees, federation.agreeing_members(&own).len())
+    }
+
+    #[test]
+    fn federation_members_agree_on_identical_rounds() {
+        let submissions = [(1, 100.0), (2, 102.0)];
+        assert_eq!(publish_pair(&submissions, &submissions), (true, 2));
+        // Arrival order does not matter.
+        assert_eq!(
+            publish_pair(&submissions, &[(2, 102.0), (1, 100.0)]),
+            (true, 2)
+        );
+    }
+
+    #[test]
+    fn federation_members_diverge_on_different_rounds() {
+        let ours = [(1, 100.0), (2, 102.0)];
+        assert_eq!(publish_pair(&ours, &[(1, 100.0), (3, 102.0)]), (false, 1));
+        assert_eq!(publish_pair(&ours, &[(1, 100.0), (2, 104.0)]), (false, 1));
+    }
+
+    #[test]
+    fn rounds_go_through_every_state_when_the_quorum_is_met() {
+        let mut coordinator = coordinator(&SigningKey::from_bytes(&[1; 32]), None);
+        assert_eq!(coordinator.round_id(), 7);
+        assert_eq!(coordinator.state(), RoundState::Open);
+
+        let now = Instant::now();
+        assert!(coordinator.record(&verified(1, 100.0), now).is_none());
+        assert_eq!(coordinator.state(), RoundState::Collecting);
+        let published = coordinator.record(&verified(2, 102.0), now).unwrap();
+        assert_eq!(
+            coordinator.history,
+            [
+                RoundState::Open,
+                RoundState::Collecting,
+                RoundState::Closed,
+                RoundState::Published,
+                RoundState::Open,
+            ]
+        );
+        let result = &published.result;
+        assert_eq!((result.round_id, result.average), (7, Some(101.0)));
+        assert_eq!(result.contributor_ids, [1, 2]);
+        assert!(result.quorum_met);
+        assert!(published.settled());
+        assert!(coordinator.published_round(7).is_some());
+        assert_eq!(coordinator.round_id(), 8);
+    }
+
+    #[test]
+    fn rounds_are_published_at_their_deadline() {
+        let mut coordinator = coordinator(&SigningKey::from_bytes(&[1; 32]), None);
+        let start = Instant::now();
+        // Nothing to publish before the first submission.
+        assert!(coordinator
+            .poll_deadline(start + Duration::from_secs(7200))
+            .is_none());
+
+        // A rejection starts the deadline as well.
+        let rejected = Verification::BadSignature { client_id: 3 };
+        assert!(coordinator.record(&rejected, start).is_none());
+        assert!(coordinator.record(&verified(1, 100.0), start).is_none());
+        let deadline = start + Duration::from_secs(3600);
+        assert!(coordinator
+            .poll_deadline(deadline - Duration::from_millis(1))
+            .is_none());
+        assert_eq!(coordinator.state(), RoundState::Collecting);
+
+        let published = coordinator.poll_deadline(deadline).unwrap();
+        let result = &published.result;
+        assert_eq!((result.round_id, result.average), (7, Some(100.0)));
+        assert_eq!((result.contributors, result.quorum), (1, 2));
+        assert!(!result.quorum_met);
+        assert_eq!(result.rejected.bad_signature, 1);
+        assert_eq!(coordinator.state(), RoundState::Open);
+        assert!(coordinator.poll_deadline(deadline).is_none());
+    }
+
+    #[test]
+    fn a_second_submission_of_a_client_is_ignored() {
+        let mut coordinator = coordinator(&SigningKey::from_bytes(&[1; 32]), None);
+        let now = Instant::now();
+        assert!(coordinator.record(&verified(1, 100.0), now).is_none());
+        // Not counted towards the quorum, nor averaged in.
+        assert!(coordinator.record(&verified(1, 200.0), now).is_none());
+        assert_eq!(coordinator.state(), RoundState::Collecting);
+
+        let published = coordinator.record(&verified(2, 102.0), now).unwrap();
+        assert_eq!(published.result.contributor_ids, [1, 2]);
+        assert_eq!(published.result.average, Some(101.0));
+        assert_eq!(published.values, [100.0, 102.0]);
+    }
+
+    #[test]
+    fn rounds_move_to_the_current_clock_slot() {
+        let mut coordinator = coordinator(&SigningKey::from_bytes(&[1; 32]), None);
+        let now = Instant::now();
+        let publish = |coordinator: &mut RoundCoordinator| {
+            coordinator.record(&verified(1, 100.0), now);
+            coordinator.record(&verified(2, 102.0), now).unwrap()
+        };
+
+        // Published within its slot, the next round follows it.
+        assert_eq!(publish(&mut coordinator).result.round_id, 7);
+        assert_eq!(coordinator.round_id(), 8);
+        // An open round nobody submitted to catches up with the clock.
+        NOW_MILLIS.set(10 * HOUR_MS);
+        assert!(coordinator.poll_deadline(now).is_none());
+        assert_eq!(coordinator.round_id(), 10);
+        // Published after its slot, the next round is the current slot's.
+        coordinator.record(&verified(1, 100.0), now);
+        NOW_MILLIS.set(12 * HOUR_MS + 5);
+        assert!(coordinator.poll_deadline(now).is_none());
+        assert_eq!(coordinator.round_id(), 10);
+        let published = coordinator.record(&verified(2, 102.0), now).unwrap();
+        assert_eq!(published.result.round_id, 10);
+        assert_eq!(coordinator.round_id(), 12);
+        assert_eq!(publish(&mut coordinator).result.round_id, 12);
+        assert_eq!(coordinator.round_id(), 13);
+    }
+}
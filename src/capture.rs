@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// One raw exchange frame as received by a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    /// Milliseconds since the Unix epoch when the frame was received.
+    pub received_at: u64,
+    pub client_id: usize,
+    /// Name of the feed that produced the frame, used to parse it on replay.
+    pub feed: String,
+    pub frame: String,
+}
+
+/// Appends received frames to a newline-delimited JSON log shared by all clients. The frames
+/// are written by a task of their own, so recording never blocks a client.
+#[derive(Debug, Clone)]
+pub struct CaptureWriter {
+    frames: mpsc::UnboundedSender<CapturedFrame>,
+}
+
+impl CaptureWriter {
+    /// Create the log at `path` and start the task writing to it. The task ends once every
+    /// clone of the writer is dropped, with the number of frames written.
+    pub async fn create(path: &str) -> io::Result<(Self, JoinHandle<io::Result<usize>>)> {
+        let file = tokio::fs::File::create(path).await?;
+        let (frames, received) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_frames(file, received));
+        Ok((CaptureWriter { frames }, task))
+    }
+
+    /// Queue `frame` for writing; fails once the writing task stopped on an error.
+    pub fn record(&self, frame: &CapturedFrame) -> io::Result<()> {
+        self.frames
+            .send(frame.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "capture log is closed"))
+    }
+}
+
+async fn write_frames(
+    file: tokio::fs::File,
+    mut frames: mpsc::UnboundedReceiver<CapturedFrame>,
+) -> io::Result<usize> {
+    let mut file = BufWriter::new(file);
+    let mut written = 0;
+    while let Some(frame) = frames.recv().await {
+        let mut line = serde_json::to_string(&frame)?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).await?;
+        written += 1;
+        // Flush whenever the queue runs empty, so the log is complete but for frames
+        // still arriving.
+        if frames.is_empty() {
+            file.flush().await?;
+        }
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Frames read from a capture log.
+#[derive(Debug, Default)]
+pub struct Capture {
+    /// Frames of every client, in receive order.
+    pub frames: Vec<CapturedFrame>,
+    /// Line number and parse error of every line that is not a frame.
+    pub skipped: Vec<(usize, String)>,
+}
+
+/// Read a capture log, keeping the frames of every client in receive order.
+pub fn read_capture(path: &str) -> io::Result<Capture> {
+    let data = fs::read_to_string(path)?;
+    let mut capture = Capture::default();
+    for (number, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<CapturedFrame>(line) {
+            Ok(frame) => capture.frames.push(frame),
+            Err(e) => capture.skipped.push((number + 1, e.to_string())),
+        }
+    }
+    capture.frames.sort_by_key(|frame| frame.received_at);
+    Ok(capture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(received_at: u64, client_id: usize, frame: &str) -> CapturedFrame {
+        CapturedFrame {
+            received_at,
+            client_id,
+            feed: "binance".to_string(),
+            frame: frame.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn captured_frames_read_back_in_receive_order() {
+        let path = std::env::temp_dir().join(format!("mcs-capture-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (writer, task) = CaptureWriter::create(path).await.unwrap();
+        let other = writer.clone();
+        writer.record(&frame(20, 1, "{\"p\":\"2\"}")).unwrap();
+        other.record(&frame(10, 2, "line\nbreak")).unwrap();
+        writer.record(&frame(30, 1, "")).unwrap();
+        drop((writer, other));
+        assert_eq!(task.await.unwrap().unwrap(), 3);
+
+        let mut data = fs::read_to_string(path).unwrap();
+        assert_eq!(data.lines().count(), 3);
+        data.push_str("\nnot a frame\n{\"received_at\":5}\n");
+        fs::write(path, data).unwrap();
+
+        let capture = read_capture(path).unwrap();
+        let _ = fs::remove_file(path);
+        let order: Vec<(u64, usize, &str)> = capture
+            .frames
+            .iter()
+            .map(|f| (f.received_at, f.client_id, f.frame.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (10, 2, "line\nbreak"),
+                (20, 1, "{\"p\":\"2\"}"),
+                (30, 1, "")
+            ]
+        );
+        let lines: Vec<usize> = capture.skipped.iter().map(|&(line, _)| line).collect();
+        assert_eq!(lines, [5, 6]);
+    }
+}
@@ -1,36 +1,347 @@
+use crate::capture::{CaptureWriter, CapturedFrame};
+use crate::federation::Federation;
+use crate::feed::{self, PriceFeed};
+use crate::frost::{Nonces, SecretShare};
+use crate::keys;
+use crate::merkle;
+use crate::protocol::{self, GlobalResult, Submission, WireMessage};
+use crate::signer::{Signer, SignerError};
+use crate::tls;
 use crate::utils;
 use base64::{engine::general_purpose, Engine as _};
-use ed25519_dalek::{Signer, SigningKey};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
 use futures::{SinkExt, StreamExt};
-use serde_json::json;
+use native_tls::TlsConnector;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::net::TcpStream;
-use tokio::time::{timeout, Duration, Instant};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
 use tokio_tungstenite::{
-    connect_async, tungstenite::protocol::frame::coding::CloseCode,
+    connect_async, tungstenite, tungstenite::protocol::frame::coding::CloseCode,
     tungstenite::protocol::CloseFrame, tungstenite::protocol::Message, MaybeTlsStream,
 };
 
-/// Connect to WebSocket server.
+/// How long to wait for an inclusion proof; the aggregator replies when the round closes.
+const INCLUSION_PROOF_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An aggregator to submit to, and the key its results must be signed with.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub url: String,
+    pub aggregator_key: VerifyingKey,
+    /// Verifies a `wss://` aggregator against a private CA instead of the system's roots.
+    pub tls: Option<TlsConnector>,
+}
+
+/// Where a client submits to and what it checks published results against.
+#[derive(Clone)]
+pub struct ResultChecks {
+    /// Every aggregator of the federation, or the single one.
+    pub endpoints: Vec<Endpoint>,
+    /// With co-signing, the client's threshold share.
+    pub share: Option<SecretShare>,
+    /// Largest relative distance from the client's own price at which it co-signs.
+    pub tolerance: f64,
+}
+
+/// How a client reduces the trades it observed to a single price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeMode {
+    /// Unweighted mean of the trade prices.
+    Mean,
+    /// Volume-weighted average price.
+    Vwap,
+}
+
+impl ComputeMode {
+    fn compute(&self, trades: &[utils::Trade]) -> Option<f64> {
+        match self {
+            ComputeMode::Mean => {
+                let prices: Vec<f64> = trades.iter().map(|t| t.price).collect();
+                utils::calculate_average(&prices)
+            }
+            ComputeMode::Vwap => utils::calculate_vwap(trades),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ComputeMode::Mean => "Average",
+            ComputeMode::Vwap => "VWAP",
+        }
+    }
+}
+
+impl FromStr for ComputeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(ComputeMode::Mean),
+            "vwap" => Ok(ComputeMode::Vwap),
+            _ => Err(format!("invalid compute mode {s:?}, use mean or vwap")),
+        }
+    }
+}
+
+/// Connect to the feed's WebSocket server and subscribe to `symbols`.
 async fn connect_to_websocket(
+    feed: &dyn PriceFeed,
+    symbols: &[String],
 ) -> Result<tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error>>
 {
-    let url = "wss://stream.binance.com:9443/ws/btcusdt@trade";
-    let (ws_stream, _) = connect_async(url).await?;
+    let (mut ws_stream, _) = connect_async(feed.url(symbols)).await?;
+    for message in feed.subscribe_messages(symbols) {
+        ws_stream.send(Message::Text(message)).await?;
+    }
     Ok(ws_stream)
 }
 
-/// Client process: Fetch prices, calculate average, sign, and send to aggregator.
-pub async fn client_process(id: usize, keypair: SigningKey, duration: u64) {
-    let mut ws_stream = match connect_to_websocket().await {
+/// Where a client takes its exchange frames from.
+pub enum FrameSource {
+    /// A live feed read for `duration` seconds, optionally recording every received frame.
+    Live {
+        feed: Arc<dyn PriceFeed>,
+        recorder: Option<CaptureWriter>,
+        duration: u64,
+    },
+    /// Frames from a capture log, replayed at `speed` times the original pace;
+    /// a speed of 0 replays without any delay.
+    Replay {
+        frames: Vec<CapturedFrame>,
+        speed: f64,
+    },
+}
+
+/// Client process: Fetch prices per symbol, calculate averages, sign, and send to every
+/// aggregator.
+pub async fn client_process(
+    id: usize,
+    signer: Arc<dyn Signer>,
+    checks: ResultChecks,
+    mode: ComputeMode,
+    symbols: Vec<String>,
+    source: FrameSource,
+    data_dir: PathBuf,
+) {
+    let trades = match source {
+        FrameSource::Live {
+            feed,
+            recorder,
+            duration,
+        } => collect_live(id, feed.as_ref(), recorder.as_ref(), &symbols, duration).await,
+        FrameSource::Replay { frames, speed } => Some(collect_replay(id, &frames, speed).await),
+    };
+    let Some(trades) = trades else {
+        return;
+    };
+
+    let mut computed: Vec<utils::SymbolData> = Vec::new();
+    for symbol in &symbols {
+        let symbol_trades = trades.get(symbol).map(Vec::as_slice).unwrap_or_default();
+        let Some(avg) = mode.compute(symbol_trades) else {
+            eprintln!("Client {id}: No {symbol} data points collected.");
+            continue;
+        };
+        let volume = utils::total_volume(symbol_trades);
+        println!(
+            "Client {id}: {} {symbol} price: {:.4} (volume {})",
+            mode.label(),
+            avg,
+            volume
+        );
+        computed.push(utils::SymbolData {
+            symbol: symbol.clone(),
+            prices: symbol_trades.iter().map(|t| t.price).collect(),
+            average: avg,
+            volume,
+        });
+    }
+
+    if computed.is_empty() {
+        eprintln!("Client {id}: No data points collected.");
+        return;
+    }
+    utils::save_client_data(&data_dir, id, &computed)
+        .unwrap_or_else(|e| eprintln!("Client {id}: Failed to save data: {e}"));
+    let signed = SignedSubmissions::default();
+    let submissions = checks
+        .endpoints
+        .iter()
+        .map(|endpoint| submit_to(id, endpoint, signer.as_ref(), &signed, &checks, &computed));
+    futures::future::join_all(submissions).await;
+}
+
+/// Submissions already signed for a (symbol, round), so every aggregator of a federation
+/// receives the same one and commits to the same Merkle leaves. Aggregators number rounds by
+/// clock slot, so they name the same round unless their clocks or deadlines differ.
+type SignedSubmissions = tokio::sync::Mutex<HashMap<(String, u64), Submission>>;
+
+/// The submission of `data` for `round_id`, signed on first use.
+async fn signed_submission(
+    id: usize,
+    signer: &dyn Signer,
+    signed: &SignedSubmissions,
+    data: &utils::SymbolData,
+    round_id: u64,
+) -> Result<Submission, SignerError> {
+    let mut signed = signed.lock().await;
+    if let Some(submission) = signed.get(&(data.symbol.clone(), round_id)) {
+        return Ok(submission.clone());
+    }
+    if let Some((_, other)) = signed
+        .keys()
+        .find(|(symbol, other)| *symbol == data.symbol && *other != round_id)
+    {
+        eprintln!(
+            "Client {id}: Aggregators are in different {} rounds, {other} and {round_id}; check their clocks and --deadline.",
+            data.symbol
+        );
+    }
+    let timestamp = utils::unix_millis();
+    let key_id = signer.current_key_id(id, timestamp).await?;
+    let mut submission = Submission {
+        client_id: id,
+        key_id,
+        symbol: data.symbol.clone(),
+        round_id,
+        timestamp,
+        nonce: rand::random(),
+        message: format!("{}", data.average),
+        volume: data.volume,
+        signature: String::new(),
+    };
+    let signature = signer
+        .sign(
+            id,
+            &submission.key_id,
+            submission.signing_payload().as_bytes(),
+        )
+        .await?;
+    submission.signature = general_purpose::STANDARD.encode(signature.to_bytes());
+    signed.insert((data.symbol.clone(), round_id), submission.clone());
+    Ok(submission)
+}
+
+/// Submit every computed average to one aggregator, then check what it published.
+async fn submit_to(
+    id: usize,
+    endpoint: &Endpoint,
+    signer: &dyn Signer,
+    signed: &SignedSubmissions,
+    checks: &ResultChecks,
+    computed: &[utils::SymbolData],
+) {
+    let ws_url = endpoint.url.as_str();
+
+    // Connect to the WebSocket server
+    let ws_stream = match tls::connect(ws_url, endpoint.tls.as_ref()).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            eprintln!("Client {id}: Failed to connect to aggregator at {ws_url}: {e}");
+            return;
+        }
+    };
+    println!("Connected to WebSocket server at {}", ws_url);
+    let (mut write, mut read) = ws_stream.split();
+
+    let mut accepted: Vec<(Submission, f64, Option<Nonces>)> = Vec::new();
+    for data in computed {
+        let symbol = &data.symbol;
+        let Some(round_id) = fetch_round_id(&mut write, &mut read, symbol).await else {
+            eprintln!(
+                "Client {id}: Could not learn the current {symbol} round from the aggregator at {ws_url}."
+            );
+            continue;
+        };
+        let submission = match signed_submission(id, signer, signed, data, round_id).await {
+            Ok(submission) => submission,
+            Err(e) => {
+                eprintln!("Client {id}: Cannot sign {symbol} submission: {e}");
+                continue;
+            }
+        };
+        // Commit before submitting: the submission may be the one that closes the round.
+        let nonces = match &checks.share {
+            Some(share) => commit_to_cosign(&mut write, &mut read, share, &submission).await,
+            None => None,
+        };
+        if let Err(e) = write
+            .send(Message::Text(
+                WireMessage::Submit(submission.clone()).to_text(),
+            ))
+            .await
+        {
+            eprintln!("Client {id}: Failed to send {symbol} submission to {ws_url}: {e}");
+            continue;
+        }
+        match timeout(Duration::from_secs(5), read.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => match protocol::decode(&text) {
+                Ok(WireMessage::Ack { .. }) => {
+                    println!("Client {id}: {symbol} submission accepted by {ws_url}.");
+                    accepted.push((submission, data.average, nonces));
+                }
+                Ok(WireMessage::Error { code, detail }) => {
+                    eprintln!("Client {id}: {symbol} submission refused ({code:?}): {detail}")
+                }
+                Ok(other) => eprintln!("Client {id}: Unexpected reply: {other:?}"),
+                Err(e) => eprintln!("Client {id}: Failed to decode reply: {e}"),
+            },
+            Ok(Some(Err(e))) => eprintln!("Client {id}: WebSocket error: {e}"),
+            Err(_) => eprintln!("Client {id}: No reply from aggregator at {ws_url}."),
+            _ => {}
+        }
+    }
+
+    for (submission, avg, nonces) in accepted {
+        let included =
+            check_inclusion(&mut write, &mut read, &submission, &endpoint.aggregator_key).await;
+        if let (true, Some(nonces)) = (included, nonces) {
+            cosign(
+                &mut write,
+                &mut read,
+                &submission,
+                avg,
+                nonces,
+                checks,
+                &endpoint.aggregator_key,
+            )
+            .await;
+        }
+    }
+    let close_frame = CloseFrame {
+        code: CloseCode::Normal, // Normal closure
+        reason: std::borrow::Cow::Borrowed("Closing the connection gracefully"),
+    };
+
+    // Send the close frame to the server
+    if let Err(e) = write.send(Message::Close(Some(close_frame))).await {
+        eprintln!("Failed to send close frame: {e}");
+    } else {
+        println!("Server sent close frame.");
+    }
+}
+
+/// Read trades from a live feed for `duration` seconds.
+async fn collect_live(
+    id: usize,
+    feed: &dyn PriceFeed,
+    recorder: Option<&CaptureWriter>,
+    symbols: &[String],
+    duration: u64,
+) -> Option<BTreeMap<String, Vec<utils::Trade>>> {
+    let mut ws_stream = match connect_to_websocket(feed, symbols).await {
         Ok(ws) => ws,
         Err(e) => {
             eprintln!("Client {id}: Failed to connect to WebSocket: {e}");
-            return;
+            return None;
         }
     };
 
-    println!("Client {id}: Connected to WebSocket.");
-    let mut prices: Vec<f64> = Vec::new();
+    println!("Client {id}: Connected to {} WebSocket.", feed.name());
+    let mut trades: BTreeMap<String, Vec<utils::Trade>> = BTreeMap::new();
     let start_time = Instant::now();
 
     while start_time.elapsed().as_secs() < duration {
@@ -41,8 +352,19 @@ pub async fn client_process(id: usize, keypair: SigningKey, duration: u64) {
 
         match result {
             Ok(Some(Ok(Message::Text(text)))) => {
-                if let Ok(price) = utils::process_message(&text) {
-                    prices.push(price);
+                if let Some(recorder) = recorder {
+                    let frame = CapturedFrame {
+                        received_at: utils::unix_millis(),
+                        client_id: id,
+                        feed: feed.name().to_string(),
+                        frame: text.clone(),
+                    };
+                    recorder
+                        .record(&frame)
+                        .unwrap_or_else(|e| eprintln!("Client {id}: Failed to record frame: {e}"));
+                }
+                for trade in feed.parse(&text) {
+                    trades.entry(trade.symbol.clone()).or_default().push(trade);
                 }
             }
             Ok(Some(Err(e))) => {
@@ -62,68 +384,469 @@ pub async fn client_process(id: usize, keypair: SigningKey, duration: u64) {
             }
         }
     }
-    let ws_url = "ws://127.0.0.1:8080";
-
-    // Connect to the WebSocket server
-    let (ws_stream, _) = connect_async(ws_url)
-        .await
-        .expect("Failed to connect WebSocket");
-    println!("Connected to WebSocket server at {}", ws_url);
-    let (mut write, _) = ws_stream.split();
+    Some(trades)
+}
 
-    if let Some(avg) = utils::calculate_average(&prices) {
-        println!("Client {id}: Average BTC price: {:.4}", avg);
+/// Replay captured frames, keeping their original spacing divided by `speed`.
+async fn collect_replay(
+    id: usize,
+    frames: &[CapturedFrame],
+    speed: f64,
+) -> BTreeMap<String, Vec<utils::Trade>> {
+    println!("Client {id}: Replaying {} captured frames.", frames.len());
+    let mut feeds: HashMap<String, Option<Arc<dyn PriceFeed>>> = HashMap::new();
+    let mut trades: BTreeMap<String, Vec<utils::Trade>> = BTreeMap::new();
+    let first = frames.first().map(|f| f.received_at).unwrap_or_default();
+    let start_time = Instant::now();
 
-        let message = format!("{}", avg);
-        let signature = keypair.sign(message.as_bytes());
-        let serialized_data = json!({
-            "client_id": id,
-            "message": message,
-            "signature": general_purpose::STANDARD.encode(signature.to_bytes())
+    for frame in frames {
+        if speed > 0.0 {
+            let offset = Duration::from_millis(frame.received_at.saturating_sub(first));
+            sleep_until(start_time + offset.div_f64(speed)).await;
+        }
+        let feed = feeds.entry(frame.feed.clone()).or_insert_with(|| {
+            feed::from_spec(&frame.feed, None)
+                .map_err(|e| eprintln!("Client {id}: Cannot replay frames: {e}"))
+                .ok()
         });
-        write
-            .send(Message::Text(serialized_data.to_string()))
-            .await
-            .expect("Failed to send message");
-        utils::save_client_data(id, &prices, avg)
-            .unwrap_or_else(|e| eprintln!("Client {id}: Failed to save data: {e}"));
-        let close_frame = CloseFrame {
-            code: CloseCode::Normal, // Normal closure
-            reason: std::borrow::Cow::Borrowed("Closing the connection gracefully"),
+        let Some(feed) = feed else {
+            continue;
         };
+        for trade in feed.parse(&frame.frame) {
+            trades.entry(trade.symbol.clone()).or_default().push(trade);
+        }
+    }
+    trades
+}
 
-        // Send the close frame to the server
-        if let Err(e) = write.send(Message::Close(Some(close_frame))).await {
-            eprintln!("Failed to send close frame: {e}");
+/// Ask the aggregator for the round the next submission must be signed for.
+async fn fetch_round_id<W, R>(write: &mut W, read: &mut R, symbol: &str) -> Option<u64>
+where
+    W: SinkExt<Message> + Unpin,
+    R: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    write
+        .send(Message::Text(
+            WireMessage::CurrentRound {
+                symbol: symbol.to_string(),
+            }
+            .to_text(),
+        ))
+        .await
+        .ok()?;
+    match timeout(Duration::from_secs(5), read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => match protocol::decode(&text) {
+            Ok(WireMessage::RoundInfo { round_id, .. }) => Some(round_id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Ask the aggregator to prove that `submission` is part of its round's signed result, and
+/// check the proof against the submission this client signed. Returns whether it verified.
+async fn check_inclusion<W, R>(
+    write: &mut W,
+    read: &mut R,
+    submission: &Submission,
+    aggregator_key: &VerifyingKey,
+) -> bool
+where
+    W: SinkExt<Message> + Unpin,
+    W::Error: std::fmt::Display,
+    R: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let (id, symbol, round_id) = (
+        submission.client_id,
+        &submission.symbol,
+        submission.round_id,
+    );
+    let request = WireMessage::ProveInclusion {
+        symbol: symbol.clone(),
+        round_id,
+        client_id: id,
+    };
+    if let Err(e) = write.send(Message::Text(request.to_text())).await {
+        eprintln!("Client {id}: Failed to request {symbol} inclusion proof: {e}");
+        return false;
+    }
+    // The aggregator answers once the round is published, which may take its whole deadline.
+    let reply = match timeout(INCLUSION_PROOF_TIMEOUT, read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => protocol::decode(&text),
+        Ok(_) => {
+            eprintln!("Client {id}: Connection closed before the {symbol} inclusion proof.");
+            return false;
+        }
+        Err(_) => {
+            eprintln!("Client {id}: No {symbol} inclusion proof from aggregator.");
+            return false;
+        }
+    };
+    let (result, proof) = match reply {
+        Ok(WireMessage::InclusionProof { result, proof, .. }) => (result, proof),
+        Ok(WireMessage::Error { code, detail }) => {
+            eprintln!("Client {id}: {symbol} inclusion proof refused ({code:?}): {detail}");
+            return false;
+        }
+        Ok(other) => {
+            eprintln!("Client {id}: Unexpected reply: {other:?}");
+            return false;
+        }
+        Err(e) => {
+            eprintln!("Client {id}: Failed to decode reply: {e}");
+            return false;
+        }
+    };
+    let verified = verify_result(&result, aggregator_key)
+        .and_then(|()| {
+            if result.symbol != *symbol || result.round_id != round_id {
+                return Err(format!(
+                    "proof is for {} round {}",
+                    result.symbol, result.round_id
+                ));
+            }
+            // The signed contributor ids fix where this client's leaf must sit in the tree.
+            if result.contributor_ids.binary_search(&id) != Ok(proof.leaf_index)
+                || result.contributor_ids.len() != proof.leaf_count
+            {
+                return Err("proof does not match the signed contributor ids".to_string());
+            }
+            merkle::decode_hash(&result.merkle_root).map_err(str::to_string)
+        })
+        .and_then(|root| proof.verify(submission.leaf_hash(), &root));
+    match verified {
+        Ok(()) => {
+            println!(
+                "Client {id}: {symbol} round {round_id} includes this submission (leaf {} of {}, merkle root {}).",
+                proof.leaf_index + 1,
+                proof.leaf_count,
+                result.merkle_root
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "Client {id}: {symbol} round {round_id} inclusion proof does not verify: {e}"
+            );
+            false
+        }
+    }
+}
+
+/// Offer to co-sign the result of the round `submission` is for, by committing to fresh
+/// nonces. Returns the nonces once the aggregator has taken the commitment.
+async fn commit_to_cosign<W, R>(
+    write: &mut W,
+    read: &mut R,
+    share: &SecretShare,
+    submission: &Submission,
+) -> Option<Nonces>
+where
+    W: SinkExt<Message> + Unpin,
+    W::Error: std::fmt::Display,
+    R: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let (id, symbol) = (submission.client_id, &submission.symbol);
+    let (nonces, commitment) = match share.commit() {
+        Ok(committed) => committed,
+        Err(e) => {
+            eprintln!("Client {id}: Cannot co-sign {symbol}: {e}");
+            return None;
+        }
+    };
+    let request = WireMessage::CosignCommit {
+        symbol: symbol.clone(),
+        round_id: submission.round_id,
+        client_id: id,
+        commitment,
+    };
+    if let Err(e) = write.send(Message::Text(request.to_text())).await {
+        eprintln!("Client {id}: Failed to send {symbol} co-signing commitment: {e}");
+        return None;
+    }
+    match timeout(Duration::from_secs(5), read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => match protocol::decode(&text) {
+            Ok(WireMessage::Ack { .. }) => Some(nonces),
+            Ok(WireMessage::Error { code, detail }) => {
+                eprintln!(
+                    "Client {id}: {symbol} co-signing commitment refused ({code:?}): {detail}"
+                );
+                None
+            }
+            Ok(other) => {
+                eprintln!("Client {id}: Unexpected reply: {other:?}");
+                None
+            }
+            Err(e) => {
+                eprintln!("Client {id}: Failed to decode reply: {e}");
+                None
+            }
+        },
+        _ => {
+            eprintln!("Client {id}: No reply to {symbol} co-signing commitment.");
+            None
+        }
+    }
+}
+
+/// Fetch the published result of `submission`'s round with the signers' commitments and,
+/// if it is signed with `aggregator_key` and close enough to this client's own price `own`,
+/// send this client's signature share of it.
+async fn cosign<W, R>(
+    write: &mut W,
+    read: &mut R,
+    submission: &Submission,
+    own: f64,
+    nonces: Nonces,
+    checks: &ResultChecks,
+    aggregator_key: &VerifyingKey,
+) where
+    W: SinkExt<Message> + Unpin,
+    W::Error: std::fmt::Display,
+    R: StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let (id, symbol, round_id) = (
+        submission.client_id,
+        &submission.symbol,
+        submission.round_id,
+    );
+    let request = WireMessage::CosignRequest {
+        symbol: symbol.clone(),
+        round_id,
+        client_id: id,
+    };
+    if let Err(e) = write.send(Message::Text(request.to_text())).await {
+        eprintln!("Client {id}: Failed to request {symbol} co-signing package: {e}");
+        return;
+    }
+    let (result, commitments) = match timeout(INCLUSION_PROOF_TIMEOUT, read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => {
+            match protocol::decode(&text) {
+                Ok(WireMessage::CosignPackage {
+                    result,
+                    commitments,
+                }) => (result, commitments.into_iter().collect::<BTreeMap<_, _>>()),
+                Ok(WireMessage::Error { code, detail }) => {
+                    eprintln!("Client {id}: Not co-signing {symbol} round {round_id} ({code:?}): {detail}");
+                    return;
+                }
+                Ok(other) => {
+                    eprintln!("Client {id}: Unexpected reply: {other:?}");
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Client {id}: Failed to decode reply: {e}");
+                    return;
+                }
+            }
+        }
+        _ => {
+            eprintln!("Client {id}: No {symbol} co-signing package from aggregator.");
+            return;
+        }
+    };
+    let Some(share) = &checks.share else {
+        return;
+    };
+    let agreed = verify_result(&result, aggregator_key).and_then(|()| {
+        if result.symbol != *symbol || result.round_id != round_id {
+            return Err(format!(
+                "package is for {} round {}",
+                result.symbol, result.round_id
+            ));
+        }
+        match result.average {
+            Some(average) if (average - own).abs() <= checks.tolerance * own.abs() => Ok(()),
+            Some(average) => Err(format!(
+                "published price {average} is too far from this client's {own}"
+            )),
+            None => Err("result has no price".to_string()),
+        }
+    });
+    if let Err(e) = agreed {
+        eprintln!("Client {id}: Refusing to co-sign {symbol} round {round_id}: {e}");
+        return;
+    }
+    // Sign the payload computed here, so the aggregator cannot have a different one signed.
+    let share = match share.sign(nonces, result.signing_payload().as_bytes(), &commitments) {
+        Ok(share) => share,
+        Err(e) => {
+            eprintln!("Client {id}: Cannot co-sign {symbol} round {round_id}: {e}");
+            return;
+        }
+    };
+    let request = WireMessage::CosignShare {
+        symbol: symbol.clone(),
+        round_id,
+        client_id: id,
+        share,
+    };
+    if let Err(e) = write.send(Message::Text(request.to_text())).await {
+        eprintln!("Client {id}: Failed to send {symbol} signature share: {e}");
+        return;
+    }
+    match timeout(Duration::from_secs(5), read.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => match protocol::decode(&text) {
+            Ok(WireMessage::Ack { .. }) => println!(
+                "Client {id}: Sent signature share for {symbol} round {round_id}, one of {} signers.",
+                commitments.len()
+            ),
+            Ok(WireMessage::Error { code, detail }) => {
+                eprintln!("Client {id}: {symbol} signature share refused ({code:?}): {detail}")
+            }
+            Ok(other) => eprintln!("Client {id}: Unexpected reply: {other:?}"),
+            Err(e) => eprintln!("Client {id}: Failed to decode reply: {e}"),
+        },
+        _ => eprintln!("Client {id}: No reply to {symbol} signature share."),
+    }
+}
+
+/// Check that `result` is signed by `aggregator_key`.
+fn verify_result(result: &GlobalResult, aggregator_key: &VerifyingKey) -> Result<(), String> {
+    let expected = keys::key_id(aggregator_key);
+    if result.aggregator_key != expected {
+        return Err(format!(
+            "signed with aggregator key {}, expected {expected}",
+            result.aggregator_key
+        ));
+    }
+    let signature: [u8; 64] = general_purpose::STANDARD
+        .decode(&result.signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("signature is not 64 base64-encoded bytes")?;
+    aggregator_key
+        .verify(
+            result.signing_payload().as_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| "bad aggregator signature".to_string())
+}
+
+/// Verify a subscribed result and print it with what it was found to be signed by.
+fn report_result(
+    result: &GlobalResult,
+    endpoint: &Endpoint,
+    federation: Option<&Federation>,
+    group_key: Option<&VerifyingKey>,
+) {
+    let mut attested = vec![format!(
+        "signed by aggregator key {}",
+        result.aggregator_key
+    )];
+    let mut missing = Vec::new();
+    if let Err(e) = verify_result(result, &endpoint.aggregator_key) {
+        eprintln!(
+            "Rejecting {} round {} result from server: {e}",
+            result.symbol, result.round_id
+        );
+        return;
+    }
+    if let Some(federation) = federation {
+        let agreeing = federation.agreeing_members(result).len();
+        let tally = format!(
+            "published alike by {agreeing} of {} aggregators",
+            federation.members.len()
+        );
+        if agreeing >= federation.majority {
+            attested.push(format!("final, {tally}"));
         } else {
-            println!("Server sent close frame.");
+            missing.push(format!("not final, only {tally}"));
         }
+    }
+    if let Some(group_key) = group_key {
+        match verify_group_signature(result, group_key) {
+            Ok(true) => attested.push("co-signed by the client group".to_string()),
+            Ok(false) => missing.push("no group signature".to_string()),
+            Err(e) => {
+                eprintln!(
+                    "Rejecting {} round {} result from server: {e}",
+                    result.symbol, result.round_id
+                );
+                return;
+            }
+        }
+    }
+    attested.push(format!("merkle root {}", result.merkle_root));
+    if missing.is_empty() {
+        println!("Received from server: {result:#} [{}]", attested.join(", "));
     } else {
-        eprintln!("Client {id}: No data points collected.");
+        eprintln!(
+            "Received {} round {} result from server with {}: {result:#} [{}]",
+            result.symbol,
+            result.round_id,
+            missing.join(" and "),
+            attested.join(", ")
+        );
     }
 }
 
-pub async fn get_results(duration: u64) {
-    let ws_url = "ws://127.0.0.1:8080";
+/// Check the result's group signature, if it carries one.
+fn verify_group_signature(result: &GlobalResult, group_key: &VerifyingKey) -> Result<bool, String> {
+    let Some(signature) = &result.group_signature else {
+        return Ok(false);
+    };
+    let signature: [u8; 64] = general_purpose::STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("group signature is not 64 base64-encoded bytes")?;
+    group_key
+        .verify(
+            result.signing_payload().as_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .map(|()| true)
+        .map_err(|_| "bad group signature".to_string())
+}
 
-    // Connect to the WebSocket server
-    let (ws_stream, _) = connect_async(ws_url)
-        .await
-        .expect("Failed to connect WebSocket");
+/// Subscribe to the symbol's next result at the first reachable aggregator. With a
+/// federation, the result must be published alike by its majority to be final, and with
+/// `group_key` also co-signed by the client group.
+pub async fn get_results(
+    duration: u64,
+    symbol: String,
+    endpoints: Vec<Endpoint>,
+    federation: Option<Arc<Federation>>,
+    group_key: Option<VerifyingKey>,
+) {
+    let mut connected = None;
+    for endpoint in &endpoints {
+        match tls::connect(&endpoint.url, endpoint.tls.as_ref()).await {
+            Ok(ws_stream) => {
+                connected = Some((endpoint, ws_stream));
+                break;
+            }
+            Err(e) => eprintln!("Failed to connect to aggregator at {}: {e}", endpoint.url),
+        }
+    }
+    let Some((endpoint, ws_stream)) = connected else {
+        eprintln!("No aggregator reachable for final {symbol} result.");
+        return;
+    };
     println!(
-        "Connected to WebSocket server at {} for final client",
-        ws_url
+        "Connected to WebSocket server at {} for final {} client",
+        endpoint.url, symbol
     );
     let (mut write, mut read) = ws_stream.split();
     write
-        .send(Message::Text(format!("receiver,{}", duration)))
+        .send(Message::Text(
+            WireMessage::Subscribe { duration, symbol }.to_text(),
+        ))
         .await
         .expect("Failed to send message");
     if let Some(message) = read.next().await {
         match message {
-            Ok(Message::Text(text)) => {
-                println!("Received from server: {}", text);
-            }
+            Ok(Message::Text(text)) => match protocol::decode(&text) {
+                Ok(WireMessage::GlobalResult(result)) => {
+                    report_result(&result, endpoint, federation.as_deref(), group_key.as_ref())
+                }
+                Ok(WireMessage::Error { code, detail }) => {
+                    eprintln!("Server returned an error ({code:?}): {detail}");
+                }
+                Ok(other) => eprintln!("Unexpected message from server: {other:?}"),
+                Err(e) => eprintln!("Failed to decode server message: {e}"),
+            },
             Ok(Message::Close(_)) => {
                 println!("Server closed the connection.");
             }
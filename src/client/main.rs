@@ -1,36 +1,303 @@
-use mcs_binary::utils;
+use client::{Endpoint, FrameSource};
+use mcs_binary::config::{self, Config};
+use mcs_binary::federation::{self, Federation};
+use mcs_binary::signer::{LocalSigner, Signer, SignerError};
+use mcs_binary::{capture, feed, frost, keys, merkle, protocol, signer, tls, utils};
+use native_tls::TlsConnector;
+use std::process;
+use std::sync::Arc;
 use tokio::task;
 mod client;
 
 #[tokio::main]
 async fn main() {
     let matches = utils::parse_arguments();
+    let config = Config::load(&utils::client_command(), &matches, |name| {
+        std::env::var(name).ok()
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to load configuration: {e}");
+        process::exit(1);
+    });
+    let problems = problems(&config);
+    if matches.subcommand_matches("config").is_some() {
+        process::exit(config::check(&config, &problems));
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("Invalid configuration: {problem}");
+        }
+        process::exit(1);
+    }
 
-    let keypairs = utils::load_private_keys("client_keys.json");
-
+    let key_dir = config.keys.dir.as_str();
     let default_mode = String::default();
     let mode = matches
         .get_one::<String>("mode")
         .unwrap_or(&default_mode)
         .as_str();
-    let times: u64 = matches
-            .get_one::<String>("times")
-            .unwrap_or(&default_mode)
-            .parse()
-            .unwrap_or_else(|_|{ eprintln!("Failed to parse input time value, please enter valid seconds, taking default 1 to calculate.."); 1});
+    let agent = config.keys.agent.as_deref();
+    // Reading saved data only needs the client ids, so keys are unlocked for signing only.
+    let (signer, client_ids) = match open_signer(agent, key_dir, mode == "cache").await {
+        Ok((_, client_ids)) if client_ids.is_empty() => {
+            match agent {
+                Some(socket) => eprintln!("The signing agent at {socket} holds no client keys."),
+                None => match keys::legacy_keys_hint() {
+                    Some(hint) => eprintln!("No client keys found in {key_dir}. {hint}"),
+                    None => eprintln!("No client keys found in {key_dir}. Run keygen first."),
+                },
+            }
+            process::exit(1);
+        }
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Failed to load client keys: {e}");
+            if let Some(hint) = keys::legacy_keys_hint() {
+                eprintln!("{hint}");
+            }
+            process::exit(1);
+        }
+    };
+    let times = config.feeds.duration;
+    // Checked above, so the fallback is never taken.
+    let compute: client::ComputeMode = config
+        .feeds
+        .compute
+        .parse()
+        .unwrap_or(client::ComputeMode::Mean);
+    let symbols = config.symbols();
+    let feed_url = config.feeds.url.as_deref();
+    let feeds: Vec<_> = config
+        .feeds
+        .sources
+        .iter()
+        .filter_map(|spec| {
+            feed::from_spec(spec, feed_url)
+                .map_err(|e| eprintln!("Skipping feed: {e}"))
+                .ok()
+        })
+        .collect();
+    let (recorder, recording) = match matches.get_one::<String>("record") {
+        Some(path) => match capture::CaptureWriter::create(path).await {
+            Ok((writer, task)) => {
+                println!("Recording exchange frames to {path}.");
+                (Some(writer), Some((path, task)))
+            }
+            Err(e) => {
+                eprintln!("Failed to create capture file {path}: {e}");
+                return;
+            }
+        },
+        None => (None, None),
+    };
+    let replay = match matches.get_one::<String>("replay") {
+        Some(path) => match capture::read_capture(path) {
+            Ok(capture) => {
+                if let Some((line, e)) = capture.skipped.first() {
+                    eprintln!(
+                        "Skipped {} unreadable lines of {path}, the first at line {line}: {e}",
+                        capture.skipped.len()
+                    );
+                }
+                Some(capture.frames)
+            }
+            Err(e) => {
+                eprintln!("Failed to read capture file {path}: {e}");
+                return;
+            }
+        },
+        None => None,
+    };
+    let speed: f64 = matches
+        .get_one::<String>("speed")
+        .map(|value| value.parse())
+        .unwrap_or(Ok(1.0))
+        .unwrap_or_else(|_| {
+            eprintln!("Failed to parse replay speed, taking default 1.");
+            1.0
+        });
     match mode {
+        "cache" if feeds.is_empty() && replay.is_none() => {
+            eprintln!("No valid price feed given.")
+        }
         "cache" => {
+            let tls = config.network.tls_ca.as_deref().map(|ca| {
+                tls::connector(ca).unwrap_or_else(|e| {
+                    eprintln!("Failed to load TLS CA certificates: {e}");
+                    process::exit(1);
+                })
+            });
+            let (endpoints, federation) = match &config.network.federation {
+                Some(path) => match federation_endpoints(path, tls.as_ref()) {
+                    Ok((endpoints, loaded)) => {
+                        println!(
+                            "Submitting to {} federated aggregators, results final once {} agree.",
+                            endpoints.len(),
+                            loaded.majority
+                        );
+                        (endpoints, Some(Arc::new(loaded)))
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to load federation: {e}");
+                        process::exit(1);
+                    }
+                },
+                None => match keys::read_public_key(&config.keys.aggregator_key) {
+                    Ok(key) => (
+                        vec![Endpoint {
+                            url: config.network.aggregator_url.clone(),
+                            aggregator_key: key,
+                            tls,
+                        }],
+                        None,
+                    ),
+                    Err(e) => {
+                        eprintln!("Failed to load aggregator public key: {e}");
+                        process::exit(1);
+                    }
+                },
+            };
+            let group = if config.aggregation.cosign {
+                match frost::read_group(&config.keys.group) {
+                    Ok(group) => Some(group),
+                    Err(e) => {
+                        eprintln!("Failed to load threshold group: {e}");
+                        process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let group_key = group.as_ref().map(|group| {
+                group.verifying_key().unwrap_or_else(|e| {
+                    eprintln!("Invalid threshold group: {e}");
+                    process::exit(1);
+                })
+            });
+            let tolerance = config.aggregation.cosign_tolerance;
             let mut clients = Vec::new();
-            for (id, keypair) in keypairs.into_iter().enumerate() {
-                clients.push(task::spawn(client::client_process(id + 1, keypair, times)));
+            for (index, &id) in client_ids.iter().enumerate() {
+                // A client without a share still submits, it just cannot co-sign.
+                let share = group.as_ref().and_then(|group| {
+                    frost::read_share(&frost::share_file_path(key_dir, id), group)
+                        .map_err(|e| eprintln!("Client {id}: Cannot co-sign: {e}"))
+                        .ok()
+                });
+                let checks = client::ResultChecks {
+                    endpoints: endpoints.clone(),
+                    share,
+                    tolerance,
+                };
+                let source = match &replay {
+                    Some(frames) => FrameSource::Replay {
+                        frames: frames
+                            .iter()
+                            .filter(|frame| frame.client_id == id)
+                            .cloned()
+                            .collect(),
+                        speed,
+                    },
+                    None => FrameSource::Live {
+                        feed: feeds[index % feeds.len()].clone(),
+                        recorder: recorder.clone(),
+                        duration: times,
+                    },
+                };
+                clients.push(task::spawn(client::client_process(
+                    id,
+                    signer.clone(),
+                    checks,
+                    compute,
+                    symbols.clone(),
+                    source,
+                    config.storage.data_dir.clone(),
+                )));
             }
             println!("Will listen for {} seconds.", times);
-            clients.push(task::spawn(client::get_results(times)));
+            for symbol in &symbols {
+                clients.push(task::spawn(client::get_results(
+                    times,
+                    symbol.clone(),
+                    endpoints.clone(),
+                    federation.clone(),
+                    group_key,
+                )));
+            }
             for client in clients {
                 let _ = client.await;
             }
         }
-        "read" => utils::read_mode(keypairs.len()).expect("Failed to read price data"),
+        "read" => utils::read_mode(&config.storage.data_dir, &client_ids, &symbols)
+            .expect("Failed to read price data"),
         _ => eprintln!("Invalid mode: {mode}. Use --mode=cache or --mode=read."),
     };
+    // The recording task finishes once the clients' writers and this one are dropped.
+    drop(recorder);
+    if let Some((path, task)) = recording {
+        match task.await {
+            Ok(Ok(frames)) => println!("Recorded {frames} exchange frames to {path}."),
+            Ok(Err(e)) => eprintln!("Failed to record exchange frames to {path}: {e}"),
+            Err(e) => eprintln!("Recording to {path} stopped: {e}"),
+        }
+    }
+}
+
+/// Every problem with the configuration, including the settings only the client reads.
+fn problems(config: &Config) -> Vec<String> {
+    let mut problems = config.problems();
+    if let Err(e) = config.feeds.compute.parse::<client::ComputeMode>() {
+        problems.push(format!("feeds.compute: {e}"));
+    }
+    problems
+}
+
+/// The members of the federation in `path` as endpoints to submit to.
+fn federation_endpoints(
+    path: &str,
+    tls: Option<&TlsConnector>,
+) -> Result<(Vec<Endpoint>, Federation), String> {
+    let loaded = federation::read_federation(path).map_err(|e| e.to_string())?;
+    let endpoints = loaded
+        .members
+        .iter()
+        .map(|member| {
+            Ok(Endpoint {
+                url: member.url.clone(),
+                aggregator_key: member.verifying_key()?,
+                tls: tls.cloned(),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((endpoints, loaded))
+}
+
+/// Sign with the agent listening on `agent`, or else with the key files in `key_dir`, and list
+/// the clients it signs for. To sign, encrypted keys are decrypted and each client must have a
+/// key that is valid now.
+async fn open_signer(
+    agent: Option<&str>,
+    key_dir: &str,
+    unlock: bool,
+) -> Result<(Arc<dyn Signer>, Vec<usize>), SignerError> {
+    let signer: Arc<dyn Signer> = match agent {
+        #[cfg(unix)]
+        Some(socket) => Arc::new(signer::AgentSigner::new(socket)),
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(SignerError::Agent(
+                "signing agents need Unix domain sockets".to_string(),
+            ))
+        }
+        None if unlock => Arc::new(LocalSigner::load(key_dir)?),
+        None => Arc::new(LocalSigner::new(keys::load_client_keys(key_dir)?)),
+    };
+    let client_ids = signer.client_ids().await?;
+    if unlock {
+        let now = utils::unix_millis();
+        for &id in &client_ids {
+            let key_id = signer.current_key_id(id, now).await?;
+            println!("Client {id}: Signing with key {key_id}.");
+        }
+    }
+    Ok((signer, client_ids))
 }
@@ -0,0 +1,609 @@
+use crate::aggregation;
+use crate::feed;
+use crate::frost;
+use crate::keys;
+use crate::protocol;
+use crate::utils;
+use clap::parser::{MatchesError, ValueSource};
+use clap::{ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Configuration file read from the working directory when `--config` is not given.
+pub const DEFAULT_CONFIG: &str = "mcs.toml";
+
+/// Values that turn an environment flag off, as clap reads them.
+const FALSE_LITERALS: [&str; 6] = ["n", "no", "f", "false", "off", "0"];
+
+/// Settings of the client and aggregator. Built from the defaults, then the configuration
+/// file, then environment variables, then command-line options, each overriding the last.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub keys: KeysConfig,
+    pub feeds: FeedsConfig,
+    pub aggregation: AggregationConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Addresses the aggregator listens on; without any, its federation member URL or
+    /// [`utils::DEFAULT_LISTEN`].
+    pub listen: Option<Vec<String>>,
+    /// Aggregator the client submits to without a federation.
+    pub aggregator_url: String,
+    /// Federation file of cross-checking aggregators.
+    pub federation: Option<String>,
+    /// PEM certificate chain the aggregator serves `wss://` with, together with `tls_key`.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM PKCS#8 private key of `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates that `wss://` aggregators are verified against instead of the
+    /// system's trusted roots.
+    pub tls_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Directory of the client key files.
+    pub dir: String,
+    /// Signing agent socket the client signs through instead of reading `dir`.
+    pub agent: Option<String>,
+    /// Public key registry the aggregator verifies submissions with.
+    pub registry: String,
+    /// The aggregator's private signing key.
+    pub signing_key: String,
+    /// The aggregator's public key, which the client checks results against.
+    pub aggregator_key: String,
+    /// Threshold group for co-signing.
+    pub group: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedsConfig {
+    /// Trading pairs the client prices and the aggregator runs rounds for.
+    pub symbols: Vec<String>,
+    /// Price feeds, assigned to clients in turn.
+    pub sources: Vec<String>,
+    /// Endpoint overriding every feed's own.
+    pub url: Option<String>,
+    /// How a client reduces its trades to one price.
+    pub compute: String,
+    /// Seconds the client reads trades for.
+    pub duration: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationConfig {
+    /// Seconds a round collects after its first submission, accepted or rejected.
+    pub deadline: u64,
+    /// Contributors needed for a full result; every registered client without one.
+    pub quorum: Option<String>,
+    /// Aggregation strategy, as accepted by [`aggregation::from_spec`].
+    pub method: String,
+    /// What to do with a round that closes under quorum.
+    pub partial: String,
+    /// Whether results are co-signed by the client group.
+    pub cosign: bool,
+    /// Largest relative distance from a client's own price at which it co-signs.
+    pub cosign_tolerance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory the client and global data files are written to and read from.
+    pub data_dir: PathBuf,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen: None,
+            aggregator_url: utils::DEFAULT_AGGREGATOR_URL.to_string(),
+            federation: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+        }
+    }
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            dir: keys::DEFAULT_KEY_DIR.to_string(),
+            agent: None,
+            registry: keys::DEFAULT_REGISTRY.to_string(),
+            signing_key: keys::DEFAULT_AGGREGATOR_KEY.to_string(),
+            aggregator_key: keys::DEFAULT_AGGREGATOR_PUBLIC_KEY.to_string(),
+            group: frost::DEFAULT_GROUP.to_string(),
+        }
+    }
+}
+
+impl Default for FeedsConfig {
+    fn default() -> Self {
+        FeedsConfig {
+            symbols: vec![utils::DEFAULT_SYMBOLS.to_string()],
+            sources: vec!["binance".to_string()],
+            url: None,
+            compute: "mean".to_string(),
+            duration: 1,
+        }
+    }
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        AggregationConfig {
+            deadline: 10,
+            quorum: None,
+            method: "mean".to_string(),
+            partial: "degraded".to_string(),
+            cosign: false,
+            cosign_tolerance: 0.01,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            data_dir: PathBuf::from("."),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            ConfigError::Invalid(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Read the configuration file named by `--config`, or [`DEFAULT_CONFIG`] if it exists,
+    /// and apply the environment variables of `command`'s options, as looked up in `env`, and
+    /// then the options in `matches` over it.
+    pub fn load(
+        command: &Command,
+        matches: &ArgMatches,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let environment = env_matches(command, env)?;
+        let path = match given::<String>(matches, "config")? {
+            Some(path) => Some(path),
+            None => given(&environment, "config")?,
+        };
+        let mut config = match path {
+            Some(path) => Config::read(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG).exists() => Config::read(Path::new(DEFAULT_CONFIG))?,
+            None => Config::default(),
+        };
+        config.apply(&environment)?;
+        config.apply(matches)?;
+        config.feeds.symbols = config.symbols();
+        Ok(config)
+    }
+
+    pub fn read(path: &Path) -> Result<Config, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&data)
+            .map_err(|e| ConfigError::Invalid(format!("{}: {}", path.display(), e.message())))
+    }
+
+    /// Override settings with the options given in `matches`; clap's own defaults leave the
+    /// configuration as it is.
+    fn apply(&mut self, matches: &ArgMatches) -> Result<(), ConfigError> {
+        let network = &mut self.network;
+        if let Some(listen) = given_many::<String>(matches, "listen")? {
+            network.listen = Some(listen);
+        }
+        set(&mut network.aggregator_url, matches, "aggregator-url")?;
+        if let Some(federation) = given(matches, "federation")? {
+            network.federation = Some(federation);
+        }
+        for (field, id) in [
+            (&mut network.tls_cert, "tls-cert"),
+            (&mut network.tls_key, "tls-key"),
+            (&mut network.tls_ca, "tls-ca"),
+        ] {
+            if let Some(path) = given::<String>(matches, id)? {
+                *field = Some(PathBuf::from(path));
+            }
+        }
+
+        let keys = &mut self.keys;
+        set(&mut keys.dir, matches, "keys")?;
+        if let Some(agent) = given(matches, "agent")? {
+            keys.agent = Some(agent);
+        }
+        set(&mut keys.registry, matches, "registry")?;
+        set(&mut keys.signing_key, matches, "signing-key")?;
+        set(&mut keys.aggregator_key, matches, "aggregator-key")?;
+        set(&mut keys.group, matches, "group")?;
+
+        let feeds = &mut self.feeds;
+        if let Some(symbols) = given::<String>(matches, "symbols")? {
+            feeds.symbols = utils::parse_symbols(&symbols);
+        }
+        if let Some(sources) = given::<String>(matches, "feed")? {
+            feeds.sources = sources.split(',').map(str::to_string).collect();
+        }
+        if let Some(url) = given(matches, "feed-url")? {
+            feeds.url = Some(url);
+        }
+        set(&mut feeds.compute, matches, "compute")?;
+        set(&mut feeds.duration, matches, "times")?;
+
+        let aggregation = &mut self.aggregation;
+        set(&mut aggregation.deadline, matches, "deadline")?;
+        if let Some(quorum) = given(matches, "quorum")? {
+            aggregation.quorum = Some(quorum);
+        }
+        set(&mut aggregation.method, matches, "method")?;
+        set(&mut aggregation.partial, matches, "partial")?;
+        set(&mut aggregation.cosign, matches, "cosign")?;
+        set(
+            &mut aggregation.cosign_tolerance,
+            matches,
+            "cosign-tolerance",
+        )?;
+
+        if let Some(data_dir) = given::<String>(matches, "data-dir")? {
+            self.storage.data_dir = PathBuf::from(data_dir);
+        }
+        Ok(())
+    }
+
+    /// Symbols to price and aggregate, normalised to unique upper-case names.
+    pub fn symbols(&self) -> Vec<String> {
+        utils::parse_symbols(&self.feeds.symbols.join(","))
+    }
+
+    /// Problems with settings either binary would refuse to start with. Settings only one
+    /// binary understands, such as the quorum, are checked by that binary.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for address in self.network.listen.iter().flatten() {
+            if let Err(e) = check_address(address) {
+                problems.push(format!("network.listen: {e}"));
+            }
+        }
+        if let Err(e) = utils::check_url(&self.network.aggregator_url) {
+            problems.push(format!("network.aggregator_url: {e}"));
+        }
+        if self.network.tls_cert.is_some() != self.network.tls_key.is_some() {
+            problems
+                .push("network.tls_cert and network.tls_key must be given together".to_string());
+        }
+        if self.symbols().is_empty() {
+            problems.push("feeds.symbols: no trading pair given".to_string());
+        }
+        for symbol in self.symbols() {
+            if symbol.contains(protocol::PAYLOAD_DELIMITERS) {
+                problems.push(format!("feeds.symbols: {symbol} contains '|' or '='"));
+            }
+        }
+        if self.feeds.sources.is_empty() {
+            problems.push("feeds.sources: no price feed given".to_string());
+        }
+        for source in &self.feeds.sources {
+            if let Err(e) = feed::from_spec(source, self.feeds.url.as_deref()) {
+                problems.push(format!("feeds.sources: {e}"));
+            }
+        }
+        if let Some(url) = &self.feeds.url {
+            if let Err(e) = utils::check_url(url) {
+                problems.push(format!("feeds.url: {e}"));
+            }
+        }
+        if self.aggregation.deadline == 0 {
+            problems.push("aggregation.deadline: must be at least one second".to_string());
+        }
+        if let Err(e) = aggregation::from_spec(&self.aggregation.method) {
+            problems.push(format!("aggregation.method: {e}"));
+        }
+        let tolerance = self.aggregation.cosign_tolerance;
+        if !tolerance.is_finite() || tolerance < 0.0 {
+            problems.push(format!(
+                "aggregation.cosign_tolerance: {tolerance} is not a non-negative number"
+            ));
+        }
+        problems
+    }
+
+    /// The configuration as TOML, as printed by `config check`.
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string_pretty(self).map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+}
+
+/// `config check`: print the effective configuration, then its problems. Returns the exit
+/// code, non-zero if there are any.
+pub fn check(config: &Config, problems: &[String]) -> i32 {
+    match config.to_toml() {
+        Ok(text) => print!("{text}"),
+        Err(e) => {
+            eprintln!("Failed to print configuration: {e}");
+            return 1;
+        }
+    }
+    if problems.is_empty() {
+        eprintln!("Configuration is valid.");
+        return 0;
+    }
+    for problem in problems {
+        eprintln!("Invalid configuration: {problem}");
+    }
+    1
+}
+
+/// The value of option `id` if it was given on the command line. Options the binary does not define are never given; an option whose
+/// value is not of the setting's type is an error.
+fn given<T: Any + Clone + Send + Sync + 'static>(
+    matches: &ArgMatches,
+    id: &str,
+) -> Result<Option<T>, ConfigError> {
+    if !explicit(matches, id) {
+        return Ok(None);
+    }
+    matches
+        .try_get_one::<T>(id)
+        .map(|value| value.cloned())
+        .map_err(|e| mismatch(id, e))
+}
+
+fn given_many<T: Any + Clone + Send + Sync + 'static>(
+    matches: &ArgMatches,
+    id: &str,
+) -> Result<Option<Vec<T>>, ConfigError> {
+    if !explicit(matches, id) {
+        return Ok(None);
+    }
+    matches
+        .try_get_many::<T>(id)
+        .map(|values| values.map(|values| values.cloned().collect()))
+        .map_err(|e| mismatch(id, e))
+}
+
+fn mismatch(id: &str, e: MatchesError) -> ConfigError {
+    ConfigError::Invalid(format!("--{id}: {e}"))
+}
+
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches
+            .try_contains_id(id)
+            .map(|_| matches.value_source(id)),
+        Ok(Some(ValueSource::CommandLine))
+    )
+}
+
+/// Parse the environment variables of `command`'s options, as looked up in `env`, as if they
+/// had been given on the command line, so they are checked like options and can be applied
+/// before them. Empty variables are unset, as clap treats them.
+fn env_matches(
+    command: &Command,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<ArgMatches, ConfigError> {
+    let mut args = vec![command.get_name().to_string()];
+    for arg in command.get_arguments() {
+        let (Some(name), Some(long)) = (arg.get_env(), arg.get_long()) else {
+            continue;
+        };
+        let Some(value) = name.to_str().and_then(&env).filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        if arg.get_action().takes_values() {
+            args.push(format!("--{long}={value}"));
+        } else if !FALSE_LITERALS.contains(&value.to_lowercase().as_str()) {
+            args.push(format!("--{long}"));
+        }
+    }
+    command
+        .clone()
+        .mut_args(|arg| arg.env(None).required(false))
+        .try_get_matches_from(args)
+        .map_err(|e| {
+            let message = e.to_string();
+            let reason = message.lines().next().unwrap_or_default();
+            ConfigError::Invalid(format!(
+                "environment: {}",
+                reason.trim_start_matches("error: ")
+            ))
+        })
+}
+
+fn set<T: Any + Clone + Send + Sync + 'static>(
+    field: &mut T,
+    matches: &ArgMatches,
+    id: &str,
+) -> Result<(), ConfigError> {
+    if let Some(value) = given(matches, id)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+/// Accept a socket address or `HOST:PORT`, with IPv6 hosts in brackets.
+fn check_address(address: &str) -> Result<(), String> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !host.contains(':') => port
+            .parse::<u16>()
+            .map(|_| ())
+            .map_err(|_| format!("{address} has no valid port")),
+        _ => Err(format!(
+            "{address} is not HOST:PORT; write IPv6 addresses as [::1]:8080"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+    use std::collections::HashMap;
+
+    /// A configuration file in the temporary directory, removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mcs-{name}-{}.toml", std::process::id()));
+            fs::write(&path, contents).unwrap();
+            ConfigFile(path)
+        }
+
+        fn arg(&self) -> String {
+            format!("--config={}", self.0.display())
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn options_override_environment_over_file() {
+        let file = ConfigFile::new(
+            "layers",
+            "[feeds]\nsymbols = [\"ethusdt\"]\nduration = 7\n\n\
+             [aggregation]\ndeadline = 20\nmethod = \"median\"\npartial = \"refuse\"\n",
+        );
+        let load = |args: &[&str], env: &[(&str, &str)]| {
+            let env: HashMap<String, String> = env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let command = utils::aggregator_command();
+            let matches = command
+                .clone()
+                .try_get_matches_from(["aggregator", &file.arg()].iter().chain(args))
+                .unwrap();
+            Config::load(&command, &matches, |name| env.get(name).cloned())
+        };
+
+        // Clap's defaults do not override the file.
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.aggregation.deadline, 20);
+        assert_eq!(config.aggregation.method, "median");
+        assert_eq!(config.aggregation.partial, "refuse");
+        assert_eq!(config.feeds.symbols, vec!["ETHUSDT"]);
+        // Client options the aggregator does not define keep their file value.
+        assert_eq!(config.feeds.duration, 7);
+        assert_eq!(config.keys.registry, keys::DEFAULT_REGISTRY);
+
+        let env = [
+            ("MCS_DEADLINE", "30"),
+            ("MCS_METHOD", "trimmed:0.2"),
+            ("MCS_COSIGN", "false"),
+        ];
+        let from_env = load(&[], &env).unwrap();
+        let from_cli = load(&["--deadline", "40", "--symbols", "btcusdt,solusdt"], &env).unwrap();
+
+        assert_eq!(from_env.aggregation.deadline, 30);
+        assert_eq!(from_env.aggregation.method, "trimmed:0.2");
+        assert_eq!(from_env.aggregation.partial, "refuse");
+        assert_eq!(from_cli.aggregation.deadline, 40);
+        assert_eq!(from_cli.aggregation.method, "trimmed:0.2");
+        assert_eq!(from_cli.feeds.symbols, vec!["BTCUSDT", "SOLUSDT"]);
+        assert!(!from_env.aggregation.cosign);
+        assert!(
+            load(&[], &[("MCS_COSIGN", "1")])
+                .unwrap()
+                .aggregation
+                .cosign
+        );
+
+        let error = load(&[], &[("MCS_DEADLINE", "soon")]).unwrap_err();
+        assert!(error.to_string().starts_with("environment: "), "{error}");
+    }
+
+    #[test]
+    fn mistyped_options_are_errors() {
+        // `deadline` without a u64 parser yields a string the setting cannot take.
+        let command = Command::new("test").arg(Arg::new("deadline").long("deadline"));
+        let matches = command
+            .try_get_matches_from(["test", "--deadline", "5"])
+            .unwrap();
+        let mut config = Config::default();
+        let error = config.apply(&matches).unwrap_err();
+        assert!(error.to_string().starts_with("--deadline: "), "{error}");
+        assert_eq!(config.aggregation.deadline, 10);
+    }
+
+    #[test]
+    fn unknown_and_mistyped_settings_are_rejected() {
+        let file = ConfigFile::new("unknown", "[aggregation]\ndeadlne = 5\n");
+        let error = Config::read(&file.0).unwrap_err().to_string();
+        assert!(error.contains("deadlne"), "{error}");
+
+        let file = ConfigFile::new("mistyped", "[aggregation]\ndeadline = \"soon\"\n");
+        assert!(Config::read(&file.0).is_err());
+
+        let error = Config::read(Path::new("/nonexistent/mcs.toml")).unwrap_err();
+        assert!(matches!(error, ConfigError::Io(..)));
+    }
+
+    #[test]
+    fn check_reports_every_problem_of_a_bad_file() {
+        let file = ConfigFile::new(
+            "problems",
+            "[network]\nlisten = [\"::1:8080\"]\naggregator_url = \"http://localhost\"\n\
+             tls_cert = \"cert.pem\"\n\n\
+             [feeds]\nsymbols = [\"btc|usdt\"]\nsources = [\"nasdaq\"]\n\n\
+             [aggregation]\ndeadline = 0\nmethod = \"mode\"\ncosign_tolerance = -1.0\n",
+        );
+        let config = Config::read(&file.0).unwrap();
+        let problems = config.problems();
+        for setting in [
+            "network.listen",
+            "network.aggregator_url",
+            "network.tls_cert and network.tls_key",
+            "feeds.symbols",
+            "feeds.sources",
+            "aggregation.deadline",
+            "aggregation.method",
+            "aggregation.cosign_tolerance",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(setting)),
+                "no problem with {setting} in {problems:?}"
+            );
+        }
+        assert_eq!(problems.len(), 8);
+        assert_eq!(check(&config, &problems), 1);
+
+        let config = Config::default();
+        assert!(config.problems().is_empty());
+        assert_eq!(check(&config, &[]), 0);
+    }
+}
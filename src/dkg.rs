@@ -0,0 +1,543 @@
+use crate::frost::{self, SecretShare, ThresholdGroup};
+use crate::keys::{self, KeyError, KeyRegistry};
+use crate::utils;
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{Signature, Signer as _, SigningKey};
+use frost_ed25519::keys::dkg::{self as frost_dkg, round1, round2};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory the participants of a key generation exchange their messages through.
+pub const DEFAULT_DKG_DIR: &str = "dkg";
+
+/// Prefix of every signed key generation message, so the signatures cannot be mistaken for
+/// signatures on submissions.
+const SIGNATURE_CONTEXT: &[u8] = b"mcs-dkg-v1:";
+
+/// A participant's first round message: its commitment to its secret polynomial and a fresh
+/// key the others encrypt its shares to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Commitment {
+    pub client_id: usize,
+    pub threshold: usize,
+    pub participants: Vec<usize>,
+    pub package: round1::Package,
+    /// Base64 compressed Edwards point.
+    pub exchange_key: String,
+}
+
+/// A participant's second round message: the share of its secret for one other participant,
+/// readable only by that participant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedShare {
+    pub from: usize,
+    pub to: usize,
+    /// Base64 ChaCha20-Poly1305 nonce.
+    pub nonce: String,
+    /// Base64 round 2 package, sealed under the participants' shared exchange key.
+    pub ciphertext: String,
+}
+
+/// A message signed with the sender's client key from the registry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signed<T> {
+    pub body: T,
+    pub key_id: String,
+    /// Base64 Ed25519 signature over the JSON body.
+    pub signature: String,
+}
+
+/// What a participant keeps between the rounds, next to its key file.
+#[derive(Serialize, Deserialize)]
+struct State {
+    client_id: usize,
+    threshold: usize,
+    participants: Vec<usize>,
+    /// Base64 scalar behind the exchange key.
+    exchange_secret: String,
+    round1: Option<round1::SecretPackage>,
+    round2: Option<round2::SecretPackage>,
+    /// Verified commitments of the other participants, kept from the second round.
+    commitments: BTreeMap<usize, Commitment>,
+}
+
+/// Path of a client's key generation state inside the key directory.
+pub fn state_file_path(dir: &str, client_id: usize) -> PathBuf {
+    Path::new(dir).join(format!("client_{client_id}.dkg"))
+}
+
+fn commitment_path(dir: &Path, client_id: usize) -> PathBuf {
+    dir.join(format!("commit_{client_id}.json"))
+}
+
+fn share_path(dir: &Path, from: usize, to: usize) -> PathBuf {
+    dir.join(format!("share_{from}_to_{to}.json"))
+}
+
+/// First round: commit to a random polynomial for a `threshold`-of-`participants` group and
+/// publish the commitment in `dir`.
+pub fn commit(
+    dir: &Path,
+    state_path: &Path,
+    client_id: usize,
+    threshold: usize,
+    participants: &[usize],
+    key: (&str, &SigningKey),
+) -> Result<(), KeyError> {
+    if !participants.contains(&client_id) {
+        return Err(KeyError::Invalid(format!(
+            "client {client_id} is not one of the participants"
+        )));
+    }
+    let max_signers = u16::try_from(participants.len()).map_err(invalid)?;
+    let min_signers = u16::try_from(threshold).map_err(invalid)?;
+    let (secret, package) = frost_dkg::part1(
+        frost::identifier(client_id).map_err(KeyError::Invalid)?,
+        max_signers,
+        min_signers,
+        OsRng,
+    )
+    .map_err(invalid)?;
+    let mut wide = [0u8; 64];
+    OsRng.fill_bytes(&mut wide);
+    let exchange_secret = Scalar::from_bytes_mod_order_wide(&wide);
+    let exchange_key = (exchange_secret * ED25519_BASEPOINT_POINT).compress();
+    let state = State {
+        client_id,
+        threshold,
+        participants: participants.to_vec(),
+        exchange_secret: encode(exchange_secret.as_bytes()),
+        round1: Some(secret),
+        round2: None,
+        commitments: BTreeMap::new(),
+    };
+    write_state(state_path, &state)?;
+    let commitment = Commitment {
+        client_id,
+        threshold,
+        participants: participants.to_vec(),
+        package,
+        exchange_key: encode(exchange_key.as_bytes()),
+    };
+    fs::create_dir_all(dir).map_err(|e| KeyError::Io(dir.to_path_buf(), e))?;
+    write_signed(&commitment_path(dir, client_id), commitment, key)
+}
+
+/// Second round: check every other participant's commitment and publish each of them its
+/// encrypted share of this participant's secret.
+pub fn share(
+    dir: &Path,
+    state_path: &Path,
+    registry: &KeyRegistry,
+    key: (&str, &SigningKey),
+) -> Result<(), KeyError> {
+    let mut state = read_state(state_path)?;
+    let secret = state.round1.take().ok_or_else(|| {
+        KeyError::Invalid(format!(
+            "client {} has already sent its shares",
+            state.client_id
+        ))
+    })?;
+    for &other in state
+        .participants
+        .iter()
+        .filter(|&&id| id != state.client_id)
+    {
+        let commitment: Commitment = read_signed(&commitment_path(dir, other), other, registry)?;
+        if commitment.client_id != other
+            || commitment.threshold != state.threshold
+            || commitment.participants != state.participants
+        {
+            return Err(KeyError::Invalid(format!(
+                "client {other} committed to a different key generation"
+            )));
+        }
+        state.commitments.insert(other, commitment);
+    }
+    let (secret, packages) =
+        frost_dkg::part2(secret, &round1_packages(&state)?).map_err(invalid)?;
+    for (&to, commitment) in &state.commitments {
+        let package = packages
+            .get(&frost::identifier(to).map_err(KeyError::Invalid)?)
+            .ok_or_else(|| KeyError::Invalid(format!("no share for client {to}")))?;
+        let cipher = exchange_cipher(&state, commitment, state.client_id, to)?;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let plaintext = serde_json::to_vec(package).map_err(invalid)?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| KeyError::Invalid(format!("cannot encrypt share for client {to}")))?;
+        let share = EncryptedShare {
+            from: state.client_id,
+            to,
+            nonce: encode(&nonce),
+            ciphertext: encode(&ciphertext),
+        };
+        write_signed(&share_path(dir, state.client_id, to), share, key)?;
+    }
+    state.round2 = Some(secret);
+    write_state(state_path, &state)
+}
+
+/// Third round: decrypt and check the shares sent to this participant, returning its secret
+/// share and the group every participant ends up with.
+pub fn finish(
+    dir: &Path,
+    state_path: &Path,
+    registry: &KeyRegistry,
+) -> Result<(SecretShare, ThresholdGroup), KeyError> {
+    let state = read_state(state_path)?;
+    let secret = state.round2.as_ref().ok_or_else(|| {
+        KeyError::Invalid(format!(
+            "client {} has not sent its shares yet",
+            state.client_id
+        ))
+    })?;
+    let mut packages = BTreeMap::new();
+    for (&from, commitment) in &state.commitments {
+        let share: EncryptedShare =
+            read_signed(&share_path(dir, from, state.client_id), from, registry)?;
+        if share.from != from || share.to != state.client_id {
+            return Err(KeyError::Invalid(format!(
+                "client {from} sent a share meant for client {}",
+                share.to
+            )));
+        }
+        let cipher = exchange_cipher(&state, commitment, from, state.client_id)?;
+        let nonce = decode(&share.nonce)?;
+        if nonce.len() != 12 {
+            return Err(KeyError::Invalid(format!(
+                "share of client {from} has a malformed nonce"
+            )));
+        }
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                decode(&share.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| KeyError::Invalid(format!("cannot decrypt share of client {from}")))?;
+        let package: round2::Package = serde_json::from_slice(&plaintext).map_err(invalid)?;
+        packages.insert(frost::identifier(from).map_err(KeyError::Invalid)?, package);
+    }
+    let (key_package, public) =
+        frost_dkg::part3(secret, &round1_packages(&state)?, &packages).map_err(invalid)?;
+    let group = ThresholdGroup::from_package(&public).map_err(KeyError::Invalid)?;
+    let share = SecretShare::from_package(&key_package, &group).map_err(KeyError::Invalid)?;
+    Ok((share, group))
+}
+
+fn round1_packages(
+    state: &State,
+) -> Result<BTreeMap<frost_ed25519::Identifier, round1::Package>, KeyError> {
+    state
+        .commitments
+        .iter()
+        .map(|(&client_id, commitment)| {
+            let identifier = frost::identifier(client_id).map_err(KeyError::Invalid)?;
+            Ok((identifier, commitment.package.clone()))
+        })
+        .collect()
+}
+
+/// Cipher for shares from `from` to `to`, keyed with the Diffie-Hellman secret of this
+/// participant's exchange key and `other`'s.
+fn exchange_cipher(
+    state: &State,
+    other: &Commitment,
+    from: usize,
+    to: usize,
+) -> Result<ChaCha20Poly1305, KeyError> {
+    let secret: [u8; 32] = decode(&state.exchange_secret)?
+        .try_into()
+        .map_err(|_| KeyError::Invalid("exchange secret must be 32 bytes".to_string()))?;
+    let secret = Option::<Scalar>::from(Scalar::from_canonical_bytes(secret))
+        .ok_or_else(|| KeyError::Invalid("invalid exchange secret".to_string()))?;
+    let point = exchange_point(other)?;
+    let shared = (secret * point).compress();
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNATURE_CONTEXT);
+    hasher.update(shared.as_bytes());
+    hasher.update((from as u64).to_le_bytes());
+    hasher.update((to as u64).to_le_bytes());
+    let key: [u8; 32] = hasher.finalize().into();
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+fn exchange_point(commitment: &Commitment) -> Result<EdwardsPoint, KeyError> {
+    let invalid = || {
+        KeyError::Invalid(format!(
+            "client {} published an invalid exchange key",
+            commitment.client_id
+        ))
+    };
+    let bytes: [u8; 32] = decode(&commitment.exchange_key)?
+        .try_into()
+        .map_err(|_| invalid())?;
+    let point = CompressedEdwardsY(bytes).decompress().ok_or_else(invalid)?;
+    if point.is_small_order() {
+        return Err(invalid());
+    }
+    Ok(point)
+}
+
+fn write_signed<T: Serialize>(
+    path: &Path,
+    body: T,
+    (key_id, key): (&str, &SigningKey),
+) -> Result<(), KeyError> {
+    let bytes = serde_json::to_vec(&body).map_err(invalid)?;
+    let signature = key.sign(&[SIGNATURE_CONTEXT, &bytes].concat());
+    let signed = Signed {
+        body,
+        key_id: key_id.to_string(),
+        signature: encode(&signature.to_bytes()),
+    };
+    let data = serde_json::to_string_pretty(&signed).map_err(invalid)?;
+    keys::replace_file(path, &data, 0o644)
+}
+
+/// Read a message and check it is signed by a currently valid, enabled key of `client_id`.
+fn read_signed<T: Serialize + DeserializeOwned>(
+    path: &Path,
+    client_id: usize,
+    registry: &KeyRegistry,
+) -> Result<T, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+    let signed: Signed<T> = serde_json::from_str(&data)
+        .map_err(|e| KeyError::Invalid(format!("{}: {e}", path.display())))?;
+    let rejected = |reason: &str| KeyError::Invalid(format!("{}: {reason}", path.display()));
+    let record = registry
+        .by_key_id(&signed.key_id)
+        .filter(|record| {
+            record.client_id == client_id
+                && record.enabled
+                && record.validity.contains(utils::unix_millis())
+        })
+        .ok_or_else(|| rejected(&format!("not signed by a valid key of client {client_id}")))?;
+    let signature = decode(&signed.signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| rejected("malformed signature"))?;
+    let bytes = serde_json::to_vec(&signed.body).map_err(invalid)?;
+    record
+        .key
+        .verify_strict(&[SIGNATURE_CONTEXT, &bytes].concat(), &signature)
+        .map_err(|_| rejected("invalid signature"))?;
+    Ok(signed.body)
+}
+
+fn write_state(path: &Path, state: &State) -> Result<(), KeyError> {
+    let data = serde_json::to_string(state).map_err(invalid)?;
+    keys::write_private(path, &data)
+}
+
+fn read_state(path: &Path) -> Result<State, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+    serde_json::from_str(&data).map_err(|e| KeyError::Invalid(format!("{}: {e}", path.display())))
+}
+
+fn invalid(e: impl std::fmt::Display) -> KeyError {
+    KeyError::Invalid(e.to_string())
+}
+
+fn encode(bytes: &[u8]) -> String {
+    general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, KeyError> {
+    general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| KeyError::Invalid(format!("invalid base64: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::ClientKeyFile;
+
+    /// A scratch directory with one key per client and their registry.
+    struct Ceremony {
+        dir: PathBuf,
+        keys: BTreeMap<usize, (String, SigningKey)>,
+        registry: KeyRegistry,
+    }
+
+    impl Ceremony {
+        fn new(name: &str, clients: usize) -> Self {
+            let dir = std::env::temp_dir().join(format!("mcs-dkg-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let mut keys = BTreeMap::new();
+            let mut entries = Vec::new();
+            for client_id in 1..=clients {
+                let key_file = ClientKeyFile::new(client_id, &SigningKey::generate(&mut OsRng));
+                entries.extend(key_file.registry_entries(0).unwrap());
+                keys.insert(
+                    client_id,
+                    key_file.current_key(utils::unix_millis()).unwrap(),
+                );
+            }
+            let registry = KeyRegistry::from_entries(entries).unwrap();
+            Ceremony {
+                dir,
+                keys,
+                registry,
+            }
+        }
+
+        fn state(&self, client_id: usize) -> PathBuf {
+            state_file_path(&self.dir.to_string_lossy(), client_id)
+        }
+
+        fn key(&self, client_id: usize) -> (&str, &SigningKey) {
+            let (key_id, key) = &self.keys[&client_id];
+            (key_id, key)
+        }
+
+        fn commit_and_share(&self, threshold: usize) {
+            let participants: Vec<usize> = self.keys.keys().copied().collect();
+            for &id in &participants {
+                let state = self.state(id);
+                commit(
+                    &self.dir,
+                    &state,
+                    id,
+                    threshold,
+                    &participants,
+                    self.key(id),
+                )
+                .unwrap();
+            }
+            for &id in &participants {
+                share(&self.dir, &self.state(id), &self.registry, self.key(id)).unwrap();
+            }
+        }
+    }
+
+    impl Drop for Ceremony {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn participants_derive_one_group() {
+        let ceremony = Ceremony::new("group", 3);
+        ceremony.commit_and_share(2);
+        let (first, group) = finish(&ceremony.dir, &ceremony.state(1), &ceremony.registry).unwrap();
+        assert_eq!(group.threshold, 2);
+        assert_eq!(group.verifying_shares.len(), 3);
+        let mut shares = BTreeMap::from([(1, first)]);
+        for id in [2, 3] {
+            let (share, other) =
+                finish(&ceremony.dir, &ceremony.state(id), &ceremony.registry).unwrap();
+            assert_eq!(other, group);
+            shares.insert(id, share);
+        }
+
+        let message = b"BTCUSDT round 7";
+        let mut nonces = BTreeMap::new();
+        let mut commitments = BTreeMap::new();
+        for id in [1, 3] {
+            let (secret, commitment) = shares[&id].commit().unwrap();
+            nonces.insert(id, secret);
+            commitments.insert(id, commitment);
+        }
+        let signed = nonces
+            .into_iter()
+            .map(|(id, nonces)| {
+                let share = shares[&id].sign(nonces, message, &commitments).unwrap();
+                let share = group
+                    .verify_share(id, &share, message, &commitments)
+                    .unwrap();
+                (id, share)
+            })
+            .collect();
+        let signature = group.aggregate(message, &commitments, &signed).unwrap();
+        assert!(group
+            .verifying_key()
+            .unwrap()
+            .verify_strict(message, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn forged_messages_are_rejected() {
+        let ceremony = Ceremony::new("forged", 3);
+        let participants = [1, 2, 3];
+        for id in participants {
+            let state = ceremony.state(id);
+            commit(
+                &ceremony.dir,
+                &state,
+                id,
+                2,
+                &participants,
+                ceremony.key(id),
+            )
+            .unwrap();
+        }
+        // Client 3 replaces client 2's commitment with one signed by its own key.
+        let path = commitment_path(&ceremony.dir, 2);
+        let mut forged: Signed<Commitment> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        forged.body.exchange_key =
+            read_signed::<Commitment>(&commitment_path(&ceremony.dir, 3), 3, &ceremony.registry)
+                .unwrap()
+                .exchange_key;
+        let original = fs::read_to_string(&path).unwrap();
+        write_signed(&path, forged.body.clone(), ceremony.key(3)).unwrap();
+        let error = share(
+            &ceremony.dir,
+            &ceremony.state(1),
+            &ceremony.registry,
+            ceremony.key(1),
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("not signed by a valid key of client 2"));
+
+        // Keeping client 2's key id does not help without its signature.
+        forged.key_id = ceremony.keys[&2].0.clone();
+        fs::write(&path, serde_json::to_string(&forged).unwrap()).unwrap();
+        let error = share(
+            &ceremony.dir,
+            &ceremony.state(1),
+            &ceremony.registry,
+            ceremony.key(1),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("invalid signature"));
+
+        fs::write(&path, original).unwrap();
+        for id in participants {
+            share(
+                &ceremony.dir,
+                &ceremony.state(id),
+                &ceremony.registry,
+                ceremony.key(id),
+            )
+            .unwrap();
+        }
+        // Shares are sealed for their recipient: client 3 cannot use the share sent to 2.
+        fs::copy(
+            share_path(&ceremony.dir, 1, 2),
+            share_path(&ceremony.dir, 1, 3),
+        )
+        .unwrap();
+        assert!(finish(&ceremony.dir, &ceremony.state(3), &ceremony.registry).is_err());
+        assert!(finish(&ceremony.dir, &ceremony.state(2), &ceremony.registry).is_ok());
+    }
+}
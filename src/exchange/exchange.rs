@@ -0,0 +1,289 @@
+use crate::utils;
+use futures::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::{accept_hdr_async, tungstenite::protocol::Message};
+
+/// Where the mock exchange takes its trades from.
+#[derive(Debug, Clone)]
+pub enum TradeSource {
+    /// Generated prices following a random walk from `start_price`.
+    RandomWalk {
+        start_price: f64,
+        /// Largest relative price move per trade, e.g. `0.0005` for 0.05%.
+        volatility: f64,
+        seed: Option<u64>,
+    },
+    /// Newline-delimited Binance trade events, replayed in a loop.
+    Fixture { path: String },
+}
+
+/// Settings for the mock exchange server.
+#[derive(Debug, Clone)]
+pub struct ExchangeConfig {
+    pub listen: String,
+    /// Symbols generated in random-walk mode.
+    pub symbols: Vec<String>,
+    /// Trades emitted per second across all symbols.
+    pub rate: u32,
+    pub source: TradeSource,
+}
+
+/// A trade event ready to be sent, tagged with its upper-case symbol.
+#[derive(Debug, Clone)]
+struct TradeEvent {
+    symbol: String,
+    data: Value,
+}
+
+/// Exchange process: Serve a Binance-compatible `@trade` stream on `config.listen`.
+pub async fn exchange_process(config: ExchangeConfig) {
+    let (sender, _) = broadcast::channel::<TradeEvent>(1024);
+    let events = match load_events(&config) {
+        Ok(events) => events,
+        Err(e) => {
+            eprintln!("Exchange: Failed to load trades: {e}");
+            return;
+        }
+    };
+    tokio::spawn(emit_trades(events, config.rate, sender.clone()));
+
+    let listener = TcpListener::bind(&config.listen)
+        .await
+        .expect("Failed to bind to address");
+    println!(
+        "Mock exchange WebSocket server listening on ws://{}",
+        config.listen
+    );
+    while let Ok((stream, _)) = listener.accept().await {
+        let receiver = sender.subscribe();
+        tokio::spawn(serve_connection(stream, receiver));
+    }
+}
+
+/// Build the trade source; random walks are endless, fixtures loop.
+fn load_events(config: &ExchangeConfig) -> Result<EventSource, Box<dyn std::error::Error>> {
+    match &config.source {
+        TradeSource::RandomWalk {
+            start_price,
+            volatility,
+            seed,
+        } => {
+            let rng = Box::new(match seed {
+                Some(seed) => StdRng::seed_from_u64(*seed),
+                None => StdRng::from_entropy(),
+            });
+            let prices = config
+                .symbols
+                .iter()
+                .map(|symbol| (symbol.clone(), *start_price))
+                .collect();
+            Ok(EventSource::RandomWalk {
+                rng,
+                prices,
+                symbols: config.symbols.clone(),
+                volatility: *volatility,
+                trade_id: 0,
+            })
+        }
+        TradeSource::Fixture { path } => {
+            let events: Vec<TradeEvent> = fs::read_to_string(path)?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| {
+                    let value: Value = serde_json::from_str(line).ok()?;
+                    // Accept both raw events and combined-stream frames.
+                    let data = value.get("data").cloned().unwrap_or(value);
+                    let symbol = data.get("s")?.as_str()?.to_uppercase();
+                    Some(TradeEvent { symbol, data })
+                })
+                .collect();
+            if events.is_empty() {
+                return Err(format!("no trade events found in {path}").into());
+            }
+            println!("Exchange: Replaying {} trades from {path}", events.len());
+            Ok(EventSource::Fixture { events, next: 0 })
+        }
+    }
+}
+
+enum EventSource {
+    RandomWalk {
+        rng: Box<StdRng>,
+        prices: HashMap<String, f64>,
+        symbols: Vec<String>,
+        volatility: f64,
+        trade_id: u64,
+    },
+    Fixture {
+        events: Vec<TradeEvent>,
+        next: usize,
+    },
+}
+
+impl EventSource {
+    fn next_event(&mut self) -> TradeEvent {
+        match self {
+            EventSource::RandomWalk {
+                rng,
+                prices,
+                symbols,
+                volatility,
+                trade_id,
+            } => {
+                let symbol = symbols[rng.gen_range(0..symbols.len())].clone();
+                let price = prices.get_mut(&symbol).expect("Every symbol has a price");
+                *price *= 1.0 + rng.gen_range(-*volatility..=*volatility);
+                let quantity: f64 = rng.gen_range(0.0001..1.0);
+                *trade_id += 1;
+                let now = utils::unix_millis();
+                let data = json!({
+                    "e": "trade",
+                    "E": now,
+                    "s": symbol,
+                    "t": *trade_id,
+                    "p": format!("{:.2}", price),
+                    "q": format!("{:.5}", quantity),
+                    "T": now,
+                    "m": rng.gen_bool(0.5),
+                    "M": true,
+                });
+                TradeEvent { symbol, data }
+            }
+            EventSource::Fixture { events, next } => {
+                let event = events[*next].clone();
+                *next = (*next + 1) % events.len();
+                event
+            }
+        }
+    }
+}
+
+async fn emit_trades(mut source: EventSource, rate: u32, sender: broadcast::Sender<TradeEvent>) {
+    let mut ticker = interval(Duration::from_secs(1) / rate.max(1));
+    loop {
+        ticker.tick().await;
+        // Sending only fails while nobody is connected, which is fine.
+        let _ = sender.send(source.next_event());
+    }
+}
+
+/// Streams requested by a Binance-style path: `/ws/<stream>` or `/stream?streams=<a>/<b>`.
+/// Returns the upper-case symbols and whether frames use the combined wrapper.
+fn requested_streams(path_and_query: &str) -> Option<(Vec<String>, bool)> {
+    let (streams, combined) = if let Some(stream) = path_and_query.strip_prefix("/ws/") {
+        (stream.to_string(), false)
+    } else {
+        let query = path_and_query.strip_prefix("/stream?")?;
+        let streams = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("streams="))?;
+        (streams.to_string(), true)
+    };
+    let symbols = streams
+        .split('/')
+        .filter_map(|stream| stream.strip_suffix("@trade"))
+        .map(str::to_uppercase)
+        .collect();
+    Some((symbols, combined))
+}
+
+// The handshake callback's error type is fixed by tungstenite.
+#[allow(clippy::result_large_err)]
+async fn serve_connection(stream: TcpStream, mut receiver: broadcast::Receiver<TradeEvent>) {
+    let mut path = String::new();
+    let callback = |request: &Request, response: Response| {
+        path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or_default();
+        Ok(response)
+    };
+    let mut ws_stream = match accept_hdr_async(stream, callback).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("Exchange: Failed to accept WebSocket: {e}");
+            return;
+        }
+    };
+    let Some((symbols, combined)) = requested_streams(&path) else {
+        eprintln!("Exchange: Unsupported stream path {path}");
+        let _ = ws_stream.close(None).await;
+        return;
+    };
+    println!("Exchange: Client subscribed to {:?}", symbols);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) if symbols.contains(&event.symbol) => {
+                    let frame = if combined {
+                        json!({
+                            "stream": format!("{}@trade", event.symbol.to_lowercase()),
+                            "data": event.data,
+                        })
+                    } else {
+                        event.data
+                    };
+                    if ws_stream.send(Message::Text(frame.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Exchange: Slow client skipped {skipped} trades");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = ws_stream.next() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    println!("Exchange: Client disconnected.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams(symbols: &[&str], combined: bool) -> Option<(Vec<String>, bool)> {
+        Some((symbols.iter().map(|s| s.to_string()).collect(), combined))
+    }
+
+    #[test]
+    fn requested_streams_read_binance_paths() {
+        for (path, expected) in [
+            ("/ws/btcusdt@trade", streams(&["BTCUSDT"], false)),
+            (
+                "/stream?streams=btcusdt@trade/ethusdt@trade",
+                streams(&["BTCUSDT", "ETHUSDT"], true),
+            ),
+            (
+                "/stream?timeUnit=MICROSECOND&streams=solusdt@trade",
+                streams(&["SOLUSDT"], true),
+            ),
+            // Streams other than trades are ignored.
+            (
+                "/stream?streams=btcusdt@depth/ethusdt@trade",
+                streams(&["ETHUSDT"], true),
+            ),
+            ("/ws/btcusdt@kline_1m", streams(&[], false)),
+            ("/", None),
+            ("/api/v3/ticker", None),
+            ("/stream", None),
+            ("/stream?symbols=btcusdt", None),
+        ] {
+            assert_eq!(requested_streams(path), expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use clap::ArgMatches;
+use exchange::{ExchangeConfig, TradeSource};
+use mcs_binary::utils;
+use std::process;
+use std::str::FromStr;
+mod exchange;
+
+/// Above one trade per nanosecond the emit interval rounds down to zero.
+const MAX_RATE: f64 = 1e9;
+
+#[tokio::main]
+async fn main() {
+    let matches = utils::parse_exchange_arguments();
+
+    let listen = matches
+        .get_one::<String>("listen")
+        .cloned()
+        .unwrap_or_else(|| "127.0.0.1:9443".to_string());
+    let symbols = utils::parse_symbols(
+        matches
+            .get_one::<String>("symbols")
+            .map(String::as_str)
+            .unwrap_or(utils::DEFAULT_SYMBOLS),
+    );
+    let rate = parse_number(
+        &matches,
+        "rate",
+        |rate: &f64| (1.0..=MAX_RATE).contains(rate),
+        "a number of trades per second from 1 to 1000000000",
+    )
+    .unwrap_or(20.0) as u32;
+    let source = match matches.get_one::<String>("fixture") {
+        Some(path) => TradeSource::Fixture { path: path.clone() },
+        None => TradeSource::RandomWalk {
+            start_price: parse_number(
+                &matches,
+                "start-price",
+                |price: &f64| price.is_finite() && *price > 0.0,
+                "a positive price",
+            )
+            .unwrap_or(100_000.0),
+            volatility: parse_number(
+                &matches,
+                "volatility",
+                |volatility: &f64| (0.0..1.0).contains(volatility),
+                "a fraction from 0 up to 1",
+            )
+            .unwrap_or(0.0005),
+            seed: parse_number(
+                &matches,
+                "seed",
+                |_: &u64| true,
+                "a whole number from 0 to 18446744073709551615",
+            ),
+        },
+    };
+
+    exchange::exchange_process(ExchangeConfig {
+        listen,
+        symbols,
+        rate,
+        source,
+    })
+    .await;
+}
+
+/// The number given as option `name`, if any. Exits when it does not parse or is not
+/// `valid`, describing the `expected` values.
+fn parse_number<T: FromStr>(
+    matches: &ArgMatches,
+    name: &str,
+    valid: fn(&T) -> bool,
+    expected: &str,
+) -> Option<T> {
+    let value = matches.get_one::<String>(name)?;
+    match value.parse::<T>() {
+        Ok(number) if valid(&number) => Some(number),
+        _ => {
+            eprintln!("Invalid --{name} {value}: expected {expected}.");
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::key_format;
+use crate::keys::{self, KeyError};
+use crate::protocol::GlobalResult;
+use crate::utils;
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Aggregators that cross-check each other's results, read by aggregators and clients.
+pub const DEFAULT_FEDERATION: &str = "federation.json";
+
+/// Aggregators that run the same rounds and cross-check each other's results.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Federation {
+    /// Members, the publishing one included, that must publish the same result for it to be
+    /// final.
+    pub majority: usize,
+    pub members: Vec<FederationMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederationMember {
+    /// WebSocket URL clients and peers reach the aggregator at.
+    pub url: String,
+    /// Public key the aggregator signs results with, in any format
+    /// [`key_format::parse_public_key`] accepts.
+    pub public_key: String,
+}
+
+impl FederationMember {
+    pub fn verifying_key(&self) -> Result<VerifyingKey, String> {
+        key_format::parse_public_key(&self.public_key)
+    }
+
+    pub fn key_id(&self) -> String {
+        self.verifying_key()
+            .map(|key| keys::key_id(&key))
+            .unwrap_or_default()
+    }
+}
+
+impl Federation {
+    /// The smallest majority of `members` aggregators.
+    pub fn default_majority(members: usize) -> usize {
+        members / 2 + 1
+    }
+
+    /// The member whose signing key has id `key_id`.
+    pub fn member(&self, key_id: &str) -> Option<&FederationMember> {
+        self.members.iter().find(|member| member.key_id() == key_id)
+    }
+
+    /// Check the member's signature over `result` as published by that member.
+    pub fn verify_signature(
+        &self,
+        result: &GlobalResult,
+        aggregator_key: &str,
+        signature: &str,
+    ) -> Result<(), String> {
+        let member = self
+            .member(aggregator_key)
+            .ok_or_else(|| format!("aggregator key {aggregator_key} is not a federation member"))?;
+        let signature: [u8; 64] = general_purpose::STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("signature is not 64 base64-encoded bytes")?;
+        member
+            .verifying_key()?
+            .verify(
+                result.signing_payload_for(aggregator_key).as_bytes(),
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| format!("bad signature of aggregator {aggregator_key}"))
+    }
+
+    /// Distinct members whose valid signatures `result` carries, its publisher included.
+    pub fn agreeing_members(&self, result: &GlobalResult) -> BTreeSet<String> {
+        let publisher = (result.aggregator_key.as_str(), result.signature.as_str());
+        let endorsements = result
+            .endorsements
+            .iter()
+            .map(|e| (e.aggregator_key.as_str(), e.signature.as_str()));
+        std::iter::once(publisher)
+            .chain(endorsements)
+            .filter(|(key, signature)| self.verify_signature(result, key, signature).is_ok())
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    /// Whether a majority of the federation signed `result`.
+    pub fn is_final(&self, result: &GlobalResult) -> bool {
+        self.agreeing_members(result).len() >= self.majority
+    }
+}
+
+/// Write the federation description, readable by anyone.
+pub fn write_federation(path: &Path, federation: &Federation) -> Result<(), KeyError> {
+    let data = serde_json::to_string_pretty(federation)
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e.into()))?;
+    fs::write(path, data + "\n").map_err(|e| KeyError::Io(path.to_path_buf(), e))
+}
+
+/// Read and check the federation description.
+pub fn read_federation(path: &str) -> Result<Federation, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    let federation: Federation =
+        serde_json::from_str(&data).map_err(|e| KeyError::Invalid(format!("{path}: {e}")))?;
+    let invalid = |reason: String| KeyError::Invalid(format!("{path}: {reason}"));
+    let mut key_ids = BTreeSet::new();
+    let mut urls = BTreeSet::new();
+    for member in &federation.members {
+        let key = member
+            .verifying_key()
+            .map_err(|e| invalid(format!("member {}: {e}", member.url)))?;
+        if !key_ids.insert(keys::key_id(&key)) {
+            return Err(invalid(format!(
+                "aggregator key {} is listed twice",
+                keys::key_id(&key)
+            )));
+        }
+        utils::check_url(&member.url).map_err(|e| invalid(format!("member {e}")))?;
+        if !urls.insert(member.url.as_str()) {
+            return Err(invalid(format!("{} is listed twice", member.url)));
+        }
+    }
+    // Two disjoint sets of members could each agree on a different result below a majority.
+    let members = federation.members.len();
+    if federation.majority < Federation::default_majority(members) || federation.majority > members
+    {
+        return Err(invalid(format!(
+            "majority {} of {members} members must be more than half and at most all of them",
+            federation.majority
+        )));
+    }
+    Ok(federation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    /// A federation file in the temporary directory, removed when dropped.
+    struct FederationFile(PathBuf);
+
+    impl FederationFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mcs-{name}-{}.json", std::process::id()));
+            fs::write(&path, contents).unwrap();
+            FederationFile(path)
+        }
+
+        fn read(&self) -> Result<Federation, KeyError> {
+            read_federation(self.0.to_str().unwrap())
+        }
+    }
+
+    impl Drop for FederationFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn public_key(seed: u8) -> String {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        general_purpose::STANDARD.encode(key.verifying_key().to_bytes())
+    }
+
+    /// A federation file of `majority` and members `(url, public key)`.
+    fn federation(name: &str, majority: usize, members: &[(&str, &str)]) -> FederationFile {
+        let federation = Federation {
+            majority,
+            members: members
+                .iter()
+                .map(|&(url, public_key)| FederationMember {
+                    url: url.to_string(),
+                    public_key: public_key.to_string(),
+                })
+                .collect(),
+        };
+        FederationFile::new(name, &serde_json::to_string(&federation).unwrap())
+    }
+
+    fn rejection(file: &FederationFile) -> String {
+        match file.read() {
+            Err(KeyError::Invalid(reason)) => reason,
+            other => panic!(
+                "{} was not rejected as invalid: {other:?}",
+                file.0.display()
+            ),
+        }
+    }
+
+    #[test]
+    fn accepts_a_majority_of_distinct_members() {
+        let (a, b, c) = (public_key(1), public_key(2), public_key(3));
+        let members = [
+            ("ws://127.0.0.1:8080", a.as_str()),
+            ("wss://aggregator.example:443", b.as_str()),
+            ("ws://[::1]:8082", c.as_str()),
+        ];
+        let file = federation("federation-ok", 2, &members);
+        let federation = file.read().unwrap();
+        assert_eq!(federation.majority, 2);
+        assert_eq!(federation.members.len(), 3);
+        let key_id = federation.members[1].key_id();
+        assert_eq!(federation.member(&key_id).unwrap().url, members[1].0);
+    }
+
+    #[test]
+    fn rejects_a_majority_of_half_or_fewer_members() {
+        let (a, b, c, d) = (public_key(1), public_key(2), public_key(3), public_key(4));
+        let members = [
+            ("ws://127.0.0.1:8080", a.as_str()),
+            ("ws://127.0.0.1:8081", b.as_str()),
+            ("ws://127.0.0.1:8082", c.as_str()),
+            ("ws://127.0.0.1:8083", d.as_str()),
+        ];
+        for majority in [0, 1, 2] {
+            let file = federation("federation-half", majority, &members);
+            assert!(rejection(&file).contains("more than half"));
+        }
+        assert!(federation("federation-half", 3, &members).read().is_ok());
+    }
+
+    #[test]
+    fn rejects_a_majority_above_the_member_count() {
+        let (a, b) = (public_key(1), public_key(2));
+        let members = [
+            ("ws://127.0.0.1:8080", a.as_str()),
+            ("ws://127.0.0.1:8081", b.as_str()),
+        ];
+        let file = federation("federation-above", 3, &members);
+        assert!(rejection(&file).contains("majority 3 of 2 members"));
+    }
+
+    #[test]
+    fn rejects_duplicate_member_keys_and_urls() {
+        let (a, b) = (public_key(1), public_key(2));
+        let file = federation(
+            "federation-keys",
+            2,
+            &[
+                ("ws://127.0.0.1:8080", a.as_str()),
+                ("ws://127.0.0.1:8081", a.as_str()),
+            ],
+        );
+        assert!(rejection(&file).contains("is listed twice"));
+
+        let file = federation(
+            "federation-urls",
+            2,
+            &[
+                ("ws://127.0.0.1:8080", a.as_str()),
+                ("ws://127.0.0.1:8080", b.as_str()),
+            ],
+        );
+        assert!(rejection(&file).contains("ws://127.0.0.1:8080 is listed twice"));
+    }
+
+    #[test]
+    fn rejects_invalid_member_keys() {
+        let a = public_key(1);
+        for key in ["", "not a key", "AAAA"] {
+            let file = federation(
+                "federation-invalid",
+                2,
+                &[
+                    ("ws://127.0.0.1:8080", a.as_str()),
+                    ("ws://127.0.0.1:8081", key),
+                ],
+            );
+            assert!(rejection(&file).contains("member ws://127.0.0.1:8081: "));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_member_urls() {
+        let (a, b) = (public_key(1), public_key(2));
+        for url in [
+            "127.0.0.1:8081",
+            "http://127.0.0.1:8081",
+            "ws://",
+            "ws://exa mple",
+        ] {
+            let file = federation(
+                "federation-url",
+                2,
+                &[("ws://127.0.0.1:8080", a.as_str()), (url, b.as_str())],
+            );
+            let reason = rejection(&file);
+            assert!(reason.contains(&format!("member {url}")), "{reason}");
+        }
+    }
+
+    #[test]
+    fn rejects_unreadable_and_malformed_files() {
+        assert!(matches!(
+            read_federation("/nonexistent/federation.json"),
+            Err(KeyError::Io(..))
+        ));
+        let file = FederationFile::new("federation-json", "{\"majority\": 1");
+        assert!(rejection(&file).starts_with(file.0.to_str().unwrap()));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::utils::{self, Trade};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Quote currencies recognised when splitting a symbol such as `BTCUSDT`, longest first.
+const KNOWN_QUOTES: [&str; 9] = [
+    "FDUSD", "USDT", "USDC", "BUSD", "USD", "EUR", "GBP", "BTC", "ETH",
+];
+
+/// An exchange trade stream the client can compute prices from.
+pub trait PriceFeed: Send + Sync {
+    /// Short name used on the command line and in logs.
+    fn name(&self) -> &'static str;
+
+    /// WebSocket URL to connect to for `symbols`.
+    fn url(&self, symbols: &[String]) -> String;
+
+    /// Text frames to send once connected, e.g. channel subscriptions.
+    fn subscribe_messages(&self, symbols: &[String]) -> Vec<String>;
+
+    /// Trades contained in a received text frame; empty for heartbeats and other events.
+    fn parse(&self, text: &str) -> Vec<Trade>;
+}
+
+/// Binance `@trade` streams, read through the combined-stream endpoint.
+#[derive(Debug, Clone)]
+pub struct BinanceFeed {
+    pub endpoint: String,
+}
+
+/// Coinbase Exchange `ticker` or `matches` channel.
+#[derive(Debug, Clone)]
+pub struct CoinbaseFeed {
+    pub endpoint: String,
+    pub channel: CoinbaseChannel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinbaseChannel {
+    Ticker,
+    Matches,
+}
+
+/// Kraken WebSocket v2 `trade` channel.
+#[derive(Debug, Clone)]
+pub struct KrakenFeed {
+    pub endpoint: String,
+}
+
+impl Default for BinanceFeed {
+    fn default() -> Self {
+        BinanceFeed {
+            endpoint: "wss://stream.binance.com:9443".to_string(),
+        }
+    }
+}
+
+impl Default for CoinbaseFeed {
+    fn default() -> Self {
+        CoinbaseFeed {
+            endpoint: "wss://ws-feed.exchange.coinbase.com".to_string(),
+            channel: CoinbaseChannel::Matches,
+        }
+    }
+}
+
+impl Default for KrakenFeed {
+    fn default() -> Self {
+        KrakenFeed {
+            endpoint: "wss://ws.kraken.com/v2".to_string(),
+        }
+    }
+}
+
+impl PriceFeed for BinanceFeed {
+    fn name(&self) -> &'static str {
+        "binance"
+    }
+
+    fn url(&self, symbols: &[String]) -> String {
+        let streams: Vec<String> = symbols
+            .iter()
+            .map(|symbol| format!("{}@trade", symbol.to_lowercase()))
+            .collect();
+        format!(
+            "{}/stream?streams={}",
+            self.endpoint.trim_end_matches('/'),
+            streams.join("/")
+        )
+    }
+
+    fn subscribe_messages(&self, _symbols: &[String]) -> Vec<String> {
+        // Streams are selected in the URL.
+        Vec::new()
+    }
+
+    fn parse(&self, text: &str) -> Vec<Trade> {
+        utils::process_message(text).into_iter().collect()
+    }
+}
+
+impl PriceFeed for CoinbaseFeed {
+    fn name(&self) -> &'static str {
+        "coinbase"
+    }
+
+    fn url(&self, _symbols: &[String]) -> String {
+        self.endpoint.clone()
+    }
+
+    fn subscribe_messages(&self, symbols: &[String]) -> Vec<String> {
+        let product_ids: Vec<String> = symbols
+            .iter()
+            .filter_map(|symbol| exchange_symbol(symbol, "-"))
+            .collect();
+        let channel = match self.channel {
+            CoinbaseChannel::Ticker => "ticker",
+            CoinbaseChannel::Matches => "matches",
+        };
+        vec![json!({
+            "type": "subscribe",
+            "product_ids": product_ids,
+            "channels": [channel],
+        })
+        .to_string()]
+    }
+
+    fn parse(&self, text: &str) -> Vec<Trade> {
+        let Ok(event) = serde_json::from_str::<Value>(text) else {
+            return Vec::new();
+        };
+        // Ticker events report the size of the last trade; match events its size.
+        let size_field = match event["type"].as_str() {
+            Some("ticker") => "last_size",
+            Some("match") | Some("last_match") => "size",
+            _ => return Vec::new(),
+        };
+        let trade = (|| {
+            Some(Trade {
+                symbol: canonical_symbol(event["product_id"].as_str()?),
+                price: event["price"].as_str()?.parse().ok()?,
+                quantity: event[size_field].as_str()?.parse().ok()?,
+            })
+        })();
+        trade.into_iter().collect()
+    }
+}
+
+impl PriceFeed for KrakenFeed {
+    fn name(&self) -> &'static str {
+        "kraken"
+    }
+
+    fn url(&self, _symbols: &[String]) -> String {
+        self.endpoint.clone()
+    }
+
+    fn subscribe_messages(&self, symbols: &[String]) -> Vec<String> {
+        let pairs: Vec<String> = symbols
+            .iter()
+            .filter_map(|symbol| exchange_symbol(symbol, "/"))
+            .collect();
+        vec![json!({
+            "method": "subscribe",
+            "params": { "channel": "trade", "symbol": pairs },
+        })
+        .to_string()]
+    }
+
+    fn parse(&self, text: &str) -> Vec<Trade> {
+        let Ok(event) = serde_json::from_str::<Value>(text) else {
+            return Vec::new();
+        };
+        if event["channel"] != "trade" {
+            return Vec::new();
+        }
+        let Some(data) = event["data"].as_array() else {
+            return Vec::new();
+        };
+        data.iter()
+            .filter_map(|trade| {
+                Some(Trade {
+                    symbol: canonical_symbol(trade["symbol"].as_str()?),
+                    price: trade["price"].as_f64()?,
+                    quantity: trade["qty"].as_f64()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Build a feed from its command-line name: `binance`, `coinbase`,
+/// `coinbase:ticker`, `coinbase:matches` or `kraken`. `endpoint` replaces the
+/// exchange's public URL, e.g. to point the feed at a local mock exchange.
+pub fn from_spec(spec: &str, endpoint: Option<&str>) -> Result<Arc<dyn PriceFeed>, String> {
+    let endpoint = |default: String| endpoint.map(str::to_string).unwrap_or(default);
+    match spec.trim().to_lowercase().as_str() {
+        "binance" => Ok(Arc::new(BinanceFeed {
+            endpoint: endpoint(BinanceFeed::default().endpoint),
+        })),
+        "coinbase" | "coinbase:matches" => Ok(Arc::new(CoinbaseFeed {
+            endpoint: endpoint(CoinbaseFeed::default().endpoint),
+            channel: CoinbaseChannel::Matches,
+        })),
+        "coinbase:ticker" => Ok(Arc::new(CoinbaseFeed {
+            endpoint: endpoint(CoinbaseFeed::default().endpoint),
+            channel: CoinbaseChannel::Ticker,
+        })),
+        "kraken" => Ok(Arc::new(KrakenFeed {
+            endpoint: endpoint(KrakenFeed::default().endpoint),
+        })),
+        other => Err(format!(
+            "unknown feed {other:?}, use binance, coinbase, coinbase:ticker or kraken"
+        )),
+    }
+}
+
+/// Exchange-specific spelling of a symbol, e.g. `BTCUSDT` -> `BTC-USDT`.
+fn exchange_symbol(symbol: &str, separator: &str) -> Option<String> {
+    let split = KNOWN_QUOTES.iter().find_map(|quote| {
+        symbol
+            .strip_suffix(quote)
+            .filter(|base| !base.is_empty())
+            .map(|base| (base, *quote))
+    });
+    match split {
+        Some((base, quote)) => Some(format!("{base}{separator}{quote}")),
+        None => {
+            eprintln!("Cannot split symbol {symbol} into base and quote currencies.");
+            None
+        }
+    }
+}
+
+/// The symbol spelling shared by clients and the aggregator, e.g. `BTC/USDT` -> `BTCUSDT`.
+fn canonical_symbol(exchange_symbol: &str) -> String {
+    exchange_symbol.replace(['-', '/'], "").to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(symbol: &str, price: f64, quantity: f64) -> Trade {
+        Trade {
+            symbol: symbol.to_string(),
+            price,
+            quantity,
+        }
+    }
+
+    fn coinbase(channel: CoinbaseChannel) -> CoinbaseFeed {
+        CoinbaseFeed {
+            channel,
+            ..CoinbaseFeed::default()
+        }
+    }
+
+    #[test]
+    fn coinbase_frames() {
+        let ticker = r#"{"type":"ticker","sequence":37475248783,"product_id":"ETH-USD","price":"1285.22","open_24h":"1310.79","volume_24h":"245532.79269678","low_24h":"1280.52","high_24h":"1313.8","volume_30d":"9788783.60117027","best_bid":"1285.04","best_bid_size":"0.46688654","best_ask":"1285.27","best_ask_size":"1.56637040","side":"buy","time":"2022-10-19T23:28:22.061769Z","trade_id":370843401,"last_size":"11.4396987"}"#;
+        let matched = r#"{"type":"match","trade_id":10,"sequence":50,"maker_order_id":"ac928c66-ca53-498f-9c13-a110027a60e8","taker_order_id":"132fb6ae-456b-4654-b4e0-d681ac05cea1","time":"2014-11-07T08:19:27.028459Z","product_id":"BTC-USD","size":"5.23512","price":"400.23","side":"sell"}"#;
+        let last_match = matched.replace(r#""type":"match""#, r#""type":"last_match""#);
+        let cases = [
+            (
+                CoinbaseChannel::Ticker,
+                ticker,
+                vec![trade("ETHUSD", 1285.22, 11.4396987)],
+            ),
+            (
+                CoinbaseChannel::Matches,
+                matched,
+                vec![trade("BTCUSD", 400.23, 5.23512)],
+            ),
+            (
+                CoinbaseChannel::Matches,
+                &last_match,
+                vec![trade("BTCUSD", 400.23, 5.23512)],
+            ),
+        ];
+        for (channel, frame, expected) in cases {
+            assert_eq!(coinbase(channel).parse(frame), expected, "{frame}");
+        }
+    }
+
+    #[test]
+    fn kraken_trade_frames() {
+        let frame = r#"{"channel":"trade","type":"update","data":[{"symbol":"MATIC/USD","side":"buy","price":0.5147,"qty":6423.46326,"ord_type":"limit","trade_id":4665846,"timestamp":"2023-09-25T07:48:36.925533Z"},{"symbol":"MATIC/USD","side":"buy","price":0.5147,"qty":1136.19677815,"ord_type":"limit","trade_id":4665847,"timestamp":"2023-09-25T07:49:36.925603Z"}]}"#;
+        assert_eq!(
+            KrakenFeed::default().parse(frame),
+            [
+                trade("MATICUSD", 0.5147, 6423.46326),
+                trade("MATICUSD", 0.5147, 1136.19677815),
+            ]
+        );
+    }
+
+    #[test]
+    fn binance_trade_frames() {
+        let frame = r#"{"stream":"btcusdt@trade","data":{"e":"trade","E":1672515782136,"s":"BTCUSDT","t":12345,"p":"16500.10000000","q":"0.00100000","T":1672515782136,"m":true,"M":true}}"#;
+        assert_eq!(
+            BinanceFeed::default().parse(frame),
+            [trade("BTCUSDT", 16500.1, 0.001)]
+        );
+    }
+
+    #[test]
+    fn heartbeats_and_subscription_frames_are_skipped() {
+        let coinbase_frames = [
+            r#"{"type":"subscriptions","channels":[{"name":"matches","product_ids":["BTC-USD"]}]}"#,
+            r#"{"type":"heartbeat","last_trade_id":20,"product_id":"BTC-USD","sequence":10,"time":"2014-11-07T08:19:28.464459Z"}"#,
+            r#"{"type":"error","message":"Failed to subscribe","reason":"ETH-XYZ is not a valid product"}"#,
+        ];
+        let kraken_frames = [
+            r#"{"channel":"heartbeat"}"#,
+            r#"{"channel":"status","type":"update","data":[{"version":"2.0.0","system":"online","api_version":"v2","connection_id":12393906104898154338}]}"#,
+            r#"{"method":"subscribe","result":{"channel":"trade","snapshot":true,"symbol":"MATIC/USD"},"success":true,"time_in":"2023-09-25T09:04:31.742599Z","time_out":"2023-09-25T09:04:31.742648Z"}"#,
+        ];
+        for frame in coinbase_frames {
+            for channel in [CoinbaseChannel::Ticker, CoinbaseChannel::Matches] {
+                assert!(coinbase(channel).parse(frame).is_empty(), "{frame}");
+            }
+        }
+        for frame in kraken_frames {
+            assert!(KrakenFeed::default().parse(frame).is_empty(), "{frame}");
+        }
+        assert!(BinanceFeed::default()
+            .parse(r#"{"result":null,"id":1}"#)
+            .is_empty());
+    }
+
+    #[test]
+    fn malformed_frames_yield_no_trades() {
+        let coinbase_frames = [
+            r#"{"type":"match","product_id":"BTC-USD","size":"5.23512","price":"four hundred"}"#,
+            r#"{"type":"match","product_id":"BTC-USD","size":"5.23512","price":400.23}"#,
+            r#"{"type":"ticker","product_id":"BTC-USD","price":"400.23"}"#,
+            "not json",
+        ];
+        for frame in coinbase_frames {
+            for channel in [CoinbaseChannel::Ticker, CoinbaseChannel::Matches] {
+                assert!(coinbase(channel).parse(frame).is_empty(), "{frame}");
+            }
+        }
+        // Kraken sends numbers; a trade with a price string is skipped, its neighbours kept.
+        let frame = r#"{"channel":"trade","type":"update","data":[{"symbol":"BTC/USD","price":"0.5147","qty":1.0},{"symbol":"BTC/USD","price":27000.5,"qty":0.25}]}"#;
+        assert_eq!(
+            KrakenFeed::default().parse(frame),
+            [trade("BTCUSD", 27000.5, 0.25)]
+        );
+        assert!(KrakenFeed::default().parse("[1, 2").is_empty());
+    }
+
+    #[test]
+    fn symbols_convert_between_spellings() {
+        for (symbol, separator, expected) in [
+            ("BTCUSDT", "-", Some("BTC-USDT")),
+            ("BTCUSD", "/", Some("BTC/USD")),
+            ("ETHBTC", "-", Some("ETH-BTC")),
+            ("BTCFDUSD", "/", Some("BTC/FDUSD")),
+            ("SOLEUR", "-", Some("SOL-EUR")),
+            ("USDT", "-", None),
+            ("BTCXYZ", "-", None),
+        ] {
+            assert_eq!(
+                exchange_symbol(symbol, separator).as_deref(),
+                expected,
+                "{symbol}"
+            );
+        }
+        for (exchange_symbol, expected) in [
+            ("BTC-USDT", "BTCUSDT"),
+            ("btc/usd", "BTCUSD"),
+            ("ETHBTC", "ETHBTC"),
+        ] {
+            assert_eq!(canonical_symbol(exchange_symbol), expected);
+        }
+    }
+}
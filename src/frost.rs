@@ -0,0 +1,560 @@
+use crate::keys::{self, KeyError};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Signature, VerifyingKey};
+use frost_ed25519 as frost;
+use frost_ed25519::keys::{KeyPackage, PublicKeyPackage, SigningShare, VerifyingShare};
+use frost_ed25519::round1::{SigningCommitments, SigningNonces};
+use frost_ed25519::{Identifier, SigningPackage};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Public description of the threshold group, read by the aggregator and clients.
+pub const DEFAULT_GROUP: &str = "group.json";
+
+/// A verified signature share of one signer.
+pub type SignatureShare = frost::round2::SignatureShare;
+
+/// The group's public key and every member's verifying share.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThresholdGroup {
+    /// Signature shares needed to sign for the group.
+    pub threshold: usize,
+    /// Base64 Ed25519 public key that group signatures verify against.
+    pub group_key: String,
+    /// Base64 public key of each client's secret share, by client id.
+    pub verifying_shares: BTreeMap<usize, String>,
+}
+
+/// A client's secret share of the group key, kept next to its key file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretShare {
+    pub client_id: usize,
+    pub threshold: usize,
+    pub group_key: String,
+    /// Base64 scalar; never leaves the client.
+    pub secret_share: String,
+}
+
+/// Secret nonces of one signing session. They are consumed by [`SecretShare::sign`], so a pair
+/// can never sign twice.
+pub struct Nonces(SigningNonces);
+
+impl std::fmt::Debug for Nonces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Nonces(..)")
+    }
+}
+
+/// Public commitment to a signer's [`Nonces`], sent in the first signing round.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonceCommitment {
+    /// Base64 hiding nonce commitment.
+    pub hiding: String,
+    /// Base64 binding nonce commitment.
+    pub binding: String,
+}
+
+impl ThresholdGroup {
+    /// The group described by the public outcome of key generation.
+    pub(crate) fn from_package(package: &PublicKeyPackage) -> Result<Self, String> {
+        let threshold = package
+            .min_signers()
+            .ok_or("key generation did not record the threshold")?;
+        let mut verifying_shares = BTreeMap::new();
+        for (identifier, share) in package.verifying_shares() {
+            verifying_shares.insert(
+                client_id(identifier)?,
+                encode(&share.serialize().map_err(|e| e.to_string())?),
+            );
+        }
+        Ok(ThresholdGroup {
+            threshold: threshold as usize,
+            group_key: encode(
+                &package
+                    .verifying_key()
+                    .serialize()
+                    .map_err(|e| e.to_string())?,
+            ),
+            verifying_shares,
+        })
+    }
+
+    /// The key group signatures verify against.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, String> {
+        let bytes: [u8; 32] = decode(&self.group_key)?
+            .try_into()
+            .map_err(|_| "group key must be 32 bytes")?;
+        VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+    }
+
+    fn public_key_package(&self) -> Result<PublicKeyPackage, String> {
+        let verifying_shares = self
+            .verifying_shares
+            .iter()
+            .map(|(&client_id, share)| Ok((identifier(client_id)?, verifying_share(share)?)))
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        let verifying_key = frost::VerifyingKey::deserialize(&decode(&self.group_key)?)
+            .map_err(|e| format!("invalid group key: {e}"))?;
+        let threshold = u16::try_from(self.threshold).map_err(|e| e.to_string())?;
+        Ok(PublicKeyPackage::new(
+            verifying_shares,
+            verifying_key,
+            Some(threshold),
+        ))
+    }
+
+    /// Check a signature share against the signer's verifying share, returning it decoded.
+    pub fn verify_share(
+        &self,
+        client_id: usize,
+        share: &str,
+        message: &[u8],
+        commitments: &BTreeMap<usize, NonceCommitment>,
+    ) -> Result<SignatureShare, String> {
+        let verifying = self
+            .verifying_shares
+            .get(&client_id)
+            .ok_or_else(|| format!("client {client_id} holds no share of the group key"))
+            .and_then(|encoded| verifying_share(encoded))?;
+        if !commitments.contains_key(&client_id) {
+            return Err(format!(
+                "client {client_id} is not a signer of this session"
+            ));
+        }
+        let share = SignatureShare::deserialize(&decode(share)?)
+            .map_err(|e| format!("invalid signature share: {e}"))?;
+        let package = signing_package(message, commitments)?;
+        let group_key = self.public_key_package()?;
+        frost_core::verify_signature_share(
+            identifier(client_id)?,
+            &verifying,
+            &share,
+            &package,
+            group_key.verifying_key(),
+        )
+        .map_err(|_| format!("signature share of client {client_id} is invalid"))?;
+        Ok(share)
+    }
+
+    /// Combine the signers' verified shares into one Ed25519 signature, checked against the
+    /// group key.
+    pub fn aggregate(
+        &self,
+        message: &[u8],
+        commitments: &BTreeMap<usize, NonceCommitment>,
+        shares: &BTreeMap<usize, SignatureShare>,
+    ) -> Result<Signature, String> {
+        if shares.len() != commitments.len() || commitments.len() < self.threshold {
+            return Err(format!(
+                "{} of {} signature shares, at least {} needed",
+                shares.len(),
+                commitments.len(),
+                self.threshold
+            ));
+        }
+        let package = signing_package(message, commitments)?;
+        let shares = shares
+            .iter()
+            .map(|(&client_id, share)| Ok((identifier(client_id)?, *share)))
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        let signature = frost::aggregate(&package, &shares, &self.public_key_package()?)
+            .map_err(|e| format!("cannot aggregate signature shares: {e}"))?;
+        let bytes: [u8; 64] = signature
+            .serialize()
+            .map_err(|e| e.to_string())?
+            .try_into()
+            .map_err(|_| "signature must be 64 bytes")?;
+        let signature = Signature::from_bytes(&bytes);
+        self.verifying_key()?
+            .verify_strict(message, &signature)
+            .map_err(|_| "aggregated signature does not verify".to_string())?;
+        Ok(signature)
+    }
+}
+
+impl SecretShare {
+    /// This client's share from the outcome of key generation.
+    pub(crate) fn from_package(
+        package: &KeyPackage,
+        group: &ThresholdGroup,
+    ) -> Result<Self, String> {
+        Ok(SecretShare {
+            client_id: client_id(package.identifier())?,
+            threshold: group.threshold,
+            group_key: group.group_key.clone(),
+            secret_share: encode(&package.signing_share().serialize()),
+        })
+    }
+
+    fn key_package(&self) -> Result<KeyPackage, String> {
+        let signing_share = SigningShare::deserialize(&decode(&self.secret_share)?)
+            .map_err(|e| format!("invalid secret share: {e}"))?;
+        let verifying_share = VerifyingShare::from(signing_share);
+        let verifying_key = frost::VerifyingKey::deserialize(&decode(&self.group_key)?)
+            .map_err(|e| format!("invalid group key: {e}"))?;
+        let threshold = u16::try_from(self.threshold).map_err(|e| e.to_string())?;
+        Ok(KeyPackage::new(
+            identifier(self.client_id)?,
+            signing_share,
+            verifying_share,
+            verifying_key,
+            threshold,
+        ))
+    }
+
+    /// Draw fresh nonces for one signing session and commit to them.
+    pub fn commit(&self) -> Result<(Nonces, NonceCommitment), String> {
+        let package = self.key_package()?;
+        let (nonces, commitments) = frost::round1::commit(package.signing_share(), &mut OsRng);
+        let commitment = NonceCommitment {
+            hiding: encode(
+                &commitments
+                    .hiding()
+                    .serialize()
+                    .map_err(|e| e.to_string())?,
+            ),
+            binding: encode(
+                &commitments
+                    .binding()
+                    .serialize()
+                    .map_err(|e| e.to_string())?,
+            ),
+        };
+        Ok((Nonces(nonces), commitment))
+    }
+
+    /// Compute this client's signature share of `message` for the signers in `commitments`,
+    /// which must include the commitment to `nonces`.
+    pub fn sign(
+        &self,
+        nonces: Nonces,
+        message: &[u8],
+        commitments: &BTreeMap<usize, NonceCommitment>,
+    ) -> Result<String, String> {
+        let own = commitments
+            .get(&self.client_id)
+            .ok_or_else(|| "the signing session does not include this client".to_string())
+            .and_then(signing_commitments)?;
+        if own != *nonces.0.commitments() {
+            return Err("the signing session altered this client's commitment".to_string());
+        }
+        let package = signing_package(message, commitments)?;
+        let share = frost::round2::sign(&package, &nonces.0, &self.key_package()?)
+            .map_err(|e| format!("cannot sign: {e}"))?;
+        Ok(encode(&share.serialize()))
+    }
+}
+
+fn signing_commitments(commitment: &NonceCommitment) -> Result<SigningCommitments, String> {
+    let decode_commitment = |encoded: &str| {
+        frost::round1::NonceCommitment::deserialize(&decode(encoded)?)
+            .map_err(|e| format!("invalid nonce commitment: {e}"))
+    };
+    Ok(SigningCommitments::new(
+        decode_commitment(&commitment.hiding)?,
+        decode_commitment(&commitment.binding)?,
+    ))
+}
+
+fn signing_package(
+    message: &[u8],
+    commitments: &BTreeMap<usize, NonceCommitment>,
+) -> Result<SigningPackage, String> {
+    let commitments = commitments
+        .iter()
+        .map(|(&client_id, commitment)| {
+            Ok((identifier(client_id)?, signing_commitments(commitment)?))
+        })
+        .collect::<Result<BTreeMap<_, _>, String>>()?;
+    Ok(SigningPackage::new(commitments, message))
+}
+
+/// Write the group description, readable by anyone.
+pub fn write_group(path: &Path, group: &ThresholdGroup) -> Result<(), KeyError> {
+    let data = serde_json::to_string_pretty(group)
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e.into()))?;
+    keys::replace_file(path, &data, 0o644)
+}
+
+/// Read and check the group description.
+pub fn read_group(path: &str) -> Result<ThresholdGroup, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    let group: ThresholdGroup =
+        serde_json::from_str(&data).map_err(|e| KeyError::Invalid(format!("{path}: {e}")))?;
+    let invalid = |reason: String| KeyError::Invalid(format!("{path}: {reason}"));
+    group.verifying_key().map_err(invalid)?;
+    if group.threshold == 0 || group.threshold > group.verifying_shares.len() {
+        return Err(invalid(format!(
+            "threshold {} for {} shares",
+            group.threshold,
+            group.verifying_shares.len()
+        )));
+    }
+    group.public_key_package().map_err(invalid)?;
+    Ok(group)
+}
+
+/// Path of a client's secret share inside the key directory.
+pub fn share_file_path(dir: &str, client_id: usize) -> PathBuf {
+    Path::new(dir).join(format!("client_{client_id}.share"))
+}
+
+/// Write a client's secret share, readable only by the current user.
+pub fn write_share(path: &Path, share: &SecretShare) -> Result<(), KeyError> {
+    let data = serde_json::to_string_pretty(share)
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e.into()))?;
+    keys::write_private(path, &data)
+}
+
+/// Read a client's secret share and check it belongs to `group`.
+pub fn read_share(path: &Path, group: &ThresholdGroup) -> Result<SecretShare, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+    let share: SecretShare = serde_json::from_str(&data)
+        .map_err(|e| KeyError::Invalid(format!("{}: {e}", path.display())))?;
+    let public = share
+        .key_package()
+        .and_then(|package| {
+            package
+                .verifying_share()
+                .serialize()
+                .map_err(|e| e.to_string())
+        })
+        .map_err(KeyError::Invalid)?;
+    if share.group_key != group.group_key
+        || share.threshold != group.threshold
+        || group.verifying_shares.get(&share.client_id) != Some(&encode(&public))
+    {
+        return Err(KeyError::Invalid(format!(
+            "{}: share of client {} does not belong to the threshold group",
+            path.display(),
+            share.client_id
+        )));
+    }
+    Ok(share)
+}
+
+/// FROST identifier of a client: its id. Id 0 is never a valid identifier.
+pub(crate) fn identifier(client_id: usize) -> Result<Identifier, String> {
+    u16::try_from(client_id)
+        .ok()
+        .and_then(|id| Identifier::try_from(id).ok())
+        .ok_or_else(|| format!("client id {client_id} cannot hold a share"))
+}
+
+/// The client id a FROST identifier was made from by [`identifier`].
+pub(crate) fn client_id(identifier: &Identifier) -> Result<usize, String> {
+    let bytes = identifier.serialize();
+    // Identifiers are little-endian scalars; those of client ids fit in the first two bytes.
+    if bytes[2..].iter().any(|&byte| byte != 0) {
+        return Err("identifier does not belong to a client".to_string());
+    }
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn verifying_share(encoded: &str) -> Result<VerifyingShare, String> {
+    VerifyingShare::deserialize(&decode(encoded)?)
+        .map_err(|e| format!("invalid verifying share: {e}"))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("invalid base64: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frost_ed25519::keys::dkg;
+
+    /// Run key generation for clients 1 to `clients` in memory.
+    fn generate(threshold: u16, clients: u16) -> (ThresholdGroup, BTreeMap<usize, SecretShare>) {
+        let ids: Vec<Identifier> = (1..=clients)
+            .map(|id| identifier(id as usize).unwrap())
+            .collect();
+        let mut round1_secrets = BTreeMap::new();
+        let mut round1_packages = BTreeMap::new();
+        for &id in &ids {
+            let (secret, package) = dkg::part1(id, clients, threshold, OsRng).unwrap();
+            round1_secrets.insert(id, secret);
+            round1_packages.insert(id, package);
+        }
+        let received = |id: &Identifier| {
+            let mut packages = round1_packages.clone();
+            packages.remove(id);
+            packages
+        };
+        let mut round2_secrets = BTreeMap::new();
+        let mut sent = BTreeMap::new();
+        for (id, secret) in round1_secrets {
+            let (secret, packages) = dkg::part2(secret, &received(&id)).unwrap();
+            round2_secrets.insert(id, secret);
+            sent.insert(id, packages);
+        }
+        let mut group = None;
+        let mut shares = BTreeMap::new();
+        for (id, secret) in &round2_secrets {
+            let packages = sent
+                .iter()
+                .filter(|(from, _)| *from != id)
+                .map(|(from, packages)| (*from, packages[id].clone()))
+                .collect();
+            let (key_package, public) = dkg::part3(secret, &received(id), &packages).unwrap();
+            let generated = ThresholdGroup::from_package(&public).unwrap();
+            assert_eq!(*group.get_or_insert(generated.clone()), generated);
+            let share = SecretShare::from_package(&key_package, &generated).unwrap();
+            shares.insert(share.client_id, share);
+        }
+        (group.unwrap(), shares)
+    }
+
+    /// Nonce commitments and signature shares of `signers` on `message`.
+    fn sign(
+        shares: &BTreeMap<usize, SecretShare>,
+        signers: &[usize],
+        message: &[u8],
+    ) -> (BTreeMap<usize, NonceCommitment>, BTreeMap<usize, String>) {
+        let mut nonces = BTreeMap::new();
+        let mut commitments = BTreeMap::new();
+        for &id in signers {
+            let (secret, commitment) = shares[&id].commit().unwrap();
+            nonces.insert(id, secret);
+            commitments.insert(id, commitment);
+        }
+        let signed = nonces
+            .into_iter()
+            .map(|(id, nonces)| {
+                let share = shares[&id].sign(nonces, message, &commitments).unwrap();
+                (id, share)
+            })
+            .collect();
+        (commitments, signed)
+    }
+
+    #[test]
+    fn threshold_signature_verifies_as_ed25519() {
+        let (group, shares) = generate(2, 3);
+        assert_eq!(group.threshold, 2);
+        assert_eq!(
+            group.verifying_shares.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        let message = b"BTCUSDT round 7";
+        for signers in [[1, 2], [1, 3], [2, 3]] {
+            let (commitments, signed) = sign(&shares, &signers, message);
+            let verified = signed
+                .iter()
+                .map(|(&id, share)| {
+                    let share = group
+                        .verify_share(id, share, message, &commitments)
+                        .unwrap();
+                    (id, share)
+                })
+                .collect();
+            let signature = group.aggregate(message, &commitments, &verified).unwrap();
+            let key = group.verifying_key().unwrap();
+            assert!(key.verify_strict(message, &signature).is_ok());
+            assert!(key.verify_strict(b"BTCUSDT round 8", &signature).is_err());
+        }
+    }
+
+    #[test]
+    fn shares_round_trip_through_files() {
+        let (group, shares) = generate(2, 3);
+        let read: ThresholdGroup =
+            serde_json::from_str(&serde_json::to_string(&group).unwrap()).unwrap();
+        assert_eq!(read, group);
+        for share in shares.values() {
+            let share: SecretShare =
+                serde_json::from_str(&serde_json::to_string(share).unwrap()).unwrap();
+            let public = share.key_package().unwrap().verifying_share().serialize();
+            assert_eq!(
+                Some(&encode(&public.unwrap())),
+                group.verifying_shares.get(&share.client_id)
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_share_is_rejected() {
+        let (group, shares) = generate(2, 3);
+        let message = b"BTCUSDT round 7";
+        let (commitments, signed) = sign(&shares, &[1, 2], message);
+
+        let mut bytes = decode(&signed[&1]).unwrap();
+        bytes[0] ^= 1;
+        let tampered = encode(&bytes);
+        assert!(group
+            .verify_share(1, &tampered, message, &commitments)
+            .is_err());
+        assert!(group
+            .verify_share(1, &signed[&1], b"BTCUSDT round 8", &commitments)
+            .is_err());
+        // Client 2's share does not verify as client 1's.
+        assert!(group
+            .verify_share(1, &signed[&2], message, &commitments)
+            .is_err());
+    }
+
+    #[test]
+    fn share_from_outside_the_signer_set_is_rejected() {
+        let (group, shares) = generate(2, 3);
+        let message = b"BTCUSDT round 7";
+        let (commitments, _) = sign(&shares, &[1, 2], message);
+        // Client 3 signs in a session of its own that includes it.
+        let (_, own) = sign(&shares, &[1, 3], message);
+        let error = group
+            .verify_share(3, &own[&3], message, &commitments)
+            .unwrap_err();
+        assert_eq!(error, "client 3 is not a signer of this session");
+        let error = group
+            .verify_share(4, &own[&3], message, &commitments)
+            .unwrap_err();
+        assert_eq!(error, "client 4 holds no share of the group key");
+
+        // A signer asked to sign a package without its commitment refuses.
+        let (nonces, _) = shares[&3].commit().unwrap();
+        assert!(shares[&3].sign(nonces, message, &commitments).is_err());
+    }
+
+    #[test]
+    fn signature_below_threshold_is_rejected() {
+        let (group, shares) = generate(3, 4);
+        let message = b"BTCUSDT round 7";
+
+        // Two signers cannot even produce shares for a 3-of-4 group.
+        let mut nonces = BTreeMap::new();
+        let mut commitments = BTreeMap::new();
+        for id in [1, 2] {
+            let (secret, commitment) = shares[&id].commit().unwrap();
+            nonces.insert(id, secret);
+            commitments.insert(id, commitment);
+        }
+        let nonces = nonces.remove(&1).unwrap();
+        assert!(shares[&1].sign(nonces, message, &commitments).is_err());
+
+        // Nor do two valid shares of a three signer session aggregate.
+        let (commitments, signed) = sign(&shares, &[1, 2, 3], message);
+        let mut verified: BTreeMap<usize, SignatureShare> = signed
+            .iter()
+            .map(|(&id, share)| {
+                let share = group
+                    .verify_share(id, share, message, &commitments)
+                    .unwrap();
+                (id, share)
+            })
+            .collect();
+        verified.remove(&3);
+        assert!(group.aggregate(message, &commitments, &verified).is_err());
+        let mut two = commitments.clone();
+        two.remove(&3);
+        assert!(group.aggregate(message, &two, &verified).is_err());
+    }
+}
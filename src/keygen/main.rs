@@ -0,0 +1,623 @@
+use ed25519_dalek::SigningKey;
+use mcs_binary::federation::{self, Federation, FederationMember};
+use mcs_binary::key_format::{self, KeyFormat};
+use mcs_binary::keys::{self, ClientKeyFile, KeyError, KeyRegistry, RegistryEntry};
+use mcs_binary::{dkg, frost, utils};
+use rand::rngs::OsRng;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+fn main() {
+    let matches = utils::parse_keygen_arguments();
+    let num_clients: usize = matches
+        .get_one::<String>("clients")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| {
+            eprintln!("Failed to parse number of clients, taking default 5.");
+            5
+        });
+    let key_dir = matches
+        .get_one::<String>("keys")
+        .map(String::as_str)
+        .unwrap_or(keys::DEFAULT_KEY_DIR);
+    let registry = matches
+        .get_one::<String>("registry")
+        .map(String::as_str)
+        .unwrap_or(keys::DEFAULT_REGISTRY);
+    let aggregator_key = matches
+        .get_one::<String>("aggregator-key")
+        .map(String::as_str)
+        .unwrap_or(keys::DEFAULT_AGGREGATOR_KEY);
+    let force = matches.get_flag("force");
+    let threshold: Option<usize> = matches.get_one::<String>("threshold").map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Failed to parse threshold {value}.");
+            process::exit(1);
+        })
+    });
+    let group = matches
+        .get_one::<String>("group")
+        .map(String::as_str)
+        .unwrap_or(frost::DEFAULT_GROUP);
+    let dkg_dir = matches
+        .get_one::<String>("dkg-dir")
+        .map(String::as_str)
+        .unwrap_or(dkg::DEFAULT_DKG_DIR);
+    let federation = matches
+        .get_one::<String>("federation")
+        .map(String::as_str)
+        .unwrap_or(federation::DEFAULT_FEDERATION);
+    let majority: Option<usize> = matches.get_one::<String>("majority").map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Failed to parse majority {value}.");
+            process::exit(1);
+        })
+    });
+    let rotate: Vec<usize> = match matches.get_one::<String>("rotate") {
+        Some(list) => match list.split(',').map(|id| id.trim().parse()).collect() {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Failed to parse client ids to rotate: {e}");
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    let overlap: u64 = matches
+        .get_one::<String>("overlap")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| {
+            eprintln!("Failed to parse rotation overlap, taking default 3600 seconds.");
+            3600
+        });
+
+    let client_id: Option<usize> = matches.get_one::<String>("client").map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Failed to parse client id {value}.");
+            process::exit(1);
+        })
+    });
+    let format: KeyFormat = matches
+        .get_one::<String>("format")
+        .map(|value| value.parse())
+        .unwrap_or(Ok(KeyFormat::Pem))
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        });
+
+    let mut passphrase = Passphrase {
+        encrypt_new: matches.get_flag("encrypt"),
+        value: None,
+    };
+
+    let result = if let Some(export_id) = matches.get_one::<String>("export") {
+        match export_id.parse() {
+            Ok(export_id) => export(key_dir, export_id, format, matches.get_flag("private")),
+            Err(_) => Err(KeyError::Invalid(format!("invalid client id {export_id}"))),
+        }
+    } else if let Some(step) = matches.get_one::<String>("dkg") {
+        import_client(client_id).and_then(|client_id| {
+            let session = DkgSession {
+                client_id,
+                participants: (1..=num_clients).collect(),
+                key_dir,
+                registry,
+                dir: Path::new(dkg_dir),
+            };
+            match step.as_str() {
+                "commit" => session.commit(threshold.unwrap_or_default(), &mut passphrase),
+                "share" => session.share(&mut passphrase),
+                _ => session.finish(group),
+            }
+        })
+    } else {
+        if matches.get_flag("migrate") {
+            migrate(key_dir, &mut passphrase)
+        } else if let Some(path) = matches.get_one::<String>("import-key") {
+            import_client(client_id).and_then(|client_id| {
+                import_key(path, client_id, key_dir, overlap * 1000, &mut passphrase)
+            })
+        } else if let Some(path) = matches.get_one::<String>("import-public") {
+            import_client(client_id)
+                .and_then(|client_id| import_public(path, client_id, key_dir, registry))
+        } else {
+            generate(num_clients, key_dir, force, &mut passphrase)
+                .and_then(|_| generate_aggregator_key(aggregator_key, force))
+                .and_then(|_| rotate_keys(&rotate, key_dir, overlap * 1000, &mut passphrase))
+                .and_then(|_| match matches.get_one::<String>("federate") {
+                    Some(url) => join_federation(federation, aggregator_key, url, majority),
+                    None => Ok(()),
+                })
+        }
+        .and_then(|_| publish(key_dir, registry))
+    };
+    if let Err(e) = result {
+        eprintln!("Key generation failed: {e}");
+        process::exit(1);
+    }
+}
+
+/// The client an imported key is for, which must be given explicitly.
+fn import_client(client_id: Option<usize>) -> Result<usize, KeyError> {
+    match client_id {
+        None => Err(KeyError::Invalid(
+            "--client is required to import a key".to_string(),
+        )),
+        Some(0) => Err(KeyError::Invalid("client id 0 is reserved".to_string())),
+        Some(client_id) => Ok(client_id),
+    }
+}
+
+/// The passphrase for encrypted key files, asked for at most once per run.
+struct Passphrase {
+    /// Whether newly created key files are encrypted.
+    encrypt_new: bool,
+    value: Option<String>,
+}
+
+impl Passphrase {
+    fn get(&mut self) -> Result<&str, KeyError> {
+        if self.value.is_none() {
+            self.value = Some(keys::read_passphrase(true)?);
+        }
+        Ok(self.value.as_deref().unwrap_or_default())
+    }
+
+    /// Encrypt every key of `key_file` with the passphrase. Keys that are already encrypted
+    /// are unlocked first, which also checks the passphrase matches theirs.
+    fn seal(&mut self, key_file: &mut ClientKeyFile) -> Result<usize, KeyError> {
+        let passphrase = self.get()?;
+        if key_file.is_encrypted() {
+            key_file.unlock(passphrase)?;
+        }
+        key_file.encrypt(passphrase)
+    }
+}
+
+/// Create the key directory, readable by the current user only, unless it exists.
+fn create_key_dir(key_dir: &str) -> Result<(), KeyError> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(key_dir)
+        .map_err(|e| KeyError::Io(PathBuf::from(key_dir), e))
+}
+
+/// Create a key file for every client from 1 to `num_clients` that does not have one yet.
+fn generate(
+    num_clients: usize,
+    key_dir: &str,
+    force: bool,
+    passphrase: &mut Passphrase,
+) -> Result<(), KeyError> {
+    create_key_dir(key_dir)?;
+    for client_id in 1..=num_clients {
+        let path = keys::key_file_path(key_dir, client_id);
+        if path.exists() && !force {
+            let key_file = keys::read_key_file(&path)?;
+            println!(
+                "Keeping {} key(s) of client {client_id}.",
+                key_file.keys.len()
+            );
+            continue;
+        }
+        let mut key_file = ClientKeyFile::new(client_id, &SigningKey::generate(&mut OsRng));
+        if passphrase.encrypt_new {
+            passphrase.seal(&mut key_file)?;
+        }
+        keys::write_key_file(&path, &key_file)?;
+        println!(
+            "Generated key {} for client {client_id} in {}.",
+            key_file.keys[0].key_id,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Create the aggregator's result signing key unless it already has one.
+fn generate_aggregator_key(path: &str, force: bool) -> Result<(), KeyError> {
+    let path = Path::new(path);
+    let public_path = path.with_extension("pub");
+    let key = if path.exists() && !force {
+        let key = keys::read_aggregator_key(&path.to_string_lossy())?;
+        println!(
+            "Keeping aggregator key {}.",
+            keys::key_id(&key.verifying_key())
+        );
+        if public_path.exists() {
+            return Ok(());
+        }
+        key
+    } else {
+        let key = SigningKey::generate(&mut OsRng);
+        println!(
+            "Generated aggregator key {} in {}.",
+            keys::key_id(&key.verifying_key()),
+            path.display()
+        );
+        key
+    };
+    keys::write_aggregator_key(path, &public_path, &key)?;
+    println!("Wrote aggregator public key to {}.", public_path.display());
+    Ok(())
+}
+
+/// One client's part in generating a threshold group key with the other clients, who
+/// exchange their messages through a shared directory.
+struct DkgSession<'a> {
+    client_id: usize,
+    participants: Vec<usize>,
+    key_dir: &'a str,
+    registry: &'a str,
+    dir: &'a Path,
+}
+
+impl DkgSession<'_> {
+    /// The client's current key, which signs its key generation messages.
+    fn signing_key(&self, passphrase: &mut Passphrase) -> Result<(String, SigningKey), KeyError> {
+        let mut key_file = keys::read_key_file(&keys::key_file_path(self.key_dir, self.client_id))?;
+        if key_file.is_encrypted() {
+            key_file.unlock(passphrase.get()?)?;
+        }
+        key_file.current_key(utils::unix_millis())
+    }
+
+    fn state_path(&self) -> PathBuf {
+        dkg::state_file_path(self.key_dir, self.client_id)
+    }
+
+    fn commit(&self, threshold: usize, passphrase: &mut Passphrase) -> Result<(), KeyError> {
+        let (key_id, key) = self.signing_key(passphrase)?;
+        dkg::commit(
+            self.dir,
+            &self.state_path(),
+            self.client_id,
+            threshold,
+            &self.participants,
+            (&key_id, &key),
+        )?;
+        println!(
+            "Client {} committed to a {threshold}-of-{} group in {}; run --dkg share once every client has committed.",
+            self.client_id,
+            self.participants.len(),
+            self.dir.display()
+        );
+        Ok(())
+    }
+
+    fn share(&self, passphrase: &mut Passphrase) -> Result<(), KeyError> {
+        let (key_id, key) = self.signing_key(passphrase)?;
+        let registry = KeyRegistry::load(self.registry)?;
+        dkg::share(self.dir, &self.state_path(), &registry, (&key_id, &key))?;
+        println!(
+            "Client {} sent its encrypted shares to {}; run --dkg finish once every client has sent its shares.",
+            self.client_id,
+            self.dir.display()
+        );
+        Ok(())
+    }
+
+    /// Derive the client's share and write it, together with the group unless another
+    /// client already wrote it, in which case both must agree.
+    fn finish(&self, group_path: &str) -> Result<(), KeyError> {
+        let registry = KeyRegistry::load(self.registry)?;
+        let (share, group) = dkg::finish(self.dir, &self.state_path(), &registry)?;
+        if Path::new(group_path).exists() {
+            if frost::read_group(group_path)? != group {
+                return Err(KeyError::Invalid(format!(
+                    "{group_path} describes a different group than client {} generated",
+                    self.client_id
+                )));
+            }
+        } else {
+            frost::write_group(Path::new(group_path), &group)?;
+        }
+        let path = frost::share_file_path(self.key_dir, self.client_id);
+        frost::write_share(&path, &share)?;
+        fs::remove_file(self.state_path()).map_err(|e| KeyError::Io(self.state_path(), e))?;
+        println!(
+            "Client {} holds a share of {}-of-{} group key {} in {}.",
+            self.client_id,
+            group.threshold,
+            group.verifying_shares.len(),
+            group.group_key,
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Add the aggregator key at `aggregator_key` to the federation file as the member reached
+/// at `url`, replacing any entry with the same key or URL.
+fn join_federation(
+    path: &str,
+    aggregator_key: &str,
+    url: &str,
+    majority: Option<usize>,
+) -> Result<(), KeyError> {
+    let mut joined = if Path::new(path).exists() {
+        federation::read_federation(path)?
+    } else {
+        Federation {
+            majority: 1,
+            members: Vec::new(),
+        }
+    };
+    let key = keys::read_aggregator_key(aggregator_key)?.verifying_key();
+    let key_id = keys::key_id(&key);
+    joined
+        .members
+        .retain(|member| member.url != url && member.key_id() != key_id);
+    joined.members.push(FederationMember {
+        url: url.to_string(),
+        public_key: key_format::encode_public_key(&key, KeyFormat::Base64, "")
+            .map_err(KeyError::Invalid)?,
+    });
+    let members = joined.members.len();
+    joined.majority = majority.unwrap_or_else(|| Federation::default_majority(members));
+    if joined.majority < Federation::default_majority(members) || joined.majority > members {
+        return Err(KeyError::Invalid(format!(
+            "majority {} of {members} members must be more than half and at most all of them",
+            joined.majority
+        )));
+    }
+    federation::write_federation(Path::new(path), &joined)?;
+    println!(
+        "Added aggregator key {key_id} at {url} to {path}: {} members, final once {} agree.",
+        joined.members.len(),
+        joined.majority
+    );
+    Ok(())
+}
+
+/// Give each listed client a new key; its older keys expire `overlap_ms` from now.
+fn rotate_keys(
+    client_ids: &[usize],
+    key_dir: &str,
+    overlap_ms: u64,
+    passphrase: &mut Passphrase,
+) -> Result<(), KeyError> {
+    let now = utils::unix_millis();
+    for &client_id in client_ids {
+        let path = keys::key_file_path(key_dir, client_id);
+        let mut key_file = keys::read_key_file(&path)?;
+        let encrypted = key_file.is_encrypted();
+        key_file.rotate(&SigningKey::generate(&mut OsRng), now, overlap_ms);
+        if encrypted || passphrase.encrypt_new {
+            passphrase.seal(&mut key_file)?;
+        }
+        keys::write_key_file(&path, &key_file)?;
+        println!(
+            "Rotated client {client_id} to key {}; older keys expire in {}s.",
+            key_file.keys[key_file.keys.len() - 1].key_id,
+            overlap_ms / 1000
+        );
+    }
+    Ok(())
+}
+
+/// Import the keys of an earlier version's [`keys::LEGACY_KEYS`], then encrypt every
+/// plaintext key in `key_dir` in place. The legacy file is removed once all its keys are
+/// encrypted in key files.
+fn migrate(key_dir: &str, passphrase: &mut Passphrase) -> Result<(), KeyError> {
+    let legacy = Path::new(keys::LEGACY_KEYS);
+    let imported = legacy.exists() && import_legacy(legacy, key_dir)?;
+    for mut key_file in keys::load_client_keys(key_dir)? {
+        if key_file.keys.iter().all(|key| key.is_encrypted()) {
+            println!(
+                "Keys of client {} are already encrypted.",
+                key_file.client_id
+            );
+            continue;
+        }
+        let encrypted = passphrase.seal(&mut key_file)?;
+        keys::write_key_file(&keys::key_file_path(key_dir, key_file.client_id), &key_file)?;
+        println!(
+            "Encrypted {encrypted} key(s) of client {}.",
+            key_file.client_id
+        );
+    }
+    if imported {
+        fs::remove_file(legacy).map_err(|e| KeyError::Io(legacy.to_path_buf(), e))?;
+        println!("Removed the plaintext keys in {}.", legacy.display());
+    } else if legacy.exists() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(legacy, fs::Permissions::from_mode(0o600))
+                .map_err(|e| KeyError::Io(legacy.to_path_buf(), e))?;
+        }
+        eprintln!(
+            "Kept {} readable by its owner only, its keys are not all in {key_dir}; remove it once they are no longer needed.",
+            legacy.display()
+        );
+    }
+    Ok(())
+}
+
+/// Give each client of a legacy key list a key file holding its key. Clients that already
+/// have a key file keep it. Returns whether every key of the list is now in a key file.
+fn import_legacy(path: &Path, key_dir: &str) -> Result<bool, KeyError> {
+    create_key_dir(key_dir)?;
+    let mut complete = true;
+    for (index, key) in keys::read_legacy_keys(path)?.iter().enumerate() {
+        let client_id = index + 1;
+        let key_id = keys::key_id(&key.verifying_key());
+        let key_path = keys::key_file_path(key_dir, client_id);
+        if key_path.exists() {
+            let key_file = keys::read_key_file(&key_path)?;
+            if !key_file.keys.iter().any(|key| key.key_id == key_id) {
+                complete = false;
+                eprintln!(
+                    "Client {client_id} already has a key file, not importing key {key_id} from {}.",
+                    path.display()
+                );
+            }
+            continue;
+        }
+        keys::write_key_file(&key_path, &ClientKeyFile::new(client_id, key))?;
+        println!(
+            "Imported key {key_id} of client {client_id} from {}.",
+            path.display()
+        );
+    }
+    Ok(complete)
+}
+
+/// Add a PKCS#8 PEM or base64 private key as the newest key of `client_id`, creating its key
+/// file if needed.
+fn import_key(
+    path: &str,
+    client_id: usize,
+    key_dir: &str,
+    overlap_ms: u64,
+    passphrase: &mut Passphrase,
+) -> Result<(), KeyError> {
+    let text = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    let key = key_format::parse_private_key(&text)
+        .map_err(|e| KeyError::Invalid(format!("{path}: {e}")))?;
+    let key_path = keys::key_file_path(key_dir, client_id);
+    let (mut key_file, encrypted) = if key_path.exists() {
+        let mut key_file = keys::read_key_file(&key_path)?;
+        let encrypted = key_file.is_encrypted();
+        key_file.rotate(&key, utils::unix_millis(), overlap_ms);
+        (key_file, encrypted)
+    } else {
+        create_key_dir(key_dir)?;
+        (ClientKeyFile::new(client_id, &key), false)
+    };
+    if encrypted || passphrase.encrypt_new {
+        passphrase.seal(&mut key_file)?;
+    }
+    keys::write_key_file(&key_path, &key_file)?;
+    println!(
+        "Imported key {} for client {client_id} from {path}.",
+        keys::key_id(&key.verifying_key())
+    );
+    Ok(())
+}
+
+/// Register a public key in SPKI PEM, OpenSSH or base64 form for a client whose private key
+/// is kept elsewhere.
+fn import_public(
+    path: &str,
+    client_id: usize,
+    key_dir: &str,
+    registry: &str,
+) -> Result<(), KeyError> {
+    if keys::key_file_path(key_dir, client_id).exists() {
+        return Err(KeyError::Invalid(format!(
+            "client {client_id} has a key file in {key_dir}, import its private key instead"
+        )));
+    }
+    let text = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    let key = key_format::parse_public_key(&text)
+        .map_err(|e| KeyError::Invalid(format!("{path}: {e}")))?;
+    let mut entries = load_existing(registry)?;
+    let key_id = keys::key_id(&key);
+    entries.push(RegistryEntry {
+        client_id,
+        key_id: key_id.clone(),
+        name: format!("client-{client_id}"),
+        public_key: key_format::encode_public_key(&key, KeyFormat::Base64, "")
+            .map_err(KeyError::Invalid)?,
+        enabled: true,
+        added_at: utils::unix_millis(),
+        validity: keys::Validity::default(),
+    });
+    KeyRegistry::from_entries(entries.clone())?;
+    keys::save_registry(registry, &entries)?;
+    println!("Registered public key {key_id} for client {client_id} from {path}.");
+    Ok(())
+}
+
+/// Print the newest valid key of a client: the public key by default, or with `private` the
+/// secret key as PKCS#8 PEM.
+fn export(
+    key_dir: &str,
+    client_id: usize,
+    format: KeyFormat,
+    private: bool,
+) -> Result<(), KeyError> {
+    let mut key_file = keys::read_key_file(&keys::key_file_path(key_dir, client_id))?;
+    let now = utils::unix_millis();
+    let index = key_file
+        .keys
+        .iter()
+        .rposition(|key| key.validity.contains(now))
+        .ok_or_else(|| KeyError::Invalid(format!("client {client_id} has no valid key")))?;
+    let encoded = if private {
+        if key_file.keys[index].is_encrypted() {
+            key_file.keys[index].decrypt(&keys::read_passphrase(false)?)?;
+        }
+        eprintln!("Exporting the private key of client {client_id} unencrypted.");
+        key_format::encode_private_key(&key_file.keys[index].signing_key()?, format)
+    } else {
+        key_format::encode_public_key(
+            &key_file.keys[index].verifying_key()?,
+            format,
+            &format!("client-{client_id}"),
+        )
+    }
+    .map_err(KeyError::Invalid)?;
+    println!("{}", encoded.trim_end());
+    Ok(())
+}
+
+fn load_existing(registry: &str) -> Result<Vec<RegistryEntry>, KeyError> {
+    if Path::new(registry).exists() {
+        keys::load_registry(registry)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Publish the public half of every key in `key_dir` to the registry. Entries of clients
+/// without a key file here, such as imported public keys, are kept as they are.
+fn publish(key_dir: &str, registry: &str) -> Result<(), KeyError> {
+    let existing = load_existing(registry)?;
+    let key_files = keys::load_client_keys(key_dir)?;
+    let local: HashSet<usize> = key_files
+        .iter()
+        .map(|key_file| key_file.client_id)
+        .collect();
+    // Keep the name, enabled flag and registration time of keys that are already registered.
+    let known: HashMap<&str, &RegistryEntry> = existing
+        .iter()
+        .map(|entry| (entry.key_id.as_str(), entry))
+        .collect();
+    let now = utils::unix_millis();
+    let mut entries = Vec::new();
+    for key_file in &key_files {
+        for mut entry in key_file.registry_entries(now)? {
+            if let Some(known) = known.get(entry.key_id.as_str()) {
+                if known.client_id == entry.client_id {
+                    entry.name = known.name.clone();
+                    entry.enabled = known.enabled;
+                    entry.added_at = known.added_at;
+                }
+            }
+            entries.push(entry);
+        }
+    }
+    let external: Vec<RegistryEntry> = existing
+        .iter()
+        .filter(|entry| !local.contains(&entry.client_id))
+        .cloned()
+        .collect();
+    entries.extend(external);
+    let validated = KeyRegistry::from_entries(entries.clone())?;
+    keys::save_registry(registry, &entries)?;
+    println!(
+        "Wrote {} public keys of {} clients to {registry}.",
+        validated.len(),
+        validated.client_count()
+    );
+    Ok(())
+}
@@ -0,0 +1,974 @@
+use crate::key_format;
+use crate::utils;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory holding one private key file per client.
+pub const DEFAULT_KEY_DIR: &str = "keys";
+/// Public-only key registry read by the aggregator.
+pub const DEFAULT_REGISTRY: &str = "registry.json";
+/// PKCS#8 PEM private key the aggregator signs its results with.
+pub const DEFAULT_AGGREGATOR_KEY: &str = "aggregator.key";
+/// SPKI PEM public half of [`DEFAULT_AGGREGATOR_KEY`], given to clients to verify results.
+pub const DEFAULT_AGGREGATOR_PUBLIC_KEY: &str = "aggregator.pub";
+/// Plaintext key list written by versions before per-client key files. Entry `i` holds the
+/// keys of client `i + 1`.
+pub const LEGACY_KEYS: &str = "client_keys.json";
+/// Environment variable holding the passphrase of encrypted key files.
+pub const PASSPHRASE_ENV: &str = "MCS_KEY_PASSPHRASE";
+
+const KDF: &str = "argon2id";
+const CIPHER: &str = "chacha20poly1305";
+
+/// Period in which a key may be used, in milliseconds since the Unix epoch. Missing bounds
+/// leave that side open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<u64>,
+}
+
+impl Validity {
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.not_before.is_none_or(|start| timestamp >= start)
+            && self.not_after.is_none_or(|end| timestamp < end)
+    }
+}
+
+/// A single client's private keys, kept in their own file so no process but that client
+/// needs to read them. Rotation appends a key, so the last entry is the newest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientKeyFile {
+    pub client_id: usize,
+    pub keys: Vec<ClientKey>,
+}
+
+/// One key of a client. The secret is stored either in plain base64 or sealed with a
+/// passphrase; exactly one of `private_key` and `encrypted` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientKey {
+    pub key_id: String,
+    /// Ed25519 secret key, base64-encoded or as PKCS#8 PEM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<EncryptedSecret>,
+    #[serde(flatten)]
+    pub validity: Validity,
+}
+
+/// A secret key sealed with ChaCha20-Poly1305 under a key derived from a passphrase with
+/// Argon2id. The key id is authenticated as associated data, so a sealed secret cannot be
+/// moved to another entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedSecret {
+    /// Base64-encoded public key, so the registry can be written without the passphrase.
+    pub public_key: String,
+    pub kdf: String,
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub cipher: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// A public key entry of the registry file. A client has one entry per key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryEntry {
+    pub client_id: usize,
+    pub key_id: String,
+    /// Human-readable label, `client-<id>` unless edited.
+    #[serde(default)]
+    pub name: String,
+    /// Ed25519 public key, base64-encoded, as SPKI PEM or as an OpenSSH line.
+    pub public_key: String,
+    /// Disabled keys stay in the registry but submissions signed with them are rejected.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Milliseconds since the Unix epoch when the key was registered.
+    #[serde(default)]
+    pub added_at: u64,
+    #[serde(flatten)]
+    pub validity: Validity,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// An entry of [`LEGACY_KEYS`]; its public key is derived from the private one.
+#[derive(Debug, Deserialize)]
+struct LegacyKey {
+    private_key: String,
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(PathBuf, io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            KeyError::Invalid(reason) => write!(f, "invalid key data: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Stable identifier of a public key: the hex-encoded first 8 bytes of its SHA-256 hash.
+pub fn key_id(key: &VerifyingKey) -> String {
+    utils::to_hex(&Sha256::digest(key.as_bytes())[..8])
+}
+
+/// Path of a client's private key file inside `dir`.
+pub fn key_file_path(dir: &str, client_id: usize) -> PathBuf {
+    Path::new(dir).join(format!("client_{client_id}.key"))
+}
+
+fn check_key_id(expected: &str, key: &VerifyingKey) -> Result<(), KeyError> {
+    if key_id(key) != expected {
+        return Err(KeyError::Invalid(format!(
+            "key id {expected} does not match its key"
+        )));
+    }
+    Ok(())
+}
+
+impl ClientKey {
+    pub fn new(key: &SigningKey, validity: Validity) -> Self {
+        ClientKey {
+            key_id: key_id(&key.verifying_key()),
+            private_key: Some(general_purpose::STANDARD.encode(key.to_bytes())),
+            encrypted: None,
+            validity,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.private_key.is_none()
+    }
+
+    /// Decode the secret key and check it still matches the recorded key id. Encrypted keys
+    /// have to be decrypted first.
+    pub fn signing_key(&self) -> Result<SigningKey, KeyError> {
+        let Some(private_key) = &self.private_key else {
+            return Err(KeyError::Invalid(format!(
+                "key {} is encrypted, a passphrase is required",
+                self.key_id
+            )));
+        };
+        let key = key_format::parse_private_key(private_key)
+            .map_err(|e| KeyError::Invalid(format!("key {}: {e}", self.key_id)))?;
+        check_key_id(&self.key_id, &key.verifying_key())?;
+        Ok(key)
+    }
+
+    /// The public key, which is readable without the passphrase.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, KeyError> {
+        match &self.encrypted {
+            Some(sealed) if self.private_key.is_none() => {
+                let key = key_format::parse_public_key(&sealed.public_key)
+                    .map_err(|e| KeyError::Invalid(format!("key {}: {e}", self.key_id)))?;
+                check_key_id(&self.key_id, &key)?;
+                Ok(key)
+            }
+            _ => Ok(self.signing_key()?.verifying_key()),
+        }
+    }
+
+    /// Replace the plaintext secret with one sealed under `passphrase`.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<(), KeyError> {
+        let key = self.signing_key()?;
+        self.encrypted = Some(EncryptedSecret::seal(&key, &self.key_id, passphrase)?);
+        self.private_key = None;
+        Ok(())
+    }
+
+    /// Unseal the secret in memory. The file on disk stays encrypted.
+    pub fn decrypt(&mut self, passphrase: &str) -> Result<(), KeyError> {
+        if let Some(sealed) = &self.encrypted {
+            let key = sealed.open(&self.key_id, passphrase)?;
+            self.private_key = Some(general_purpose::STANDARD.encode(key.to_bytes()));
+            self.encrypted = None;
+        }
+        Ok(())
+    }
+}
+
+impl EncryptedSecret {
+    fn seal(key: &SigningKey, key_id: &str, passphrase: &str) -> Result<Self, KeyError> {
+        let params = seal_params();
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = derive_cipher(passphrase, &salt, params.clone())?;
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: key.as_bytes(),
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| KeyError::Invalid(format!("key {key_id}: encryption failed")))?;
+        Ok(EncryptedSecret {
+            public_key: general_purpose::STANDARD.encode(key.verifying_key().to_bytes()),
+            kdf: KDF.to_string(),
+            salt: general_purpose::STANDARD.encode(salt),
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+            cipher: CIPHER.to_string(),
+            nonce: general_purpose::STANDARD.encode(nonce),
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+        })
+    }
+
+    fn open(&self, key_id: &str, passphrase: &str) -> Result<SigningKey, KeyError> {
+        let invalid = |reason: &str| KeyError::Invalid(format!("key {key_id}: {reason}"));
+        if self.kdf != KDF || self.cipher != CIPHER {
+            return Err(invalid(&format!(
+                "unsupported encryption {} with {}",
+                self.kdf, self.cipher
+            )));
+        }
+        let decode = |field: &str| {
+            general_purpose::STANDARD
+                .decode(field)
+                .map_err(|e| invalid(&e.to_string()))
+        };
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(invalid("nonce must be 12 bytes"));
+        }
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| invalid(&e.to_string()))?;
+        let cipher = derive_cipher(passphrase, &decode(&self.salt)?, params)?;
+        let secret = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &decode(&self.ciphertext)?,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid("wrong passphrase or corrupted key"))?;
+        let bytes: [u8; 32] = secret
+            .try_into()
+            .map_err(|_| invalid("private key must be 32 bytes"))?;
+        let key = SigningKey::from_bytes(&bytes);
+        check_key_id(key_id, &key.verifying_key())?;
+        Ok(key)
+    }
+}
+
+/// Argon2id costs new secrets are sealed with. Opening uses the costs recorded with the
+/// secret, so the tests can seal with the cheapest ones.
+#[cfg(not(test))]
+fn seal_params() -> Params {
+    Params::default()
+}
+
+#[cfg(test)]
+fn seal_params() -> Params {
+    Params::new(
+        Params::MIN_M_COST,
+        Params::MIN_T_COST,
+        Params::MIN_P_COST,
+        None,
+    )
+    .expect("minimum Argon2 parameters are valid")
+}
+
+fn derive_cipher(
+    passphrase: &str,
+    salt: &[u8],
+    params: Params,
+) -> Result<ChaCha20Poly1305, KeyError> {
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| KeyError::Invalid(format!("key derivation failed: {e}")))?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    key.fill(0);
+    Ok(cipher)
+}
+
+/// Read the key file passphrase from [`PASSPHRASE_ENV`], or prompt for it on the terminal.
+/// With `confirm` the prompt asks twice, for passphrases that are about to be set.
+pub fn read_passphrase(confirm: bool) -> Result<String, KeyError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let prompt_error = |e| {
+        KeyError::Invalid(format!(
+            "cannot prompt for the passphrase ({e}), set {PASSPHRASE_ENV} instead"
+        ))
+    };
+    let passphrase = rpassword::prompt_password("Key passphrase: ").map_err(prompt_error)?;
+    if passphrase.is_empty() {
+        return Err(KeyError::Invalid("empty passphrase".to_string()));
+    }
+    if confirm
+        && rpassword::prompt_password("Repeat passphrase: ").map_err(prompt_error)? != passphrase
+    {
+        return Err(KeyError::Invalid("passphrases do not match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+impl ClientKeyFile {
+    pub fn new(client_id: usize, key: &SigningKey) -> Self {
+        ClientKeyFile {
+            client_id,
+            keys: vec![ClientKey::new(key, Validity::default())],
+        }
+    }
+
+    /// Add `key` as the newest key, valid from `now`. Older keys stay valid for `overlap_ms`
+    /// more so submissions signed before the switch still verify.
+    pub fn rotate(&mut self, key: &SigningKey, now: u64, overlap_ms: u64) {
+        let retire_at = now + overlap_ms;
+        for old in &mut self.keys {
+            if old.validity.not_after.is_none_or(|end| end > retire_at) {
+                old.validity.not_after = Some(retire_at);
+            }
+        }
+        self.keys.push(ClientKey::new(
+            key,
+            Validity {
+                not_before: Some(now),
+                not_after: None,
+            },
+        ));
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.keys.iter().any(ClientKey::is_encrypted)
+    }
+
+    /// Encrypt every key still stored in plaintext, returning how many were encrypted.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<usize, KeyError> {
+        let mut encrypted = 0;
+        for key in self.keys.iter_mut().filter(|key| !key.is_encrypted()) {
+            key.encrypt(passphrase)?;
+            encrypted += 1;
+        }
+        Ok(encrypted)
+    }
+
+    /// Decrypt every key in memory so the client can sign with them.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), KeyError> {
+        self.keys
+            .iter_mut()
+            .try_for_each(|key| key.decrypt(passphrase))
+    }
+
+    /// The newest key that is valid at `now`, with its key id.
+    pub fn current_key(&self, now: u64) -> Result<(String, SigningKey), KeyError> {
+        let key = self
+            .keys
+            .iter()
+            .rev()
+            .find(|key| key.validity.contains(now))
+            .ok_or_else(|| {
+                KeyError::Invalid(format!("client {} has no valid key", self.client_id))
+            })?;
+        Ok((key.key_id.clone(), key.signing_key()?))
+    }
+
+    /// The public halves of these keys, as published in the registry.
+    pub fn registry_entries(&self, added_at: u64) -> Result<Vec<RegistryEntry>, KeyError> {
+        self.keys
+            .iter()
+            .map(|key| {
+                let public_key = key.verifying_key()?;
+                Ok(RegistryEntry {
+                    client_id: self.client_id,
+                    key_id: key.key_id.clone(),
+                    name: format!("client-{}", self.client_id),
+                    public_key: general_purpose::STANDARD.encode(public_key.to_bytes()),
+                    enabled: true,
+                    added_at,
+                    validity: key.validity,
+                })
+            })
+            .collect()
+    }
+}
+
+impl RegistryEntry {
+    /// Decode the public key, given as base64, SPKI PEM or an OpenSSH line.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, KeyError> {
+        key_format::parse_public_key(&self.public_key)
+            .map_err(|e| KeyError::Invalid(format!("client {}: {e}", self.client_id)))
+    }
+}
+
+/// Write a private key file that only the current user can read.
+pub fn write_key_file(path: &Path, key_file: &ClientKeyFile) -> Result<(), KeyError> {
+    let data = serde_json::to_string_pretty(key_file)
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e.into()))?;
+    write_private(path, &data)
+}
+
+/// Write a file only the current user can read. The file is replaced rather than rewritten,
+/// so an existing file with looser permissions does not keep them.
+pub(crate) fn write_private(path: &Path, data: &str) -> Result<(), KeyError> {
+    replace_file(path, data, 0o600)
+}
+
+/// Write the aggregator's private key to `path` as PKCS#8 PEM, readable by the current user
+/// only, and its public key as SPKI PEM to `public_path`.
+pub fn write_aggregator_key(
+    path: &Path,
+    public_path: &Path,
+    key: &SigningKey,
+) -> Result<(), KeyError> {
+    let pem = key_format::encode_private_key(key, key_format::KeyFormat::Pem)
+        .map_err(KeyError::Invalid)?;
+    write_private(path, &pem)?;
+    let public_pem =
+        key_format::encode_public_key(&key.verifying_key(), key_format::KeyFormat::Pem, "")
+            .map_err(KeyError::Invalid)?;
+    fs::write(public_path, public_pem).map_err(|e| KeyError::Io(public_path.to_path_buf(), e))
+}
+
+/// Read the aggregator's private key, in any format [`key_format::parse_private_key`] accepts.
+pub fn read_aggregator_key(path: &str) -> Result<SigningKey, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    key_format::parse_private_key(&data).map_err(|e| KeyError::Invalid(format!("{path}: {e}")))
+}
+
+/// Read a public key file, in any format [`key_format::parse_public_key`] accepts.
+pub fn read_public_key(path: &str) -> Result<VerifyingKey, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    key_format::parse_public_key(&data).map_err(|e| KeyError::Invalid(format!("{path}: {e}")))
+}
+
+pub fn read_key_file(path: &Path) -> Result<ClientKeyFile, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+    serde_json::from_str(&data).map_err(|e| KeyError::Invalid(format!("{}: {e}", path.display())))
+}
+
+/// Read the private keys of a [`LEGACY_KEYS`] file, in client order.
+pub fn read_legacy_keys(path: &Path) -> Result<Vec<SigningKey>, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+    let entries: Vec<LegacyKey> = serde_json::from_str(&data)
+        .map_err(|e| KeyError::Invalid(format!("{}: {e}", path.display())))?;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            key_format::parse_private_key(&entry.private_key).map_err(|e| {
+                KeyError::Invalid(format!("{}: client {}: {e}", path.display(), index + 1))
+            })
+        })
+        .collect()
+}
+
+/// Advice for running with keys of an earlier version, if its [`LEGACY_KEYS`] file is here.
+pub fn legacy_keys_hint() -> Option<String> {
+    Path::new(LEGACY_KEYS).exists().then(|| {
+        format!("{LEGACY_KEYS} holds keys of an earlier version, run `keygen --migrate` to import them.")
+    })
+}
+
+/// Load every `client_<id>.key` file in `dir`, ordered by client id.
+pub fn load_client_keys(dir: &str) -> Result<Vec<ClientKeyFile>, KeyError> {
+    let entries = fs::read_dir(dir).map_err(|e| KeyError::Io(PathBuf::from(dir), e))?;
+    let mut key_files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| KeyError::Io(PathBuf::from(dir), e))?
+            .path();
+        let is_key_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("client_") && name.ends_with(".key"));
+        if !is_key_file {
+            continue;
+        }
+        let key_file = read_key_file(&path)?;
+        if path != key_file_path(dir, key_file.client_id) {
+            return Err(KeyError::Invalid(format!(
+                "{} holds the key of client {}",
+                path.display(),
+                key_file.client_id
+            )));
+        }
+        key_files.push(key_file);
+    }
+    key_files.sort_by_key(|key_file| key_file.client_id);
+    Ok(key_files)
+}
+
+/// Write the registry in one step, so an aggregator reloading it never reads half a file.
+pub fn save_registry(path: &str, entries: &[RegistryEntry]) -> Result<(), KeyError> {
+    let path = Path::new(path);
+    let data = serde_json::to_string_pretty(entries)
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e.into()))?;
+    replace_file(path, &data, 0o644)
+}
+
+/// Write `data` to a new file with Unix permissions `mode` next to `path`, flush it to disk
+/// and rename it over `path`. Readers see either the old or the new contents.
+pub(crate) fn replace_file(path: &Path, data: &str, mode: u32) -> Result<(), KeyError> {
+    let io_error = |e| KeyError::Io(path.to_path_buf(), e);
+    let name = path
+        .file_name()
+        .ok_or_else(|| io_error(io::ErrorKind::InvalidInput.into()))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let written = options.open(&temporary).and_then(|mut file| {
+        writeln!(file, "{}", data.trim_end())?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&temporary, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temporary);
+            Err(io_error(e))
+        }
+    }
+}
+
+pub fn load_registry(path: &str) -> Result<Vec<RegistryEntry>, KeyError> {
+    let data = fs::read_to_string(path).map_err(|e| KeyError::Io(PathBuf::from(path), e))?;
+    serde_json::from_str(&data).map_err(|e| KeyError::Invalid(format!("{path}: {e}")))
+}
+
+/// A validated registry entry.
+#[derive(Debug, Clone)]
+pub struct KeyRecord {
+    pub client_id: usize,
+    pub key_id: String,
+    pub name: String,
+    pub key: VerifyingKey,
+    pub enabled: bool,
+    pub added_at: u64,
+    pub validity: Validity,
+}
+
+/// Public keys of the registered clients, looked up by key id. A client may hold several keys
+/// while one is rotated out.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRegistry {
+    by_key_id: HashMap<String, KeyRecord>,
+    /// Key ids of each client, in registry file order.
+    by_client: BTreeMap<usize, Vec<String>>,
+}
+
+impl KeyRegistry {
+    /// Read and validate a registry file.
+    pub fn load(path: &str) -> Result<Self, KeyError> {
+        KeyRegistry::from_entries(load_registry(path)?)
+    }
+
+    /// Build a registry, rejecting undecodable keys, key ids that do not match their key,
+    /// duplicate key ids, empty validity windows and keys of one client whose windows overlap
+    /// other than by a rotation handover.
+    pub fn from_entries(entries: Vec<RegistryEntry>) -> Result<Self, KeyError> {
+        let mut registry = KeyRegistry::default();
+        for entry in entries {
+            let client_id = entry.client_id;
+            if client_id == 0 {
+                return Err(KeyError::Invalid("client id 0 is reserved".to_string()));
+            }
+            let key = entry.verifying_key()?;
+            if key_id(&key) != entry.key_id {
+                return Err(KeyError::Invalid(format!(
+                    "client {client_id}: key id {} does not match its public key",
+                    entry.key_id
+                )));
+            }
+            if let (Some(start), Some(end)) = (entry.validity.not_before, entry.validity.not_after)
+            {
+                if start >= end {
+                    return Err(KeyError::Invalid(format!(
+                        "key {}: not_before must be earlier than not_after",
+                        entry.key_id
+                    )));
+                }
+            }
+            if let Some(other) = registry.by_key_id.get(&entry.key_id) {
+                return Err(KeyError::Invalid(format!(
+                    "key {} is registered for clients {} and {client_id}",
+                    entry.key_id, other.client_id
+                )));
+            }
+            let name = if entry.name.is_empty() {
+                format!("client-{client_id}")
+            } else {
+                entry.name
+            };
+            registry
+                .by_client
+                .entry(client_id)
+                .or_default()
+                .push(entry.key_id.clone());
+            registry.by_key_id.insert(
+                entry.key_id.clone(),
+                KeyRecord {
+                    client_id,
+                    key_id: entry.key_id,
+                    name,
+                    key,
+                    enabled: entry.enabled,
+                    added_at: entry.added_at,
+                    validity: entry.validity,
+                },
+            );
+        }
+        for client_id in registry.by_client.keys() {
+            registry.check_handovers(*client_id)?;
+        }
+        Ok(registry)
+    }
+
+    /// Check that of each two consecutive keys of a client, ordered by start, the older one
+    /// ends, and no later than the newer one. Their windows then overlap only while a
+    /// rotation hands over.
+    fn check_handovers(&self, client_id: usize) -> Result<(), KeyError> {
+        let mut records: Vec<&KeyRecord> = self.keys_of(client_id).collect();
+        records.sort_by_key(|record| record.validity.not_before);
+        for pair in records.windows(2) {
+            let (older, newer) = (pair[0], pair[1]);
+            let handover = match (
+                older.validity.not_after,
+                newer.validity.not_before,
+                newer.validity.not_after,
+            ) {
+                (Some(end), Some(start), newer_end) => {
+                    older.validity.not_before < Some(start) && newer_end.is_none_or(|e| end <= e)
+                }
+                _ => false,
+            };
+            if !handover {
+                return Err(KeyError::Invalid(format!(
+                    "client {client_id}: validity of keys {} and {} overlaps beyond a rotation",
+                    older.key_id, newer.key_id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn by_key_id(&self, key_id: &str) -> Option<&KeyRecord> {
+        self.by_key_id.get(key_id)
+    }
+
+    /// Keys registered for a client.
+    pub fn keys_of(&self, client_id: usize) -> impl Iterator<Item = &KeyRecord> {
+        self.by_client
+            .get(&client_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|key_id| self.by_key_id.get(key_id))
+    }
+
+    /// Records in client id order.
+    pub fn iter(&self) -> impl Iterator<Item = &KeyRecord> {
+        self.by_client
+            .keys()
+            .flat_map(|client_id| self.keys_of(*client_id))
+    }
+
+    /// Number of clients with at least one registered key.
+    pub fn client_count(&self) -> usize {
+        self.by_client.len()
+    }
+
+    /// Number of registered keys.
+    pub fn len(&self) -> usize {
+        self.by_key_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key_id.is_empty()
+    }
+
+    /// Number of clients with at least one enabled key, which is what quorum fractions refer to.
+    pub fn enabled_count(&self) -> usize {
+        self.by_client
+            .keys()
+            .filter(|client_id| self.keys_of(**client_id).any(|record| record.enabled))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(client_id: usize, key: &SigningKey, validity: Validity) -> RegistryEntry {
+        RegistryEntry {
+            client_id,
+            key_id: key_id(&key.verifying_key()),
+            name: String::new(),
+            public_key: general_purpose::STANDARD.encode(key.verifying_key().to_bytes()),
+            enabled: true,
+            added_at: 0,
+            validity,
+        }
+    }
+
+    fn window(not_before: Option<u64>, not_after: Option<u64>) -> Validity {
+        Validity {
+            not_before,
+            not_after,
+        }
+    }
+
+    fn rejection(entries: Vec<RegistryEntry>) -> String {
+        match KeyRegistry::from_entries(entries) {
+            Ok(_) => panic!("registry was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_rotated_client() {
+        let mut key_file = ClientKeyFile::new(1, &SigningKey::generate(&mut OsRng));
+        key_file.rotate(&SigningKey::generate(&mut OsRng), 1_000, 500);
+        key_file.rotate(&SigningKey::generate(&mut OsRng), 1_200, 500);
+        let mut entries = key_file.registry_entries(0).unwrap();
+        entries.push(entry(
+            2,
+            &SigningKey::generate(&mut OsRng),
+            Validity::default(),
+        ));
+        let registry = KeyRegistry::from_entries(entries).unwrap();
+        assert_eq!(registry.len(), 4);
+        assert_eq!(registry.client_count(), 2);
+        assert_eq!(registry.keys_of(1).count(), 3);
+    }
+
+    #[test]
+    fn rejects_duplicate_key_ids() {
+        let key = SigningKey::generate(&mut OsRng);
+        let reason = rejection(vec![
+            entry(1, &key, Validity::default()),
+            entry(2, &key, Validity::default()),
+        ]);
+        assert!(
+            reason.contains("registered for clients 1 and 2"),
+            "{reason}"
+        );
+        let reason = rejection(vec![
+            entry(1, &key, window(None, Some(10))),
+            entry(1, &key, window(Some(5), None)),
+        ]);
+        assert!(
+            reason.contains("registered for clients 1 and 1"),
+            "{reason}"
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_key_ids_and_reserved_client() {
+        let key = SigningKey::generate(&mut OsRng);
+        let mut mismatched = entry(1, &key, Validity::default());
+        mismatched.key_id = "0000000000000000".to_string();
+        assert!(rejection(vec![mismatched]).contains("does not match"));
+        assert!(rejection(vec![entry(0, &key, Validity::default())]).contains("reserved"));
+    }
+
+    #[test]
+    fn rejects_empty_windows() {
+        let key = SigningKey::generate(&mut OsRng);
+        for validity in [window(Some(10), Some(10)), window(Some(20), Some(10))] {
+            assert!(rejection(vec![entry(1, &key, validity)]).contains("not_before"));
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_windows() {
+        let overlapping = [
+            // Two keys without an end.
+            (Validity::default(), Validity::default()),
+            (window(None, None), window(Some(10), None)),
+            // The newer key ends before the older one.
+            (window(Some(0), Some(100)), window(Some(10), Some(50))),
+            // Both start together.
+            (window(Some(10), Some(20)), window(Some(10), None)),
+        ];
+        for (older, newer) in overlapping {
+            let reason = rejection(vec![
+                entry(1, &SigningKey::generate(&mut OsRng), older),
+                entry(1, &SigningKey::generate(&mut OsRng), newer),
+            ]);
+            assert!(reason.contains("overlaps beyond a rotation"), "{reason}");
+        }
+    }
+
+    fn sealed_key(passphrase: &str) -> (SigningKey, ClientKey) {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let mut client_key = ClientKey::new(&key, Validity::default());
+        client_key.encrypt(passphrase).unwrap();
+        (key, client_key)
+    }
+
+    #[test]
+    fn encrypted_secrets_round_trip() {
+        let (key, sealed) = sealed_key("correct horse");
+        assert!(sealed.is_encrypted());
+        // The public key stays readable, and the sealed key survives the key file format.
+        assert_eq!(sealed.verifying_key().unwrap(), key.verifying_key());
+        assert!(sealed.signing_key().is_err());
+        let mut client_key: ClientKey =
+            serde_json::from_str(&serde_json::to_string(&sealed).unwrap()).unwrap();
+
+        client_key.decrypt("correct horse").unwrap();
+        assert!(!client_key.is_encrypted());
+        assert_eq!(client_key.signing_key().unwrap().to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn a_wrong_passphrase_fails_cleanly() {
+        let (_, mut client_key) = sealed_key("correct horse");
+        let error = client_key.decrypt("battery staple").unwrap_err();
+        assert!(error.to_string().contains("wrong passphrase"), "{error}");
+        assert!(client_key.is_encrypted());
+        client_key.decrypt("correct horse").unwrap();
+    }
+
+    #[test]
+    fn a_secret_moved_to_another_key_id_fails_cleanly() {
+        let (_, sealed) = sealed_key("correct horse");
+        let other = SigningKey::from_bytes(&[6; 32]);
+        let mut moved = ClientKey::new(&other, Validity::default());
+        moved.encrypted = sealed.encrypted.clone();
+        moved.private_key = None;
+        let error = moved.decrypt("correct horse").unwrap_err();
+        assert!(
+            matches!(&error, KeyError::Invalid(reason) if reason.contains("wrong passphrase or corrupted key")),
+            "{error}"
+        );
+
+        // Opening the secret itself under another key id fails on the associated data.
+        let secret = sealed.encrypted.unwrap();
+        assert!(secret.open(&moved.key_id, "correct horse").is_err());
+        assert!(secret.open(&sealed.key_id, "correct horse").is_ok());
+    }
+
+    /// Whether `registry` accepts a payload signed with `key` at `timestamp`, as the
+    /// aggregator checks submissions.
+    fn accepts(registry: &KeyRegistry, key: &SigningKey, timestamp: u64) -> bool {
+        use ed25519_dalek::{Signer, Verifier};
+        let payload = format!("ts={timestamp}");
+        let signature = key.sign(payload.as_bytes());
+        registry
+            .by_key_id(&key_id(&key.verifying_key()))
+            .is_some_and(|record| {
+                record.enabled
+                    && record.validity.contains(timestamp)
+                    && record.key.verify(payload.as_bytes(), &signature).is_ok()
+            })
+    }
+
+    #[test]
+    fn rotation_switches_keys_at_not_before() {
+        let old = SigningKey::from_bytes(&[1; 32]);
+        let new = SigningKey::from_bytes(&[2; 32]);
+        let mut key_file = ClientKeyFile::new(1, &old);
+        key_file.rotate(&new, 1_000, 500);
+
+        for (now, expected) in [
+            (0, &old),
+            (999, &old),
+            (1_000, &new),
+            (1_499, &new),
+            (9_999, &new),
+        ] {
+            let (id, key) = key_file.current_key(now).unwrap();
+            assert_eq!(key.to_bytes(), expected.to_bytes(), "at {now}");
+            assert_eq!(id, key_id(&expected.verifying_key()));
+        }
+        // The old key stays valid for the overlap after the new one starts.
+        assert!(key_file.keys[0].validity.contains(1_499));
+        assert!(!key_file.keys[0].validity.contains(1_500));
+
+        // A rotation before the overlap ends retires every older key at the new overlap's end.
+        let newest = SigningKey::from_bytes(&[3; 32]);
+        key_file.rotate(&newest, 1_200, 100);
+        let ends: Vec<Option<u64>> = key_file
+            .keys
+            .iter()
+            .map(|key| key.validity.not_after)
+            .collect();
+        assert_eq!(ends, [Some(1_300), Some(1_300), None]);
+        assert_eq!(
+            key_file.current_key(1_250).unwrap().1.to_bytes(),
+            newest.to_bytes()
+        );
+    }
+
+    #[test]
+    fn registry_accepts_both_keys_during_the_overlap_only() {
+        let old = SigningKey::from_bytes(&[1; 32]);
+        let new = SigningKey::from_bytes(&[2; 32]);
+        let mut key_file = ClientKeyFile::new(1, &old);
+        key_file.rotate(&new, 1_000, 500);
+        let registry = KeyRegistry::from_entries(key_file.registry_entries(0).unwrap()).unwrap();
+
+        assert!(accepts(&registry, &old, 999));
+        assert!(!accepts(&registry, &new, 999));
+        for timestamp in [1_000, 1_499] {
+            assert!(
+                accepts(&registry, &old, timestamp),
+                "old key at {timestamp}"
+            );
+            assert!(
+                accepts(&registry, &new, timestamp),
+                "new key at {timestamp}"
+            );
+        }
+        assert!(!accepts(&registry, &old, 1_500));
+        assert!(accepts(&registry, &new, 1_500));
+        assert!(!accepts(
+            &registry,
+            &SigningKey::from_bytes(&[3; 32]),
+            1_200
+        ));
+    }
+
+    #[test]
+    fn a_client_without_a_valid_key_has_no_current_key() {
+        let mut key_file = ClientKeyFile::new(1, &SigningKey::from_bytes(&[1; 32]));
+        key_file.keys[0].validity = window(Some(1_000), Some(2_000));
+        assert!(key_file.current_key(999).is_err());
+        assert!(key_file.current_key(1_000).is_ok());
+        assert!(key_file.current_key(2_000).is_err());
+    }
+}
@@ -1 +1,14 @@
+pub mod aggregation;
+pub mod capture;
+pub mod config;
+pub mod dkg;
+pub mod federation;
+pub mod feed;
+pub mod frost;
+pub mod key_format;
+pub mod keys;
+pub mod merkle;
+pub mod protocol;
+pub mod signer;
+pub mod tls;
 pub mod utils;
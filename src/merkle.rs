@@ -0,0 +1,259 @@
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 hash.
+pub type Hash = [u8; 32];
+
+/// Leaves and inner nodes are hashed with different prefixes, so an inner node can never be
+/// passed off as a leaf.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Binary Merkle tree over leaf hashes. A node without a sibling is carried up to the next
+/// level unchanged.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    /// `levels[0]` holds the leaves, the last level the root.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks(2) yields one or two nodes"),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    /// The root; the tree of no leaves has the SHA-256 of the empty string as its root.
+    pub fn root(&self) -> Hash {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => Sha256::digest([]).into(),
+        }
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Proof that the leaf at `index` is part of the tree.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(utils::to_hex(sibling));
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            leaf_index: index,
+            leaf_count: self.leaf_count(),
+            siblings,
+        })
+    }
+}
+
+/// The sibling hashes on the path from one leaf to the root, bottom up. Levels where the
+/// path node has no sibling are skipped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    /// Hex-encoded sibling hashes.
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    /// The root reached by hashing `leaf` up along this proof.
+    pub fn root_from(&self, leaf: Hash) -> Result<Hash, String> {
+        if self.leaf_index >= self.leaf_count {
+            return Err(format!(
+                "leaf {} is outside a tree of {} leaves",
+                self.leaf_index, self.leaf_count
+            ));
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = leaf;
+        let mut position = self.leaf_index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            let has_sibling = position % 2 == 1 || position + 1 < width;
+            if has_sibling {
+                let sibling = siblings
+                    .next()
+                    .ok_or("proof is missing sibling hashes")
+                    .and_then(|hex| decode_hash(hex))?;
+                hash = if position % 2 == 1 {
+                    node_hash(&sibling, &hash)
+                } else {
+                    node_hash(&hash, &sibling)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        if siblings.next().is_some() {
+            return Err("proof has more sibling hashes than the tree is deep".to_string());
+        }
+        Ok(hash)
+    }
+
+    /// Whether `leaf` is part of the tree with root `root`.
+    pub fn verify(&self, leaf: Hash, root: &Hash) -> Result<(), String> {
+        if self.root_from(leaf)? != *root {
+            return Err("proof does not lead to the published root".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Decode a hex-encoded hash.
+pub fn decode_hash(hex: &str) -> Result<Hash, &'static str> {
+    utils::from_hex(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("invalid hash, expected 64 hex digits")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<Hash> {
+        (0..count)
+            .map(|i| leaf_hash(format!("submission {i}").as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn single_leaf_is_the_root() {
+        let leaf = leaf_hash(b"only");
+        let tree = MerkleTree::new(vec![leaf]);
+        assert_eq!(tree.root(), leaf);
+        let proof = tree.proof(0).unwrap();
+        assert!(proof.siblings.is_empty());
+        proof.verify(leaf, &tree.root()).unwrap();
+        assert!(tree.proof(1).is_none());
+    }
+
+    #[test]
+    fn empty_tree_root() {
+        let tree = MerkleTree::new(Vec::new());
+        assert_eq!(tree.root(), <Hash>::from(Sha256::digest([])));
+        assert!(tree.proof(0).is_none());
+    }
+
+    #[test]
+    fn odd_trees_carry_the_last_node_up() {
+        let hashes = leaves(3);
+        let root = MerkleTree::new(hashes.clone()).root();
+        assert_eq!(
+            root,
+            node_hash(&node_hash(&hashes[0], &hashes[1]), &hashes[2])
+        );
+    }
+
+    #[test]
+    fn every_index_proves() {
+        for count in 1..=9 {
+            let hashes = leaves(count);
+            let tree = MerkleTree::new(hashes.clone());
+            for (index, leaf) in hashes.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert_eq!(proof.leaf_count, count);
+                proof.verify(*leaf, &tree.root()).unwrap();
+                // A proof is only good for its own leaf.
+                let other = hashes[(index + 1) % count];
+                if count > 1 {
+                    assert!(proof.verify(other, &tree.root()).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        for count in [3, 5, 7] {
+            let hashes = leaves(count);
+            let tree = MerkleTree::new(hashes.clone());
+            let root = tree.root();
+            for (index, leaf) in hashes.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                let mut tampered_leaf = *leaf;
+                tampered_leaf[0] ^= 1;
+                assert!(proof.verify(tampered_leaf, &root).is_err());
+                for sibling in 0..proof.siblings.len() {
+                    let mut tampered = proof.clone();
+                    let mut hash = decode_hash(&tampered.siblings[sibling]).unwrap();
+                    hash[31] ^= 1;
+                    tampered.siblings[sibling] = utils::to_hex(&hash);
+                    assert!(tampered.verify(*leaf, &root).is_err());
+                }
+                let mut moved = proof.clone();
+                moved.leaf_index = (index + 1) % count;
+                assert!(moved.verify(*leaf, &root).is_err());
+                let mut short = proof.clone();
+                short.siblings.pop();
+                assert!(short.verify(*leaf, &root).is_err());
+                let mut long = proof.clone();
+                long.siblings.push(utils::to_hex(leaf));
+                assert!(long.root_from(*leaf).is_err());
+                let mut outside = proof.clone();
+                outside.leaf_index = count;
+                assert!(outside.root_from(*leaf).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_and_nodes_hash_apart() {
+        let hashes = leaves(4);
+        let tree = MerkleTree::new(hashes.clone());
+        let left = node_hash(&hashes[0], &hashes[1]);
+        let right = node_hash(&hashes[2], &hashes[3]);
+        // Hashed up from an inner node, a proof for a two-leaf tree reaches the root. The leaf
+        // prefix keeps any submission from hashing to that node, even one made of its children.
+        let mut children = Vec::new();
+        children.extend_from_slice(&hashes[0]);
+        children.extend_from_slice(&hashes[1]);
+        assert_ne!(leaf_hash(&children), left);
+        let forged = MerkleProof {
+            leaf_index: 0,
+            leaf_count: 2,
+            siblings: vec![utils::to_hex(&right)],
+        };
+        assert_eq!(forged.root_from(left).unwrap(), tree.root());
+        assert!(forged.verify(leaf_hash(&children), &tree.root()).is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use crate::frost::NonceCommitment;
+use crate::merkle::{self, MerkleProof};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Version of the client/aggregator wire protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 13;
+
+/// Characters that separate the fields of signing payloads, which the signed text fields of a
+/// submission must not contain for its payload to have a single reading.
+pub const PAYLOAD_DELIMITERS: [char; 2] = ['|', '='];
+
+/// A message exchanged between clients and the aggregator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WireMessage {
+    /// A client's signed average.
+    Submit(Submission),
+    /// Ask the aggregator to reply with the global result of the symbol's current round.
+    Subscribe { duration: u64, symbol: String },
+    /// Ask the aggregator which round submissions for a symbol should be signed for.
+    CurrentRound { symbol: String },
+    /// Reply to [`WireMessage::CurrentRound`].
+    RoundInfo {
+        symbol: String,
+        round_id: u64,
+        state: RoundState,
+    },
+    /// The aggregated result of a round.
+    GlobalResult(GlobalResult),
+    /// Ask for proof that the client's accepted submission is part of a round's result. The
+    /// aggregator replies once the round is published.
+    ProveInclusion {
+        symbol: String,
+        round_id: u64,
+        client_id: usize,
+    },
+    /// Reply to [`WireMessage::ProveInclusion`]: the signed result and the Merkle path from
+    /// the client's submission to its `merkle_root`.
+    InclusionProof {
+        client_id: usize,
+        result: GlobalResult,
+        proof: MerkleProof,
+    },
+    /// First co-signing round: commit to the nonces the client will sign the result of a round
+    /// it contributed to with, before the result is known.
+    CosignCommit {
+        symbol: String,
+        round_id: u64,
+        client_id: usize,
+        commitment: NonceCommitment,
+    },
+    /// Ask for the signing package of a round the client committed to. The aggregator replies
+    /// once the round is published.
+    CosignRequest {
+        symbol: String,
+        round_id: u64,
+        client_id: usize,
+    },
+    /// Reply to [`WireMessage::CosignRequest`]: the result to co-sign and the commitments of
+    /// every signer as `(client_id, commitment)` pairs, since tagged messages cannot carry
+    /// maps keyed by numbers.
+    CosignPackage {
+        result: GlobalResult,
+        commitments: Vec<(usize, NonceCommitment)>,
+    },
+    /// Second co-signing round: the client's signature share of the result.
+    CosignShare {
+        symbol: String,
+        round_id: u64,
+        client_id: usize,
+        share: String,
+    },
+    /// A federated aggregator's signed result of one of its rounds, pushed to its peers when
+    /// published. A peer that has published the same round replies with its own.
+    RoundSummary(GlobalResult),
+    /// The previous message could not be handled.
+    Error { code: ErrorCode, detail: String },
+    /// The previous submission was verified and admitted.
+    Ack { client_id: usize },
+}
+
+/// A signed value submitted by a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submission {
+    pub client_id: usize,
+    /// Id of the registered key the submission is signed with.
+    pub key_id: String,
+    /// Trading pair the value was computed for, e.g. `BTCUSDT`.
+    pub symbol: String,
+    /// Round the value is submitted for, as announced by the aggregator.
+    pub round_id: u64,
+    /// Milliseconds since the Unix epoch at signing time.
+    pub timestamp: u64,
+    /// Random value that makes every signed payload unique.
+    pub nonce: u64,
+    pub message: String,
+    /// Total quantity traded over the window the value was computed from.
+    pub volume: f64,
+    /// Base64-encoded Ed25519 signature over [`Submission::signing_payload`].
+    pub signature: String,
+}
+
+impl Submission {
+    /// Canonical encoding of every signed field; the signature itself is excluded.
+    pub fn signing_payload(&self) -> String {
+        format!(
+            "v{}|client={}|key={}|symbol={}|round={}|ts={}|nonce={}|value={}|volume={}",
+            PROTOCOL_VERSION,
+            self.client_id,
+            self.key_id,
+            self.symbol,
+            self.round_id,
+            self.timestamp,
+            self.nonce,
+            self.message,
+            self.volume
+        )
+    }
+
+    /// Check that no signed text field contains one of the [`PAYLOAD_DELIMITERS`].
+    pub fn check_fields(&self) -> Result<(), String> {
+        for (name, field) in [
+            ("key_id", &self.key_id),
+            ("symbol", &self.symbol),
+            ("message", &self.message),
+        ] {
+            if field.contains(PAYLOAD_DELIMITERS) {
+                return Err(format!("{name} contains '|' or '='"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merkle leaf of the signed payload and its signature, identifying exactly what the
+    /// aggregator accepted.
+    pub fn leaf_hash(&self) -> merkle::Hash {
+        merkle::leaf_hash(format!("{}|sig={}", self.signing_payload(), self.signature).as_bytes())
+    }
+}
+
+/// Lifecycle of an aggregation round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundState {
+    /// Waiting for the first submission.
+    Open,
+    /// Accepting submissions until the quorum or the deadline is reached.
+    Collecting,
+    /// No longer accepting submissions; the result is being computed.
+    Closed,
+    /// The result has been published to receivers.
+    Published,
+}
+
+/// Global average published to receivers, signed by the aggregator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalResult {
+    pub symbol: String,
+    pub round_id: u64,
+    /// `None` when no submission was admitted this round, or when the result was
+    /// withheld for missing the quorum.
+    pub average: Option<f64>,
+    /// Number of accepted submissions the average was computed from.
+    pub contributors: usize,
+    /// Contributors required for a full result.
+    pub quorum: usize,
+    /// `false` marks a degraded result computed from fewer clients than the quorum.
+    pub quorum_met: bool,
+    /// Aggregation strategy the average was computed with.
+    pub method: String,
+    /// Accepted submissions the strategy left out as outliers.
+    pub excluded: Vec<ExcludedSubmission>,
+    /// Submissions this aggregator rejected. Advisory only: the counts are not signed, since
+    /// federation members may each see different bad submissions, and clients ignore them.
+    pub rejected: RejectionCounts,
+    /// Clients whose submissions were accepted this round, in ascending order.
+    pub contributor_ids: Vec<usize>,
+    /// Hex root of the Merkle tree over the [`Submission::leaf_hash`]es of the accepted
+    /// submissions, in ascending client id order.
+    pub merkle_root: String,
+    /// Key id of the aggregator key the result is signed with.
+    pub aggregator_key: String,
+    /// Base64-encoded Ed25519 signature over [`GlobalResult::signing_payload`].
+    pub signature: String,
+    /// Base64-encoded threshold signature of the client group over the same payload, when
+    /// enough contributors co-signed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_signature: Option<String>,
+    /// Signatures of the federated aggregators that published the same result.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endorsements: Vec<Endorsement>,
+}
+
+/// Another aggregator's signature over [`GlobalResult::signing_payload_for`] its own key id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endorsement {
+    pub aggregator_key: String,
+    pub signature: String,
+}
+
+impl GlobalResult {
+    /// Canonical encoding of the attested fields; the signature itself and the advisory
+    /// `rejected` counts are not part of it.
+    pub fn signing_payload(&self) -> String {
+        self.signing_payload_for(&self.aggregator_key)
+    }
+
+    /// The signing payload of the same result published by the aggregator with key id
+    /// `aggregator_key`. Two aggregators agree on a round when these match.
+    pub fn signing_payload_for(&self, aggregator_key: &str) -> String {
+        let value = self
+            .average
+            .map_or_else(|| "none".to_string(), |average| average.to_string());
+        let contributors: Vec<String> = self.contributor_ids.iter().map(usize::to_string).collect();
+        let mut excluded: Vec<&ExcludedSubmission> = self.excluded.iter().collect();
+        excluded.sort_by(|a, b| {
+            a.client_id
+                .cmp(&b.client_id)
+                .then(a.value.total_cmp(&b.value))
+        });
+        let excluded: Vec<String> = excluded
+            .iter()
+            .map(|e| format!("{}:{}", e.client_id, e.value))
+            .collect();
+        format!(
+            "v{}|result|symbol={}|round={}|value={}|method={}|quorum={}|quorum_met={}|contributors={}|excluded={}|merkle_root={}|aggregator={}",
+            PROTOCOL_VERSION,
+            self.symbol,
+            self.round_id,
+            value,
+            self.method,
+            self.quorum,
+            self.quorum_met,
+            contributors.join(","),
+            excluded.join(","),
+            self.merkle_root,
+            aggregator_key
+        )
+    }
+
+    /// Whether `other` attests to the same result, whoever published it.
+    pub fn agrees_with(&self, other: &GlobalResult) -> bool {
+        self.signing_payload_for("") == other.signing_payload_for("")
+    }
+}
+
+/// An accepted submission left out of the global value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExcludedSubmission {
+    pub client_id: usize,
+    pub value: f64,
+}
+
+/// Number of submissions rejected in a round, grouped by reason.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RejectionCounts {
+    pub bad_signature: usize,
+    pub unknown_client: usize,
+    pub malformed: usize,
+    pub stale: usize,
+    pub replayed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    Malformed,
+    Rejected,
+    UnexpectedMessage,
+    /// The round was not published before the subscriber's timeout.
+    Timeout,
+    /// The aggregator does not run rounds for the requested symbol.
+    UnknownSymbol,
+    /// The round is no longer kept, or the client has no accepted submission in it.
+    NotFound,
+}
+
+/// Every message on the wire is wrapped with the protocol version.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    #[serde(flatten)]
+    message: WireMessage,
+}
+
+/// Reasons a text frame could not be decoded into a [`WireMessage`].
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnsupportedVersion(u32),
+    Malformed(String),
+}
+
+impl WireMessage {
+    /// Serialize the message with the current protocol version.
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(&Envelope {
+            version: PROTOCOL_VERSION,
+            message: self.clone(),
+        })
+        .expect("Wire messages always serialize")
+    }
+
+    /// Build the error reply for a frame that failed to decode.
+    pub fn from_error(err: &ProtocolError) -> Self {
+        let code = match err {
+            ProtocolError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            ProtocolError::Malformed(_) => ErrorCode::Malformed,
+        };
+        WireMessage::Error {
+            code,
+            detail: err.to_string(),
+        }
+    }
+}
+
+/// Decode a text frame, checking the protocol version before the payload.
+pub fn decode(text: &str) -> Result<WireMessage, ProtocolError> {
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| ProtocolError::Malformed("missing protocol version".to_string()))?;
+    if version != u64::from(PROTOCOL_VERSION) {
+        return Err(ProtocolError::UnsupportedVersion(
+            u32::try_from(version).unwrap_or(u32::MAX),
+        ));
+    }
+    serde_json::from_value::<Envelope>(value)
+        .map(|envelope| envelope.message)
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version}, expected {PROTOCOL_VERSION}"
+            ),
+            ProtocolError::Malformed(reason) => write!(f, "malformed message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl fmt::Display for RejectionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rejected: {} bad signature, {} unknown client, {} malformed, {} stale, {} replayed",
+            self.bad_signature, self.unknown_client, self.malformed, self.stale, self.replayed
+        )
+    }
+}
+
+/// The alternate form, `{:#}`, leaves out the unsigned rejection counts.
+impl fmt::Display for GlobalResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quorum = if self.quorum_met {
+            "quorum met"
+        } else {
+            "degraded: quorum not met"
+        };
+        let rejected = if f.alternate() {
+            String::new()
+        } else {
+            format!("; {}", self.rejected)
+        };
+        match self.average {
+            Some(average) => write!(
+                f,
+                "{} round {}: Global {} price: {:.4} ({} of {} contributors, {}, {} excluded{})",
+                self.symbol,
+                self.round_id,
+                self.method,
+                average,
+                self.contributors,
+                self.quorum,
+                quorum,
+                self.excluded.len(),
+                rejected
+            ),
+            None if self.contributors > 0 => write!(
+                f,
+                "{} round {}: Result withheld, quorum not met ({} of {} contributors{})",
+                self.symbol, self.round_id, self.contributors, self.quorum, rejected
+            ),
+            None if f.alternate() => write!(
+                f,
+                "{} round {}: No valid averages received",
+                self.symbol, self.round_id
+            ),
+            None => write!(
+                f,
+                "{} round {}: No valid averages received ({})",
+                self.symbol, self.round_id, self.rejected
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> GlobalResult {
+        GlobalResult {
+            symbol: "BTCUSDT".to_string(),
+            round_id: 3,
+            average: Some(96651.7868),
+            contributors: 2,
+            quorum: 2,
+            quorum_met: true,
+            method: "mean".to_string(),
+            excluded: vec![ExcludedSubmission {
+                client_id: 3,
+                value: 1.5,
+            }],
+            rejected: RejectionCounts {
+                stale: 1,
+                ..RejectionCounts::default()
+            },
+            contributor_ids: vec![1, 2],
+            merkle_root: "780f0704".to_string(),
+            aggregator_key: "7a1c5988d33b3afb".to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+            group_signature: Some("Z3JvdXA=".to_string()),
+            endorsements: vec![Endorsement {
+                aggregator_key: "0b9d0a1e2f3c4d5e".to_string(),
+                signature: "ZW5kb3JzZWQ=".to_string(),
+            }],
+        }
+    }
+
+    fn commitment() -> NonceCommitment {
+        NonceCommitment {
+            hiding: "aGlkaW5n".to_string(),
+            binding: "YmluZGluZw==".to_string(),
+        }
+    }
+
+    /// One message of every kind, with its position in [`kind`]'s order.
+    fn messages() -> Vec<WireMessage> {
+        let symbol = "BTCUSDT".to_string();
+        vec![
+            WireMessage::Submit(Submission {
+                client_id: 1,
+                key_id: "92b8e7fb22295bfd".to_string(),
+                symbol: symbol.clone(),
+                round_id: 3,
+                timestamp: 1_734_000_000_000,
+                nonce: 912_873,
+                message: "96650.2884".to_string(),
+                volume: 12.5,
+                signature: "c2lnbmF0dXJl".to_string(),
+            }),
+            WireMessage::Subscribe {
+                duration: 30,
+                symbol: symbol.clone(),
+            },
+            WireMessage::CurrentRound {
+                symbol: symbol.clone(),
+            },
+            WireMessage::RoundInfo {
+                symbol: symbol.clone(),
+                round_id: 3,
+                state: RoundState::Collecting,
+            },
+            WireMessage::GlobalResult(result()),
+            WireMessage::ProveInclusion {
+                symbol: symbol.clone(),
+                round_id: 3,
+                client_id: 1,
+            },
+            WireMessage::InclusionProof {
+                client_id: 1,
+                result: result(),
+                proof: MerkleProof {
+                    leaf_index: 0,
+                    leaf_count: 2,
+                    siblings: vec!["ab".repeat(32)],
+                },
+            },
+            WireMessage::CosignCommit {
+                symbol: symbol.clone(),
+                round_id: 3,
+                client_id: 1,
+                commitment: commitment(),
+            },
+            WireMessage::CosignRequest {
+                symbol: symbol.clone(),
+                round_id: 3,
+                client_id: 1,
+            },
+            WireMessage::CosignPackage {
+                result: result(),
+                commitments: vec![(1, commitment()), (2, commitment())],
+            },
+            WireMessage::CosignShare {
+                symbol,
+                round_id: 3,
+                client_id: 1,
+                share: "c2hhcmU=".to_string(),
+            },
+            WireMessage::RoundSummary(result()),
+            WireMessage::Error {
+                code: ErrorCode::NotFound,
+                detail: "round 2 is no longer kept".to_string(),
+            },
+            WireMessage::Ack { client_id: 1 },
+        ]
+    }
+
+    /// Position of the message's kind in [`messages`]; a new kind fails to compile here.
+    fn kind(message: &WireMessage) -> usize {
+        match message {
+            WireMessage::Submit(_) => 0,
+            WireMessage::Subscribe { .. } => 1,
+            WireMessage::CurrentRound { .. } => 2,
+            WireMessage::RoundInfo { .. } => 3,
+            WireMessage::GlobalResult(_) => 4,
+            WireMessage::ProveInclusion { .. } => 5,
+            WireMessage::InclusionProof { .. } => 6,
+            WireMessage::CosignCommit { .. } => 7,
+            WireMessage::CosignRequest { .. } => 8,
+            WireMessage::CosignPackage { .. } => 9,
+            WireMessage::CosignShare { .. } => 10,
+            WireMessage::RoundSummary(_) => 11,
+            WireMessage::Error { .. } => 12,
+            WireMessage::Ack { .. } => 13,
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = messages();
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(kind(message), i);
+            let text = message.to_text();
+            let value: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(value["version"], PROTOCOL_VERSION);
+            let decoded = decode(&text).unwrap_or_else(|e| panic!("{text}: {e}"));
+            assert_eq!(kind(&decoded), i);
+            assert_eq!(decoded.to_text(), text);
+        }
+        assert_eq!(messages.len(), kind(&WireMessage::Ack { client_id: 0 }) + 1);
+    }
+
+    #[test]
+    fn rejects_missing_and_unsupported_versions() {
+        let text = WireMessage::Ack { client_id: 1 }.to_text();
+        let mut value: Value = serde_json::from_str(&text).unwrap();
+
+        value["version"] = Value::from(PROTOCOL_VERSION + 1);
+        let error = decode(&value.to_string()).unwrap_err();
+        assert!(
+            matches!(error, ProtocolError::UnsupportedVersion(v) if v == PROTOCOL_VERSION + 1),
+            "{error}"
+        );
+        assert!(matches!(
+            WireMessage::from_error(&error),
+            WireMessage::Error {
+                code: ErrorCode::UnsupportedVersion,
+                ..
+            }
+        ));
+        value["version"] = Value::from(u64::MAX);
+        assert!(matches!(
+            decode(&value.to_string()),
+            Err(ProtocolError::UnsupportedVersion(u32::MAX))
+        ));
+
+        for version in [Value::Null, Value::from("12"), Value::from(-1)] {
+            value["version"] = version;
+            assert!(matches!(
+                decode(&value.to_string()),
+                Err(ProtocolError::Malformed(_))
+            ));
+        }
+        value.as_object_mut().unwrap().remove("version");
+        let error = decode(&value.to_string()).unwrap_err();
+        assert!(
+            error.to_string().contains("missing protocol version"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        let version = PROTOCOL_VERSION;
+        for text in [
+            "".to_string(),
+            "not json".to_string(),
+            "{\"version\":".to_string(),
+            "[1, 2, 3]".to_string(),
+            format!("{{\"version\":{version}}}"),
+            format!("{{\"version\":{version},\"type\":\"unknown\"}}"),
+            format!("{{\"version\":{version},\"type\":\"ack\"}}"),
+            format!("{{\"version\":{version},\"type\":\"ack\",\"client_id\":\"one\"}}"),
+            format!("{{\"version\":{version},\"type\":\"submit\",\"client_id\":1}}"),
+        ] {
+            let error = decode(&text).unwrap_err();
+            assert!(
+                matches!(error, ProtocolError::Malformed(_)),
+                "{text:?}: {error}"
+            );
+            assert!(matches!(
+                WireMessage::from_error(&error),
+                WireMessage::Error {
+                    code: ErrorCode::Malformed,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn excluded_submissions_are_signed_in_any_order() {
+        let mut ours = result();
+        ours.excluded = vec![
+            ExcludedSubmission {
+                client_id: 7,
+                value: 2.0,
+            },
+            ExcludedSubmission {
+                client_id: 3,
+                value: 1.5,
+            },
+        ];
+        let mut theirs = ours.clone();
+        theirs.excluded.reverse();
+        assert!(ours.signing_payload().contains("|excluded=3:1.5,7:2|"));
+        assert_eq!(ours.signing_payload(), theirs.signing_payload());
+
+        theirs.excluded[0].value = 2.5;
+        assert!(!ours.agrees_with(&theirs));
+        theirs.excluded.pop();
+        assert!(!ours.agrees_with(&theirs));
+    }
+
+    #[test]
+    fn rejection_counts_are_advisory() {
+        let ours = result();
+        let mut theirs = ours.clone();
+        theirs.rejected.bad_signature += 3;
+        assert_eq!(ours.signing_payload(), theirs.signing_payload());
+        assert!(ours.agrees_with(&theirs));
+
+        assert!(ours.to_string().contains("rejected: "));
+        assert!(!format!("{ours:#}").contains("rejected"));
+    }
+}
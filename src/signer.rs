@@ -0,0 +1,422 @@
+use crate::keys::{self, ClientKeyFile, KeyError};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::Signature;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Default path of the signing agent's socket.
+pub const DEFAULT_AGENT_SOCKET: &str = "mcs-agent.sock";
+
+/// Signs submissions on behalf of clients. The client only sees key ids and signatures, so
+/// the secret keys may live in this process or behind a signing agent.
+pub trait Signer: Send + Sync {
+    /// Ids of the clients this signer holds keys for.
+    fn client_ids(&self) -> BoxFuture<'_, Result<Vec<usize>, SignerError>>;
+
+    /// Id of the key `client_id` signs with at `now`, to be named in the signed payload.
+    fn current_key_id(
+        &self,
+        client_id: usize,
+        now: u64,
+    ) -> BoxFuture<'_, Result<String, SignerError>>;
+
+    /// Sign `payload` with key `key_id` of `client_id`.
+    fn sign<'a>(
+        &'a self,
+        client_id: usize,
+        key_id: &'a str,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<Signature, SignerError>>;
+}
+
+#[derive(Debug)]
+pub enum SignerError {
+    UnknownClient(usize),
+    Key(KeyError),
+    /// The agent could not be reached, or answered with an error or nonsense.
+    Agent(String),
+}
+
+impl fmt::Display for SignerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignerError::UnknownClient(id) => write!(f, "no keys for client {id}"),
+            SignerError::Key(e) => write!(f, "{e}"),
+            SignerError::Agent(reason) => write!(f, "signing agent: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SignerError {}
+
+impl From<KeyError> for SignerError {
+    fn from(e: KeyError) -> Self {
+        SignerError::Key(e)
+    }
+}
+
+/// Signs with unlocked key files held in this process.
+#[derive(Debug)]
+pub struct LocalSigner {
+    key_files: BTreeMap<usize, ClientKeyFile>,
+}
+
+impl LocalSigner {
+    /// The key files must already be unlocked.
+    pub fn new(key_files: Vec<ClientKeyFile>) -> Self {
+        LocalSigner {
+            key_files: key_files
+                .into_iter()
+                .map(|key_file| (key_file.client_id, key_file))
+                .collect(),
+        }
+    }
+
+    /// Load every client's key file from `key_dir`, decrypting encrypted keys with the
+    /// passphrase from [`crate::keys::read_passphrase`].
+    pub fn load(key_dir: &str) -> Result<Self, KeyError> {
+        let mut key_files = keys::load_client_keys(key_dir)?;
+        if key_files.iter().any(ClientKeyFile::is_encrypted) {
+            let passphrase = keys::read_passphrase(false)?;
+            for key_file in &mut key_files {
+                key_file.unlock(&passphrase)?;
+            }
+        }
+        Ok(LocalSigner::new(key_files))
+    }
+
+    fn key_file(&self, client_id: usize) -> Result<&ClientKeyFile, SignerError> {
+        self.key_files
+            .get(&client_id)
+            .ok_or(SignerError::UnknownClient(client_id))
+    }
+
+    fn sign_now(
+        &self,
+        client_id: usize,
+        key_id: &str,
+        payload: &[u8],
+    ) -> Result<Signature, SignerError> {
+        use ed25519_dalek::Signer as _;
+        let now = crate::utils::unix_millis();
+        let key = self
+            .key_file(client_id)?
+            .keys
+            .iter()
+            .find(|key| key.key_id == key_id)
+            .ok_or_else(|| KeyError::Invalid(format!("client {client_id} has no key {key_id}")))?;
+        if !key.validity.contains(now) {
+            return Err(KeyError::Invalid(format!("key {key_id} is not valid now")).into());
+        }
+        Ok(key.signing_key()?.sign(payload))
+    }
+
+    /// Answer one request of the agent protocol.
+    pub fn handle(&self, request: AgentRequest) -> AgentResponse {
+        let result = match request {
+            AgentRequest::Clients => Ok(AgentResponse::Clients {
+                client_ids: self.key_files.keys().copied().collect(),
+            }),
+            AgentRequest::CurrentKey { client_id, now } => self
+                .key_file(client_id)
+                .and_then(|key_file| Ok(key_file.current_key(now)?))
+                .map(|(key_id, _)| AgentResponse::Key { key_id }),
+            AgentRequest::Sign {
+                client_id,
+                key_id,
+                payload,
+            } => general_purpose::STANDARD
+                .decode(payload)
+                .map_err(|e| SignerError::Agent(format!("invalid payload: {e}")))
+                .and_then(|payload| self.sign_now(client_id, &key_id, &payload))
+                .map(|signature| AgentResponse::Signature {
+                    signature: general_purpose::STANDARD.encode(signature.to_bytes()),
+                }),
+        };
+        result.unwrap_or_else(|e| AgentResponse::Error {
+            detail: e.to_string(),
+        })
+    }
+}
+
+impl Signer for LocalSigner {
+    fn client_ids(&self) -> BoxFuture<'_, Result<Vec<usize>, SignerError>> {
+        Box::pin(async move { Ok(self.key_files.keys().copied().collect()) })
+    }
+
+    fn current_key_id(
+        &self,
+        client_id: usize,
+        now: u64,
+    ) -> BoxFuture<'_, Result<String, SignerError>> {
+        Box::pin(async move { Ok(self.key_file(client_id)?.current_key(now)?.0) })
+    }
+
+    fn sign<'a>(
+        &'a self,
+        client_id: usize,
+        key_id: &'a str,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<Signature, SignerError>> {
+        Box::pin(async move { self.sign_now(client_id, key_id, payload) })
+    }
+}
+
+/// A request to the signing agent, sent as one JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AgentRequest {
+    Clients,
+    CurrentKey {
+        client_id: usize,
+        now: u64,
+    },
+    Sign {
+        client_id: usize,
+        key_id: String,
+        /// Base64 of the bytes to sign.
+        payload: String,
+    },
+}
+
+/// The agent's answer to one request, sent as one JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AgentResponse {
+    Clients { client_ids: Vec<usize> },
+    Key { key_id: String },
+    Signature { signature: String },
+    Error { detail: String },
+}
+
+/// Signs by asking a signing agent over its Unix socket, so the secret keys never enter this
+/// process. Each request uses a fresh connection, so the agent may restart between rounds.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct AgentSigner {
+    socket: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl AgentSigner {
+    pub fn new(socket: impl Into<std::path::PathBuf>) -> Self {
+        AgentSigner {
+            socket: socket.into(),
+        }
+    }
+
+    async fn request(&self, request: &AgentRequest) -> Result<AgentResponse, SignerError> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let agent_error =
+            |e: std::io::Error| SignerError::Agent(format!("{}: {e}", self.socket.display()));
+        let mut stream = tokio::net::UnixStream::connect(&self.socket)
+            .await
+            .map_err(agent_error)?;
+        let mut line =
+            serde_json::to_string(request).map_err(|e| SignerError::Agent(e.to_string()))?;
+        line.push('\n');
+        stream
+            .write_all(line.as_bytes())
+            .await
+            .map_err(agent_error)?;
+        let mut reply = String::new();
+        BufReader::new(stream)
+            .read_line(&mut reply)
+            .await
+            .map_err(agent_error)?;
+        match serde_json::from_str(&reply) {
+            Ok(AgentResponse::Error { detail }) => Err(SignerError::Agent(detail)),
+            Ok(response) => Ok(response),
+            Err(e) => Err(SignerError::Agent(format!("malformed reply: {e}"))),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unexpected(response: AgentResponse) -> SignerError {
+    SignerError::Agent(format!("unexpected reply {response:?}"))
+}
+
+#[cfg(unix)]
+impl Signer for AgentSigner {
+    fn client_ids(&self) -> BoxFuture<'_, Result<Vec<usize>, SignerError>> {
+        Box::pin(async move {
+            match self.request(&AgentRequest::Clients).await? {
+                AgentResponse::Clients { client_ids } => Ok(client_ids),
+                other => Err(unexpected(other)),
+            }
+        })
+    }
+
+    fn current_key_id(
+        &self,
+        client_id: usize,
+        now: u64,
+    ) -> BoxFuture<'_, Result<String, SignerError>> {
+        Box::pin(async move {
+            match self
+                .request(&AgentRequest::CurrentKey { client_id, now })
+                .await?
+            {
+                AgentResponse::Key { key_id } => Ok(key_id),
+                other => Err(unexpected(other)),
+            }
+        })
+    }
+
+    fn sign<'a>(
+        &'a self,
+        client_id: usize,
+        key_id: &'a str,
+        payload: &'a [u8],
+    ) -> BoxFuture<'a, Result<Signature, SignerError>> {
+        Box::pin(async move {
+            let request = AgentRequest::Sign {
+                client_id,
+                key_id: key_id.to_string(),
+                payload: general_purpose::STANDARD.encode(payload),
+            };
+            let signature = match self.request(&request).await? {
+                AgentResponse::Signature { signature } => signature,
+                other => return Err(unexpected(other)),
+            };
+            let bytes: [u8; 64] = general_purpose::STANDARD
+                .decode(signature)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| SignerError::Agent("invalid signature".to_string()))?;
+            Ok(Signature::from_bytes(&bytes))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{SigningKey, Verifier};
+
+    fn client_key() -> SigningKey {
+        SigningKey::from_bytes(&[4; 32])
+    }
+
+    fn local_signer() -> LocalSigner {
+        LocalSigner::new(vec![ClientKeyFile::new(3, &client_key())])
+    }
+
+    #[test]
+    fn handle_signs_with_the_requested_key() {
+        let signer = local_signer();
+        let key_id = keys::key_id(&client_key().verifying_key());
+        let AgentResponse::Clients { client_ids } = signer.handle(AgentRequest::Clients) else {
+            panic!("no client list");
+        };
+        assert_eq!(client_ids, [3]);
+
+        let response = signer.handle(AgentRequest::Sign {
+            client_id: 3,
+            key_id,
+            payload: general_purpose::STANDARD.encode(b"v13|client=3"),
+        });
+        let AgentResponse::Signature { signature } = response else {
+            panic!("no signature: {response:?}");
+        };
+        let bytes: [u8; 64] = general_purpose::STANDARD
+            .decode(signature)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        client_key()
+            .verifying_key()
+            .verify(b"v13|client=3", &Signature::from_bytes(&bytes))
+            .unwrap();
+    }
+
+    #[test]
+    fn handle_answers_unknown_keys_and_clients_with_errors() {
+        let signer = local_signer();
+        let key_id = keys::key_id(&client_key().verifying_key());
+        let payload = general_purpose::STANDARD.encode(b"payload");
+        for (client_id, key_id, payload, reason) in [
+            (
+                3,
+                "0011223344556677",
+                payload.as_str(),
+                "has no key 0011223344556677",
+            ),
+            (4, key_id.as_str(), payload.as_str(), "no keys for client 4"),
+            (3, key_id.as_str(), "not base64!", "invalid payload"),
+        ] {
+            let response = signer.handle(AgentRequest::Sign {
+                client_id,
+                key_id: key_id.to_string(),
+                payload: payload.to_string(),
+            });
+            assert!(
+                matches!(&response, AgentResponse::Error { detail } if detail.contains(reason)),
+                "{response:?}"
+            );
+        }
+        let response = signer.handle(AgentRequest::CurrentKey {
+            client_id: 4,
+            now: 0,
+        });
+        assert!(
+            matches!(response, AgentResponse::Error { .. }),
+            "{response:?}"
+        );
+    }
+
+    /// An agent answering one request per connection on `socket` with `signer`.
+    #[cfg(unix)]
+    fn spawn_agent(socket: &std::path::Path, signer: LocalSigner) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let listener = tokio::net::UnixListener::bind(socket).unwrap();
+        let signer = std::sync::Arc::new(signer);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut line = String::new();
+                BufReader::new(read).read_line(&mut line).await.unwrap();
+                let response = signer.handle(serde_json::from_str(&line).unwrap());
+                let reply = serde_json::to_string(&response).unwrap() + "\n";
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn agent_signer_signs_through_the_socket() {
+        let dir = std::env::temp_dir().join(format!("mcs-agent-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("agent.sock");
+        let _ = std::fs::remove_file(&socket);
+        spawn_agent(&socket, local_signer());
+
+        let agent = AgentSigner::new(&socket);
+        assert_eq!(agent.client_ids().await.unwrap(), [3]);
+        let key_id = agent
+            .current_key_id(3, crate::utils::unix_millis())
+            .await
+            .unwrap();
+        assert_eq!(key_id, keys::key_id(&client_key().verifying_key()));
+        let signature = agent.sign(3, &key_id, b"payload").await.unwrap();
+        client_key()
+            .verifying_key()
+            .verify(b"payload", &signature)
+            .unwrap();
+
+        let error = agent.sign(4, &key_id, b"payload").await.unwrap_err();
+        assert!(
+            matches!(&error, SignerError::Agent(detail) if detail.contains("client 4")),
+            "{error}"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // With the socket gone, requests fail instead of hanging.
+        let error = agent.client_ids().await.unwrap_err();
+        assert!(matches!(error, SignerError::Agent(_)), "{error}");
+    }
+}
@@ -0,0 +1,131 @@
+use native_tls::{Certificate, Identity, TlsConnector};
+use std::fs;
+use std::path::Path;
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::{
+    connect_async, connect_async_tls_with_config, tungstenite, Connector, MaybeTlsStream,
+    WebSocketStream,
+};
+
+/// A WebSocket connection to an aggregator, over TLS for `wss://` URLs.
+pub type AggregatorStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// TLS acceptor for serving `wss://` with the PEM certificate chain in `cert` and its PEM
+/// PKCS#8 private key in `key`.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("{}: {e}", path.display()));
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(|e| {
+        format!(
+            "{} and {}: {e} (expected a PEM certificate and a PEM PKCS#8 private key)",
+            cert.display(),
+            key.display()
+        )
+    })?;
+    native_tls::TlsAcceptor::new(identity)
+        .map(TlsAcceptor::from)
+        .map_err(|e| e.to_string())
+}
+
+/// TLS connector that trusts only the PEM certificates in `ca`, for aggregators whose
+/// certificates are issued by a private CA.
+pub fn connector(ca: &Path) -> Result<TlsConnector, String> {
+    let data = fs::read(ca).map_err(|e| format!("{}: {e}", ca.display()))?;
+    let mut builder = TlsConnector::builder();
+    builder.disable_built_in_roots(true);
+    let mut found = false;
+    for block in pem_blocks(&data, "CERTIFICATE") {
+        let certificate = Certificate::from_pem(block.as_bytes())
+            .map_err(|e| format!("{}: {e}", ca.display()))?;
+        builder.add_root_certificate(certificate);
+        found = true;
+    }
+    if !found {
+        return Err(format!("{}: no PEM certificate found", ca.display()));
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Connect to the aggregator at `url`. `wss://` URLs are verified against `tls` if given,
+/// or else against the system's trusted roots.
+pub async fn connect(
+    url: &str,
+    tls: Option<&TlsConnector>,
+) -> Result<AggregatorStream, tungstenite::Error> {
+    let (ws_stream, _) = match tls {
+        Some(tls) => {
+            let connector = Connector::NativeTls(tls.clone());
+            connect_async_tls_with_config(url, None, false, Some(connector)).await?
+        }
+        None => connect_async(url).await?,
+    };
+    Ok(ws_stream)
+}
+
+/// The PEM blocks labelled `label` in `data`, each with its armour lines.
+fn pem_blocks(data: &[u8], label: &str) -> Vec<String> {
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+    let text = String::from_utf8_lossy(data);
+    let mut blocks = Vec::new();
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find(&begin) {
+        let Some(length) = rest[start..].find(&end) else {
+            break;
+        };
+        let stop = start + length + end.len();
+        blocks.push(rest[start..stop].to_string());
+        rest = &rest[stop..];
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+
+    /// A certificate file of `tests/fixtures/tls`: a CA, a `localhost` server certificate
+    /// it issued with its key, and an unrelated CA.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    /// Serve WebSocket handshakes over TLS on a free port until the test ends.
+    async fn serve(acceptor: TlsAcceptor) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let _ = tokio_tungstenite::accept_async(stream).await;
+                }
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn connects_only_with_the_issuing_ca() {
+        let acceptor = acceptor(&fixture("server.pem"), &fixture("server.key")).unwrap();
+        let url = format!("wss://localhost:{}", serve(acceptor).await);
+
+        let trusted = connector(&fixture("ca.pem")).unwrap();
+        connect(&url, Some(&trusted)).await.unwrap();
+
+        let unrelated = connector(&fixture("other-ca.pem")).unwrap();
+        let error = connect(&url, Some(&unrelated)).await.unwrap_err();
+        assert!(matches!(error, tungstenite::Error::Tls(_)), "{error}");
+    }
+
+    #[test]
+    fn rejects_files_without_certificates_or_keys() {
+        let error = connector(&fixture("server.key")).unwrap_err();
+        assert!(error.contains("no PEM certificate found"), "{error}");
+        assert!(acceptor(&fixture("server.pem"), &fixture("ca.pem")).is_err());
+        assert!(acceptor(&fixture("missing.pem"), &fixture("server.key")).is_err());
+    }
+}
@@ -1,82 +1,112 @@
-use base64::{engine::general_purpose, Engine as _};
-use clap::{Arg, Command};
-use ed25519_dalek::{SigningKey, VerifyingKey};
-use rand::rngs::OsRng;
-use serde_json::json;
+use crate::dkg;
+use crate::federation;
+use crate::frost;
+use crate::keys;
+use crate::protocol::GlobalResult;
+use crate::signer;
+use clap::{Arg, ArgAction, Command};
 use serde_json::Value;
-use std::fs;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::http::Uri;
 
-/// Save the keypairs (private and public) to a JSON file.
-pub fn save_keys(keypairs: Vec<SigningKey>, file_path: &str) -> io::Result<()> {
-    let serialized_keys: Vec<_> = keypairs
-        .iter()
-        .map(|sk| {
-            json!({
-                "private_key": general_purpose::STANDARD.encode(sk.to_bytes()),
-                "public_key": general_purpose::STANDARD.encode(sk.verifying_key().to_bytes())
-            })
-        })
-        .collect();
-
-    let mut file = fs::File::create(file_path)?;
-    writeln!(file, "{}", json!(serialized_keys))?;
+/// Current time in milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Accept a `ws://` or `wss://` URL with a host.
+pub fn check_url(url: &str) -> Result<(), String> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| format!("{url} is not a URL: {e}"))?;
+    if !matches!(uri.scheme_str(), Some("ws" | "wss")) {
+        return Err(format!("{url} is not a ws:// or wss:// URL"));
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(format!("{url} has no host"));
+    }
     Ok(())
 }
 
-/// Load private keys from a JSON file (for the client).
-pub fn load_private_keys(file_path: &str) -> Vec<SigningKey> {
-    let data = fs::read_to_string(file_path).expect("Failed to read keys file");
-    let key_data: Vec<Value> = serde_json::from_str(&data).expect("Failed to parse JSON");
-    key_data
-        .into_iter()
-        .map(|entry| {
-            let private_key = general_purpose::STANDARD
-                .decode(entry["private_key"].as_str().unwrap())
-                .unwrap();
-            let key_bytes: [u8; 32] = private_key
-                .try_into()
-                .expect("Private key must be 32 bytes");
-            SigningKey::from_bytes(&key_bytes)
-        })
-        .collect()
+/// Lower-case hexadecimal encoding of `bytes`.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
-/// Load public keys from a JSON file (for the aggregator).
-pub fn load_public_keys(file_path: &str) -> Vec<VerifyingKey> {
-    let data = fs::read_to_string(file_path).expect("Failed to read keys file");
-    let key_data: Vec<Value> = serde_json::from_str(&data).expect("Failed to parse JSON");
-    key_data
-        .into_iter()
-        .map(|entry| {
-            let public_key = general_purpose::STANDARD
-                .decode(entry["public_key"].as_str().unwrap())
-                .unwrap();
-            let key_bytes: [u8; 32] = public_key.try_into().expect("Public key must be 32 bytes");
-            VerifyingKey::from_bytes(&key_bytes).expect("Invalid public key")
-        })
+/// Decode lower- or upper-case hexadecimal, or `None` if `hex` is not valid hex.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
         .collect()
 }
 
-pub fn generate_keypairs(num_clients: usize) -> Vec<SigningKey> {
-    (0..num_clients)
-        .map(|_| SigningKey::generate(&mut OsRng))
-        .collect()
+/// Symbols used when none are given on the command line.
+pub const DEFAULT_SYMBOLS: &str = "BTCUSDT";
+
+/// Address the aggregator listens on without `--listen` or a federation.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+/// Aggregator the client submits to without `--aggregator-url` or a federation.
+pub const DEFAULT_AGGREGATOR_URL: &str = "ws://127.0.0.1:8080";
+
+/// Parse a comma-separated list of trading pairs into unique upper-case symbols,
+/// falling back to [`DEFAULT_SYMBOLS`] when the list is empty.
+pub fn parse_symbols(list: &str) -> Vec<String> {
+    let mut symbols: Vec<String> = Vec::new();
+    for symbol in list.split(',') {
+        let symbol = symbol.trim().to_uppercase();
+        if !symbol.is_empty() && !symbols.contains(&symbol) {
+            symbols.push(symbol);
+        }
+    }
+    if symbols.is_empty() {
+        eprintln!("No symbols given, taking default {DEFAULT_SYMBOLS}.");
+        return parse_symbols(DEFAULT_SYMBOLS);
+    }
+    symbols
+}
+
+/// A single trade taken from the exchange stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub symbol: String,
+    pub price: f64,
+    pub quantity: f64,
 }
-/// Process WebSocket message to extract price.
-pub fn process_message(text: &str) -> Result<f64, Box<dyn std::error::Error>> {
+
+/// Process a Binance trade-stream message to extract the trade symbol, price and quantity.
+pub fn process_message(text: &str) -> Result<Trade, Box<dyn std::error::Error>> {
     let json: Value = serde_json::from_str(text)?;
-    if let Some(price) = json.get("p") {
-        price
-            .as_str()
-            .to_owned()
-            .unwrap_or_default()
-            .parse::<f64>()
-            .map_err(|e| e.into())
-    } else {
-        Err("No price field found".into())
-    }
+    // Combined streams wrap each event as {"stream": "...", "data": {...}}.
+    let event = json.get("data").unwrap_or(&json);
+    let field = |name: &str| -> Result<f64, Box<dyn std::error::Error>> {
+        match event.get(name) {
+            Some(value) => value
+                .as_str()
+                .unwrap_or_default()
+                .parse::<f64>()
+                .map_err(|e| e.into()),
+            None => Err(format!("No {name} field found").into()),
+        }
+    };
+    let symbol = event
+        .get("s")
+        .and_then(Value::as_str)
+        .ok_or("No symbol field found")?;
+    Ok(Trade {
+        symbol: symbol.to_uppercase(),
+        price: field("p")?,
+        quantity: field("q")?,
+    })
 }
 
 /// Calculate the average of a vector of numbers.
@@ -88,36 +118,145 @@ pub fn calculate_average(prices: &[f64]) -> Option<f64> {
     }
 }
 
-/// Save individual client data to file.
-pub fn save_client_data(id: usize, prices: &Vec<f64>, average: f64) -> std::io::Result<()> {
-    let mut file = File::create(format!("client_{id}_data.txt"))?;
-    writeln!(file, "Prices: {:?}\nAverage: {:.4}", prices, average)?;
+/// Calculate the volume-weighted average price of a list of trades.
+pub fn calculate_vwap(trades: &[Trade]) -> Option<f64> {
+    let volume = total_volume(trades);
+    if volume > 0.0 {
+        Some(trades.iter().map(|t| t.price * t.quantity).sum::<f64>() / volume)
+    } else {
+        None
+    }
+}
+
+/// Total traded quantity of a list of trades.
+pub fn total_volume(trades: &[Trade]) -> f64 {
+    trades.iter().map(|t| t.quantity).sum()
+}
+
+/// What a client computed for one symbol.
+#[derive(Debug, Clone)]
+pub struct SymbolData {
+    pub symbol: String,
+    pub prices: Vec<f64>,
+    pub average: f64,
+    pub volume: f64,
+}
+
+/// Save individual client data to file in `dir`, one section per symbol.
+pub fn save_client_data(dir: &Path, id: usize, data: &[SymbolData]) -> std::io::Result<()> {
+    let mut file = File::create(dir.join(format!("client_{id}_data.txt")))?;
+    for entry in data {
+        writeln!(
+            file,
+            "Symbol: {}\nPrices: {:?}\nAverage: {:.4}\nVolume: {}",
+            entry.symbol, entry.prices, entry.average, entry.volume
+        )?;
+    }
     Ok(())
 }
 
-pub fn save_client_error_data(id: usize, message: String) -> std::io::Result<()> {
-    let mut file = File::create(format!("client_{id}_data.txt"))?;
+pub fn save_client_error_data(dir: &Path, id: usize, message: String) -> std::io::Result<()> {
+    let mut file = File::create(dir.join(format!("client_{id}_data.txt")))?;
     writeln!(file, "Error: {}", message)?;
     Ok(())
 }
 
-/// Save global aggregator data to file.
-pub fn save_global_data(averages: &Vec<f64>, global_average: f64) -> std::io::Result<()> {
-    let mut file = File::create("global_data.txt")?;
+/// Save a symbol's global aggregator data to file in `dir`, listing any rejected submissions.
+pub fn save_global_data(
+    dir: &Path,
+    result: &GlobalResult,
+    averages: &[f64],
+    rejected: &[String],
+) -> std::io::Result<()> {
+    let mut file = File::create(dir.join(format!("global_{}_data.txt", result.symbol)))?;
     writeln!(
         file,
-        "Client Averages: {:?}\nGlobal Average: {:.4}",
-        averages, global_average
+        "Symbol: {}\nRound: {}",
+        result.symbol, result.round_id
     )?;
+    writeln!(file, "Client Averages: {:?}", averages)?;
+    match result.average {
+        Some(global_average) => writeln!(file, "Global Average: {:.4}", global_average)?,
+        None => writeln!(file, "Global Average: none")?,
+    }
+    writeln!(
+        file,
+        "Contributors: {} of {} (quorum {})",
+        result.contributors,
+        result.quorum,
+        if result.quorum_met { "met" } else { "not met" }
+    )?;
+    writeln!(file, "Method: {}", result.method)?;
+    writeln!(file, "Contributor Ids: {:?}", result.contributor_ids)?;
+    writeln!(file, "Merkle Root: {}", result.merkle_root)?;
+    writeln!(
+        file,
+        "Aggregator Key: {}\nSignature: {}",
+        result.aggregator_key, result.signature
+    )?;
+    if let Some(group_signature) = &result.group_signature {
+        writeln!(file, "Group Signature: {group_signature}")?;
+    }
+    if !result.endorsements.is_empty() {
+        let endorsed_by: Vec<&str> = result
+            .endorsements
+            .iter()
+            .map(|endorsement| endorsement.aggregator_key.as_str())
+            .collect();
+        writeln!(file, "Endorsed By: {endorsed_by:?}")?;
+    }
+    writeln!(file, "Excluded: {}", result.excluded.len())?;
+    for entry in &result.excluded {
+        writeln!(file, "  Client-{}: {:.4}", entry.client_id, entry.value)?;
+    }
+    writeln!(file, "Rejected: {}", rejected.len())?;
+    for entry in rejected {
+        writeln!(file, "  {}", entry)?;
+    }
     Ok(())
 }
 
+/// Options and the `config check` command both the client and the aggregator take.
+fn with_config(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .env("MCS_CONFIG")
+                .value_name("FILE")
+                .global(true)
+                .help("TOML configuration file; environment variables and options override it [default: mcs.toml if present]"),
+        )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .env("MCS_DATA_DIR")
+                .value_name("DIR")
+                .help("Directory the client and global data files are written to and read from [default: .]"),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate the effective configuration and print it as TOML"),
+                ),
+        )
+        .subcommand_negates_reqs(true)
+}
+
 /// Parse the command-line arguments
 pub fn parse_arguments() -> clap::ArgMatches {
-    Command::new("WebSocket Listener")
+    client_command().get_matches()
+}
+
+/// The client's command line.
+pub fn client_command() -> Command {
+    let command = Command::new("WebSocket Listener")
         .version("1.0")
         .author("Pruthvi Thakor")
-        .about("Listens to the WebSocket for BTC/USDT prices")
+        .about("Listens to the exchange WebSocket for trade prices")
         .arg(
             Arg::new("mode")
                 .short('m')
@@ -130,31 +269,532 @@ pub fn parse_arguments() -> clap::ArgMatches {
             Arg::new("times")
                 .short('t')
                 .long("times")
+                .env("MCS_TIMES")
                 .value_name("NUMBER")
+                .value_parser(clap::value_parser!(u64))
                 .help("The number of seconds to listen")
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("keys")
+                .short('k')
+                .long("keys")
+                .env("MCS_KEY_DIR")
+                .value_name("DIR")
+                .help("Directory of per-client private key files written by keygen")
+                .default_value(keys::DEFAULT_KEY_DIR),
+        )
+        .arg(
+            Arg::new("aggregator-key")
+                .long("aggregator-key")
+                .env("MCS_AGGREGATOR_KEY")
+                .value_name("FILE")
+                .help("Aggregator public key that published results must be signed with")
+                .default_value(keys::DEFAULT_AGGREGATOR_PUBLIC_KEY),
+        )
+        .arg(
+            Arg::new("aggregator-url")
+                .long("aggregator-url")
+                .value_name("URL")
+                .env("MCS_AGGREGATOR_URL")
+                .help("WebSocket URL of the aggregator, e.g. ws://[::1]:8080 or wss://aggregator.example:8443")
+                .default_value(DEFAULT_AGGREGATOR_URL),
+        )
+        .arg(
+            Arg::new("tls-ca")
+                .long("tls-ca")
+                .env("MCS_TLS_CA")
+                .value_name("FILE")
+                .help("PEM CA certificates to verify wss:// aggregators against instead of the system's trusted roots"),
+        )
+        .arg(
+            Arg::new("federation")
+                .long("federation")
+                .env("MCS_FEDERATION")
+                .value_name("FILE")
+                .help("Submit to every aggregator in this federation file and require results published alike by its majority; replaces --aggregator-url and --aggregator-key"),
+        )
+        .arg(
+            Arg::new("cosign")
+                .long("cosign")
+                .env("MCS_COSIGN")
+                .action(ArgAction::SetTrue)
+                .help("Co-sign accepted results with each client's threshold share and require a group signature on them"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .env("MCS_GROUP")
+                .value_name("FILE")
+                .help("Threshold group written by keygen --dkg finish")
+                .default_value(frost::DEFAULT_GROUP),
+        )
+        .arg(
+            Arg::new("cosign-tolerance")
+                .long("cosign-tolerance")
+                .env("MCS_COSIGN_TOLERANCE")
+                .value_name("FRACTION")
+                .value_parser(clap::value_parser!(f64))
+                .help("Largest relative distance from a client's own price at which it still co-signs a result")
+                .default_value("0.01"),
+        )
+        .arg(
+            Arg::new("agent")
+                .long("agent")
+                .env("MCS_AGENT")
+                .value_name("SOCKET")
+                .help("Sign with the keys held by the signing agent on this Unix socket instead of reading --keys"),
+        )
+        .arg(
+            Arg::new("compute")
+                .short('c')
+                .long("compute")
+                .env("MCS_COMPUTE")
+                .value_name("MODE")
+                .help("How each client reduces its trades to one price. Use --compute=mean or --compute=vwap")
+                .default_value("mean"),
+        )
+        .arg(
+            Arg::new("symbols")
+                .short('s')
+                .long("symbols")
+                .env("MCS_SYMBOLS")
+                .value_name("LIST")
+                .help("Comma-separated trading pairs to listen to, e.g. btcusdt,ethusdt")
+                .default_value(DEFAULT_SYMBOLS),
+        )
+        .arg(
+            Arg::new("feed")
+                .short('f')
+                .long("feed")
+                .env("MCS_FEED")
+                .value_name("LIST")
+                .help("Comma-separated price feeds assigned to clients in turn: binance, coinbase, coinbase:ticker or kraken")
+                .default_value("binance"),
+        )
+        .arg(
+            Arg::new("feed-url")
+                .long("feed-url")
+                .env("MCS_FEED_URL")
+                .value_name("URL")
+                .help("Override the feed endpoint, e.g. ws://127.0.0.1:9443 for the mock exchange"),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Write every received exchange frame with its receive time to FILE")
+                .conflicts_with("replay"),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Compute prices from frames recorded with --record instead of a live feed"),
+        )
+        .arg(
+            Arg::new("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .help("Replay speed relative to the recording; 0 replays without delays")
+                .default_value("1"),
+        );
+    with_config(command)
+}
+
+/// Parse the aggregator's command-line arguments
+pub fn parse_aggregator_arguments() -> clap::ArgMatches {
+    aggregator_command().get_matches()
+}
+
+/// The aggregator's command line.
+pub fn aggregator_command() -> Command {
+    let command = Command::new("Aggregator")
+        .version("1.0")
+        .author("Pruthvi Thakor")
+        .about("Aggregates signed price averages from clients in rounds")
+        .arg(
+            Arg::new("registry")
+                .long("registry")
+                .env("MCS_REGISTRY")
+                .value_name("FILE")
+                .help("Public key registry written by keygen")
+                .default_value(keys::DEFAULT_REGISTRY),
+        )
+        .arg(
+            Arg::new("signing-key")
+                .long("signing-key")
+                .env("MCS_SIGNING_KEY")
+                .value_name("FILE")
+                .help("Private key the aggregator signs published results with, written by keygen")
+                .default_value(keys::DEFAULT_AGGREGATOR_KEY),
+        )
+        .arg(
+            Arg::new("cosign")
+                .long("cosign")
+                .env("MCS_COSIGN")
+                .action(ArgAction::SetTrue)
+                .help("Have contributors co-sign every result with a threshold signature of the client group"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .env("MCS_GROUP")
+                .value_name("FILE")
+                .help("Threshold group written by keygen --dkg finish")
+                .default_value(frost::DEFAULT_GROUP),
+        )
+        .arg(
+            Arg::new("federation")
+                .long("federation")
+                .env("MCS_FEDERATION")
+                .value_name("FILE")
+                .help("Cross-check results with the other aggregators listed in this federation file; without --listen this aggregator listens on its own member URL"),
+        )
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .env("MCS_LISTEN")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .help("Address to listen on, e.g. 0.0.0.0:8080 or [::]:8080; repeat or separate with commas to listen on several [default: 127.0.0.1:8080]"),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .env("MCS_TLS_CERT")
+                .value_name("FILE")
+                .help("Serve wss:// with this PEM certificate chain; needs --tls-key"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .env("MCS_TLS_KEY")
+                .value_name("FILE")
+                .help("PEM PKCS#8 private key of --tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-ca")
+                .long("tls-ca")
+                .env("MCS_TLS_CA")
+                .value_name("FILE")
+                .help("PEM CA certificates to verify wss:// federation peers against instead of the system's trusted roots"),
+        )
+        .arg(
+            Arg::new("deadline")
+                .short('d')
+                .long("deadline")
+                .env("MCS_DEADLINE")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("How long a round collects after its first submission, accepted or rejected")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("quorum")
+                .short('q')
+                .long("quorum")
+                .env("MCS_QUORUM")
+                .value_name("COUNT|FRACTION")
+                .help("Contributors needed for a full result, as a count (3) or a fraction of registered keys (0.6 or 60%). Defaults to all registered keys"),
+        )
+        .arg(
+            Arg::new("method")
+                .short('a')
+                .long("method")
+                .env("MCS_METHOD")
+                .value_name("METHOD")
+                .help("Aggregation strategy: mean, median, vwap, trimmed[:FRACTION] or mad[:THRESHOLD]")
+                .default_value("mean"),
+        )
+        .arg(
+            Arg::new("partial")
+                .short('p')
+                .long("partial")
+                .env("MCS_PARTIAL")
+                .value_name("POLICY")
+                .help("What to do with a round that closes under quorum. Use --partial=degraded or --partial=refuse")
+                .default_value("degraded"),
+        )
+        .arg(
+            Arg::new("symbols")
+                .short('s')
+                .long("symbols")
+                .env("MCS_SYMBOLS")
+                .value_name("LIST")
+                .help("Comma-separated trading pairs to run rounds for, e.g. btcusdt,ethusdt")
+                .default_value(DEFAULT_SYMBOLS),
+        );
+    with_config(command)
+}
+
+/// Parse the mock exchange's command-line arguments
+pub fn parse_exchange_arguments() -> clap::ArgMatches {
+    Command::new("Mock Exchange")
+        .version("1.0")
+        .author("Pruthvi Thakor")
+        .about("Serves a Binance-compatible @trade WebSocket stream for offline runs")
+        .arg(
+            Arg::new("listen")
+                .short('l')
+                .long("listen")
+                .value_name("ADDRESS")
+                .help("Address to listen on")
+                .default_value("127.0.0.1:9443"),
+        )
+        .arg(
+            Arg::new("symbols")
+                .short('s')
+                .long("symbols")
+                .value_name("LIST")
+                .help("Comma-separated trading pairs to generate trades for")
+                .default_value(DEFAULT_SYMBOLS),
+        )
+        .arg(
+            Arg::new("rate")
+                .short('r')
+                .long("rate")
+                .value_name("NUMBER")
+                .help("Trades emitted per second across all symbols")
+                .default_value("20"),
+        )
+        .arg(Arg::new("fixture").long("fixture").value_name("FILE").help(
+            "Replay newline-delimited Binance trade events from FILE instead of a random walk",
+        ))
+        .arg(
+            Arg::new("start-price")
+                .long("start-price")
+                .value_name("PRICE")
+                .help("Random walk starting price")
+                .default_value("100000"),
+        )
+        .arg(
+            Arg::new("volatility")
+                .long("volatility")
+                .value_name("FRACTION")
+                .help("Largest relative price move per random-walk trade")
+                .default_value("0.0005"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("NUMBER")
+                .help("Seed for a reproducible random walk"),
+        )
+        .get_matches()
+}
+
+/// Parse the key generator's command-line arguments
+pub fn parse_keygen_arguments() -> clap::ArgMatches {
+    Command::new("Key Generator")
+        .version("1.0")
+        .author("Pruthvi Thakor")
+        .about("Creates per-client private key files and the aggregator's public key registry")
+        .arg(
+            Arg::new("clients")
+                .short('n')
+                .long("clients")
+                .value_name("NUMBER")
+                .help("Make sure clients 1 to NUMBER have a key")
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("keys")
+                .short('k')
+                .long("keys")
+                .value_name("DIR")
+                .help("Directory for the per-client private key files")
+                .default_value(keys::DEFAULT_KEY_DIR),
+        )
+        .arg(
+            Arg::new("registry")
+                .long("registry")
+                .value_name("FILE")
+                .help("Where to write the public key registry")
+                .default_value(keys::DEFAULT_REGISTRY),
+        )
+        .arg(
+            Arg::new("aggregator-key")
+                .long("aggregator-key")
+                .value_name("FILE")
+                .help("Where to create the aggregator's result signing key; its public key is written next to it with a .pub extension")
+                .default_value(keys::DEFAULT_AGGREGATOR_KEY),
+        )
+        .arg(
+            Arg::new("dkg")
+                .long("dkg")
+                .value_name("STEP")
+                .value_parser(["commit", "share", "finish"])
+                .requires("client")
+                .conflicts_with_all(["migrate", "rotate", "import-key", "import-public", "export"])
+                .help("Run one step of the key generation of a threshold group for --client; every client of 1 to --clients runs commit, then share, then finish"),
+        )
+        .arg(
+            Arg::new("threshold")
+                .long("threshold")
+                .value_name("T")
+                .required_if_eq("dkg", "commit")
+                .help("Signature shares the group generated with --dkg needs, so that any T of the clients can co-sign a result"),
+        )
+        .arg(
+            Arg::new("dkg-dir")
+                .long("dkg-dir")
+                .value_name("DIR")
+                .help("Directory shared by the clients of a --dkg key generation for their messages")
+                .default_value(dkg::DEFAULT_DKG_DIR),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .value_name("FILE")
+                .help("Where --dkg finish writes the threshold group, or checks it against the one there")
+                .default_value(frost::DEFAULT_GROUP),
+        )
+        .arg(
+            Arg::new("federate")
+                .long("federate")
+                .value_name("URL")
+                .help("Add the aggregator key to the federation file as the aggregator reached at this ws:// URL"),
+        )
+        .arg(
+            Arg::new("federation")
+                .long("federation")
+                .value_name("FILE")
+                .help("Federation file updated by --federate")
+                .default_value(federation::DEFAULT_FEDERATION),
+        )
+        .arg(
+            Arg::new("majority")
+                .long("majority")
+                .value_name("COUNT")
+                .requires("federate")
+                .help("Aggregators that must publish the same result for it to be final; default more than half of the members"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Replace existing keys instead of keeping them"),
+        )
+        .arg(
+            Arg::new("rotate")
+                .long("rotate")
+                .value_name("IDS")
+                .help("Comma-separated client ids to give a new key"),
+        )
+        .arg(
+            Arg::new("overlap")
+                .long("overlap")
+                .value_name("SECONDS")
+                .help("How long a rotated client's older keys remain valid")
+                .default_value("3600"),
+        )
+        .arg(
+            Arg::new("encrypt")
+                .long("encrypt")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Encrypt new key files with a passphrase from MCS_KEY_PASSPHRASE or a prompt",
+                ),
+        )
+        .arg(
+            Arg::new("migrate")
+                .long("migrate")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["force", "rotate"])
+                .help("Import client_keys.json of earlier versions, then encrypt every plaintext key file in place"),
+        )
+        .arg(
+            Arg::new("client")
+                .long("client")
+                .value_name("ID")
+                .help("Client id an imported key belongs to, or that runs a --dkg step"),
+        )
+        .arg(
+            Arg::new("import-key")
+                .long("import-key")
+                .value_name("FILE")
+                .requires("client")
+                .conflicts_with_all(["migrate", "rotate", "import-public"])
+                .help("Add a PKCS#8 PEM or base64 private key as the client's newest key"),
+        )
+        .arg(
+            Arg::new("import-public")
+                .long("import-public")
+                .value_name("FILE")
+                .requires("client")
+                .conflicts_with_all(["migrate", "rotate"])
+                .help("Register an SPKI PEM, OpenSSH or base64 public key for a client whose private key is kept elsewhere"),
+        )
+        .arg(
+            Arg::new("export")
+                .long("export")
+                .value_name("ID")
+                .conflicts_with_all(["migrate", "rotate", "import-key", "import-public"])
+                .help("Print the client's newest valid key instead of generating keys"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Export format: pem (SPKI or PKCS#8), openssh or base64")
+                .default_value("pem"),
+        )
+        .arg(
+            Arg::new("private")
+                .long("private")
+                .action(ArgAction::SetTrue)
+                .requires("export")
+                .help("Export the private key, as PKCS#8 PEM or base64, instead of the public key"),
+        )
         .get_matches()
 }
 
-/// Prints the data after reading it from file
-pub fn read_mode(num_clients: usize) -> io::Result<()> {
+pub fn parse_agent_arguments() -> clap::ArgMatches {
+    Command::new("Signing Agent")
+        .version("1.0")
+        .author("Pruthvi Thakor")
+        .about("Holds the client keys and signs submissions for clients over a Unix socket")
+        .arg(
+            Arg::new("keys")
+                .short('k')
+                .long("keys")
+                .value_name("DIR")
+                .help("Directory of per-client private key files written by keygen")
+                .default_value(keys::DEFAULT_KEY_DIR),
+        )
+        .arg(
+            Arg::new("socket")
+                .short('s')
+                .long("socket")
+                .value_name("PATH")
+                .help("Unix socket to serve signing requests on, readable by this user only")
+                .default_value(signer::DEFAULT_AGENT_SOCKET),
+        )
+        .get_matches()
+}
+
+/// Prints the data after reading it from the files in `dir`
+pub fn read_mode(dir: &Path, client_ids: &[usize], symbols: &[String]) -> io::Result<()> {
     println!("Reading prices data ...\n");
-    let mut files: Vec<String> = Vec::with_capacity(num_clients + 1);
-    for i in 1..=num_clients {
-        files.push(format!("client_{}_data.txt", i));
+    let mut files: Vec<PathBuf> = Vec::with_capacity(client_ids.len() + symbols.len());
+    for id in client_ids {
+        files.push(dir.join(format!("client_{}_data.txt", id)));
+    }
+    for symbol in symbols {
+        files.push(dir.join(format!("global_{}_data.txt", symbol)));
     }
-    files.push(String::from("global_data.txt"));
     'file_loop: for file_path in files.iter() {
         // Attempt to open the file
         let file = match File::open(file_path) {
             Ok(file) => file,
             Err(err) => {
-                eprintln!("Failed to open {}: {}", file_path, err);
+                eprintln!("Failed to open {}: {}", file_path.display(), err);
                 break 'file_loop; // Exit the loop on error
             }
         };
-        println!("\nReading file: {}\n", file_path);
+        println!("\nReading file: {}\n", file_path.display());
         let reader = BufReader::new(file);
 
         // Read the file line by line
@@ -162,7 +802,7 @@ pub fn read_mode(num_clients: usize) -> io::Result<()> {
             match line {
                 Ok(content) => println!("{}", content),
                 Err(err) => {
-                    eprintln!("Error reading a line in {}: {}", file_path, err);
+                    eprintln!("Error reading a line in {}: {}", file_path.display(), err);
                     break 'file_loop; // Exit the loop on error
                 }
             }
@@ -171,3 +811,32 @@ pub fn read_mode(num_clients: usize) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(price: f64, quantity: f64) -> Trade {
+        Trade {
+            symbol: "BTCUSDT".to_string(),
+            price,
+            quantity,
+        }
+    }
+
+    #[test]
+    fn vwap_weights_prices_by_quantity() {
+        let trades = [trade(100.0, 1.0), trade(110.0, 3.0)];
+        assert_eq!(calculate_vwap(&trades), Some(107.5));
+        assert_eq!(total_volume(&trades), 4.0);
+    }
+
+    #[test]
+    fn vwap_of_no_volume_is_none() {
+        assert_eq!(calculate_vwap(&[]), None);
+        assert_eq!(
+            calculate_vwap(&[trade(100.0, 0.0), trade(110.0, 0.0)]),
+            None
+        );
+    }
+}